@@ -89,7 +89,7 @@ fn main() {
     for entry in archive.entries().expect("failed to iterate tar entries") {
         let mut entry = entry.expect("bad tar entry");
         let path = entry.path().expect("bad tar path").into_owned();
-        if path.extension().map_or(false, |e| e == "mmdb") {
+        if path.extension().is_some_and(|e| e == "mmdb") {
             eprintln!("[build] Extracting {:?} ...", path.file_name().unwrap_or_default());
             let mut buf = Vec::new();
             entry.read_to_end(&mut buf).expect("failed to read mmdb bytes");
@@ -0,0 +1,840 @@
+//! cli.rs — the command line shared by `tor-node-parser` and `world-map`.
+//!
+//! `tor-node-parser [GLOBAL OPTIONS] [COMMAND] [ARGS]`; see [`USAGE`]. The
+//! global options may appear anywhere on the line and pick where the
+//! details document comes from, where outputs go and how chatty the run is.
+//! Without a command the default export runs, so existing invocations keep
+//! working; `world-map` is `tor-node-parser map`.
+
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+    process,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    config::{Config, Format, OutputSpec},
+    daemon, diff, diversity, export,
+    log::{self, Level},
+    metrics,
+    onionoo::{read_response, read_snapshots, Fetcher, OnionooResponse, Source},
+    output::{self, Layout, Vars},
+    region, serve, simulate, stats, summary, theme, validate, world_map,
+};
+
+pub const USAGE: &str = "\
+Usage: tor-node-parser [GLOBAL OPTIONS] [COMMAND] [ARGS]
+
+Commands:
+  export      write every output of the config (default)
+  fetch       print the details document, or --save it
+  map         write only the world map
+  timelapse   animate the map over saved snapshots
+  check       validate the document without writing anything
+  diff        compare two details documents
+  stats       network report
+  diversity   concentration metrics
+  simulate    guard/exit compromise simulation
+  metrics     Prometheus exposition
+  serve       serve outputs and metrics over HTTP
+  watch       refresh outputs on a schedule
+  help        show this message
+
+Global options:
+  --input FILE     read the details document from FILE (`-` for stdin)
+  --cache DIR      cache Onionoo responses in DIR
+  --no-cache       do not cache Onionoo responses
+  --mirror URL     fetch from URL; repeat to try several in order
+  --timeout SECS   Onionoo read timeout (default 60)
+  --retries N      retries per mirror (default 3)
+  --output-dir DIR publish outputs under DIR
+  -q, --quiet      only print warnings and errors
+  -v, --verbose    print debug detail
+  --log-format F   `text` (default) or `json`, one object per line
+  --summary PATH   write a JSON run summary to PATH
+  --strict         reject documents with malformed relay records
+";
+
+/// Options accepted before or after the command.
+struct Global {
+    source: Source,
+    output_dir: Option<PathBuf>,
+    summary: Option<PathBuf>,
+}
+
+impl Global {
+    /// Output options with `--output-dir` applied; commands add their own
+    /// flags and the config's defaults on top.
+    fn output(&self) -> output::Options {
+        let mut out = output::Options::default();
+        if let Some(dir) = &self.output_dir {
+            out.dir = dir.clone();
+        }
+        out
+    }
+}
+
+/// Remove the global options from `args`, wherever they appear.
+fn take_global(args: Vec<String>) -> anyhow::Result<(Global, Vec<String>)> {
+    let mut input      = None;
+    let mut fetcher    = Fetcher::default();
+    let mut output_dir = None;
+    let mut level      = Level::Info;
+    let mut mirrors    = Vec::new();
+    let mut timeout    = None;
+    let mut summary    = None;
+    let mut rest       = Vec::new();
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--input"         => input             = Some(PathBuf::from(next_value(&mut it, arg)?)),
+            "--cache"         => fetcher.cache_dir = Some(PathBuf::from(next_value(&mut it, arg)?)),
+            "--no-cache"      => fetcher.cache_dir = None,
+            "--mirror"        => mirrors.push(next_value(&mut it, arg)?.to_string()),
+            "--timeout"       => timeout           = Some(count(arg, next_value(&mut it, arg)?)?),
+            "--retries"       => fetcher.retries   = count(arg, next_value(&mut it, arg)?)?,
+            "--output-dir"    => output_dir        = Some(PathBuf::from(next_value(&mut it, arg)?)),
+            "-q" | "--quiet"  => level             = Level::Warn,
+            "-v" | "--verbose" => level            = Level::Debug,
+            "--log-format"    => log::set_format(next_value(&mut it, arg)?.parse()?),
+            "--summary"       => summary           = Some(PathBuf::from(next_value(&mut it, arg)?)),
+            "--strict"        => validate::set_strict(true),
+            _ => rest.push(arg.clone()),
+        }
+    }
+    log::set_level(level);
+
+    if !mirrors.is_empty() {
+        fetcher.urls = mirrors;
+    }
+    if let Some(secs) = timeout {
+        anyhow::ensure!(secs > 0, "--timeout must be at least 1 second");
+        fetcher.read_timeout = Duration::from_secs(secs);
+    }
+    let source = match input {
+        Some(path) => Source::File(path),
+        None       => Source::Live(fetcher),
+    };
+    Ok((Global { source, output_dir, summary }, rest))
+}
+
+// ---------------------------------------------------------------------------
+// Commands
+// ---------------------------------------------------------------------------
+
+/// `export [--config FILE]`, plus the output and validation flags — load
+/// once and (after validation) write every output of the config; by
+/// default the three CSVs and the map.
+fn run_export(global: &Global, args: &[String]) -> anyhow::Result<()> {
+    let mut validation = Validation::default();
+    let mut out = global.output();
+    let mut config_path = None;
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        if arg == "--config" {
+            config_path = Some(next_value(&mut it, arg)?);
+        } else if !validation.parse_flag(arg, &mut it)?
+            && !parse_output_flag(arg, &mut it, &mut out)?
+        {
+            anyhow::bail!("unknown argument `{arg}`");
+        }
+    }
+    let thresholds = validation.resolve()?;
+    let config = config_path.map_or_else(|| Ok(Config::default()), Config::load)?;
+    write_outputs(global, &config, out, thresholds.as_ref())
+}
+
+/// `map [--format svg|html|png|webp] [--width PX] [--dpi N] [--projection SPEC]
+/// [--region SPEC] [--inset SPEC]... [--choropleth METRIC | --density SPEC
+/// [--density-by relays|bandwidth] | --marker-size METRIC] [--categories
+/// LIST] [--theme THEME]`, plus the output flags — write only the world
+/// map, as SVG, an interactive HTML page or a PNG or WebP image. This is
+/// what the `world-map` binary runs; like it always did, it skips
+/// validation.
+fn run_map(global: &Global, args: &[String]) -> anyhow::Result<()> {
+    let mut out = global.output();
+    let mut config = Config::map_only();
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        let map = &mut config.outputs[0];
+        if arg == "--format" {
+            map.format = match next_value(&mut it, arg)? {
+                "svg"  => Format::Svg,
+                "html" => Format::Html,
+                "png"  => Format::Png,
+                "webp" => Format::Webp,
+                other  => anyhow::bail!("map: unknown format `{other}` (expected svg, html, png or webp)"),
+            };
+        } else if arg == "--width" {
+            map.width = Some(next_value(&mut it, arg)?.parse()?);
+        } else if arg == "--dpi" {
+            map.dpi = Some(next_value(&mut it, arg)?.parse()?);
+        } else if arg == "--projection" {
+            map.projection = Some(next_value(&mut it, arg)?.to_string());
+        } else if arg == "--region" {
+            map.region = Some(next_value(&mut it, arg)?.to_string());
+        } else if arg == "--inset" {
+            map.insets.push(next_value(&mut it, arg)?.to_string());
+        } else if arg == "--choropleth" {
+            map.choropleth = Some(next_value(&mut it, arg)?.to_string());
+        } else if arg == "--density" {
+            map.density = Some(next_value(&mut it, arg)?.to_string());
+        } else if arg == "--density-by" {
+            map.density_by = Some(next_value(&mut it, arg)?.to_string());
+        } else if arg == "--marker-size" {
+            map.marker_size = Some(next_value(&mut it, arg)?.to_string());
+        } else if arg == "--categories" {
+            map.categories = Some(category_list(next_value(&mut it, arg)?));
+        } else if arg == "--theme" {
+            map.theme = Some(next_value(&mut it, arg)?.to_string());
+        } else if !parse_output_flag(arg, &mut it, &mut out)? {
+            anyhow::bail!("map: unknown argument `{arg}`");
+        }
+    }
+    config.check()?;
+    write_outputs(global, &config, out, None)
+}
+
+/// `timelapse --snapshots DIR [--frame-seconds S] [--projection SPEC]
+/// [--region SPEC] [--categories LIST] [--theme THEME]`, plus the output
+/// flags — an animated SVG map of the details documents saved in DIR (e.g.
+/// by `fetch --save`), oldest first, published as `timelapse.svg`.
+fn run_timelapse(global: &Global, args: &[String]) -> anyhow::Result<()> {
+    let mut out        = global.output();
+    let mut snapshots  = None;
+    let mut seconds    = world_map::FRAME_SECONDS;
+    let mut projection = None;
+    let mut area       = None;
+    let mut categories = None;
+    let mut colors     = None;
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--snapshots"     => snapshots  = Some(PathBuf::from(next_value(&mut it, arg)?)),
+            "--frame-seconds" => seconds    = next_value(&mut it, arg)?.parse()?,
+            "--projection"    => projection = Some(next_value(&mut it, arg)?.to_string()),
+            "--region"        => area       = Some(region::parse(next_value(&mut it, arg)?)?),
+            "--categories"    => categories = Some(world_map::parse_categories(&category_list(next_value(&mut it, arg)?))?),
+            "--theme"         => colors     = Some(theme::load(next_value(&mut it, arg)?)?),
+            other => {
+                if !parse_output_flag(other, &mut it, &mut out)? {
+                    anyhow::bail!("timelapse: unknown argument `{other}`");
+                }
+            }
+        }
+    }
+    let dir = snapshots.ok_or_else(|| anyhow::anyhow!("timelapse: --snapshots DIR is required"))?;
+    anyhow::ensure!(seconds > 0.0 && seconds.is_finite(), "--frame-seconds must be positive");
+    out.check()?;
+
+    let snapshots = read_snapshots(&dir)?;
+    anyhow::ensure!(snapshots.len() >= 2, "timelapse needs at least two snapshots in {}", dir.display());
+    let geojson = world_map::world_geojson()?;
+    let mut opts = world_map::Options::new(projection.as_deref())?;
+    opts.region = area.map(|a| a.resolve(&geojson)).transpose()?;
+    if let Some(categories) = categories {
+        opts.categories = categories;
+    }
+    if let Some(theme) = colors {
+        opts.theme = theme;
+    }
+
+    let svg = summary::timed("render", || world_map::render_timelapse(&snapshots, &geojson, &opts, seconds));
+    let latest = &snapshots[snapshots.len() - 1];
+    let names = out.names("timelapse", "svg", &Vars::new(latest));
+    let mut tx = out.begin()?;
+    tx.write(&names[0], svg.as_bytes())?;
+    for alias in &names[1..] {
+        tx.copy(&names[0], alias)?;
+    }
+    summary::timed("commit", || tx.commit())?;
+    info!("Done - wrote {} (output directory {}).", names.join(", "), out.published_dir().display());
+    Ok(())
+}
+
+fn write_outputs(
+    global: &Global,
+    config: &Config,
+    mut out: output::Options,
+    thresholds: Option<&validate::Thresholds>,
+) -> anyhow::Result<()> {
+    config.apply(&mut out)?;
+    out.check()?;
+    let geojson = config.has_map().then(world_map::world_geojson).transpose()?;
+
+    let response = global.source.load()?;
+    if let Some(thresholds) = thresholds {
+        validate::check(&response, thresholds, config, &out)?;
+    }
+    let mut tx = out.begin()?;
+    let names = export::write_all(&mut tx, config, &response, &out, geojson.as_ref())?;
+    summary::timed("commit", || tx.commit())?;
+    info!("Done - wrote {} (output directory {}).", names.join(", "), out.published_dir().display());
+    Ok(())
+}
+
+/// `fetch [--save]`, plus the output flags — print the details document
+/// exactly as Onionoo served it, or with `--save` publish it as
+/// `details.json` (category `details`) like any other output.
+fn run_fetch(global: &Global, args: &[String]) -> anyhow::Result<()> {
+    let mut save = false;
+    let mut out = global.output();
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        if arg == "--save" {
+            save = true;
+        } else if !parse_output_flag(arg, &mut it, &mut out)? {
+            anyhow::bail!("fetch: unknown argument `{arg}`");
+        }
+    }
+
+    let body = global.source.load_raw()?;
+    if !save {
+        // `fetch | head` closing the pipe early is not an error.
+        return match io::stdout().lock().write_all(&body) {
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => Err(e.into()),
+            _ => Ok(()),
+        };
+    }
+    out.check()?;
+    let response: OnionooResponse = serde_json::from_slice(&body)
+        .map_err(|e| anyhow::anyhow!("not a details document: {e}"))?;
+    let names = out.names("details", "json", &Vars::new(&response));
+    let mut tx = out.begin()?;
+    tx.write(&names[0], &body)?;
+    for alias in &names[1..] {
+        tx.copy(&names[0], alias)?;
+    }
+    tx.commit()?;
+    info!("Saved {} (output directory {}).", names.join(", "), out.published_dir().display());
+    Ok(())
+}
+
+/// `check [--config FILE]`, plus the validation flags — run the checks the
+/// export would run, against the outputs currently published, and write
+/// nothing. Exits with the validation status on failure.
+fn run_check(global: &Global, args: &[String]) -> anyhow::Result<()> {
+    let mut validation = Validation::default();
+    let mut config_path = None;
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        if arg == "--config" {
+            config_path = Some(next_value(&mut it, arg)?);
+        } else if !validation.parse_flag(arg, &mut it)? {
+            anyhow::bail!("check: unknown argument `{arg}`");
+        }
+    }
+    let thresholds = validation.resolve()?.unwrap_or_default();
+    let config = config_path.map_or_else(|| Ok(Config::default()), Config::load)?;
+    let mut out = global.output();
+    config.apply(&mut out)?;
+
+    let response = global.source.load()?;
+    validate::check(&response, &thresholds, &config, &out)?;
+    println!(
+        "OK: {} relays, published {}.",
+        response.relays.len(),
+        response.relays_published.as_deref().unwrap_or("unknown"),
+    );
+    Ok(())
+}
+
+/// `diff OLD [NEW] [--format text|json|markdown] [--top N]` — what changed
+/// between two saved documents; NEW defaults to the current source.
+fn run_diff(global: &Global, args: &[String]) -> anyhow::Result<()> {
+    let mut format = stats::Format::Text;
+    let mut top    = 50usize;
+    let mut files  = Vec::new();
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--format" => format = next_value(&mut it, arg)?.parse()?,
+            "--top"    => top    = next_value(&mut it, arg)?.parse()?,
+            flag if flag.starts_with('-') => anyhow::bail!("diff: unknown argument `{flag}`"),
+            file => files.push(PathBuf::from(file)),
+        }
+    }
+
+    let (old, new) = match files.as_slice() {
+        [old]      => (read_response(old)?, global.source.load()?),
+        [old, new] => (read_response(old)?, read_response(new)?),
+        _          => anyhow::bail!("diff: expected OLD [NEW]"),
+    };
+    print!("{}", diff::Diff::build(&old, &new).render(format, top)?);
+    Ok(())
+}
+
+/// Output flags shared by the commands that publish files:
+/// `--name TEMPLATE` (repeatable), `--compress gz,zst` and
+/// `--versioned KEEP`. Returns `false` if `arg` isn't one of them.
+fn parse_output_flag<'a>(
+    arg: &str,
+    it: &mut impl Iterator<Item = &'a String>,
+    out: &mut output::Options,
+) -> anyhow::Result<bool> {
+    match arg {
+        "--name"       => out.templates.push(next_value(it, arg)?.to_string()),
+        "--compress"   => {
+            for variant in next_value(it, arg)?.split(',') {
+                let variant = variant.trim().parse()?;
+                if !out.compress.contains(&variant) {
+                    out.compress.push(variant);
+                }
+            }
+        }
+        "--versioned"  => {
+            let keep: usize = next_value(it, arg)?.parse()?;
+            anyhow::ensure!(keep > 0, "--versioned must keep at least 1 release");
+            out.layout = Layout::Versioned { keep };
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Validation flags shared by `export`, `check` and `watch`:
+/// `--no-validate`, `--max-age HOURS`, `--min-relays N`, `--min-guards N`,
+/// `--min-exits N` and `--max-drop PERCENT`.
+#[derive(Default)]
+struct Validation {
+    thresholds: validate::Thresholds,
+    disabled: bool,
+    /// Threshold flags given, which `--no-validate` would silently override.
+    given: Vec<String>,
+}
+
+impl Validation {
+    /// Returns `false` if `arg` isn't one of the flags.
+    fn parse_flag<'a>(&mut self, arg: &str, it: &mut impl Iterator<Item = &'a String>) -> anyhow::Result<bool> {
+        let t = &mut self.thresholds;
+        match arg {
+            "--no-validate" => {
+                self.disabled = true;
+                return Ok(true);
+            }
+            "--max-age"    => t.max_age    = hours(next_value(it, arg)?)
+                .map_err(|e| anyhow::anyhow!("--max-age: {e}"))?,
+            "--min-relays" => t.min_relays = next_value(it, arg)?.parse()?,
+            "--min-guards" => t.min_guards = next_value(it, arg)?.parse()?,
+            "--min-exits"  => t.min_exits  = next_value(it, arg)?.parse()?,
+            "--max-drop"   => t.max_drop   = next_value(it, arg)?.parse::<f64>()? / 100.0,
+            _ => return Ok(false),
+        }
+        self.given.push(arg.to_string());
+        Ok(true)
+    }
+
+    /// The thresholds to check against, `None` for `--no-validate`, which
+    /// cannot be combined with thresholds or `--strict`.
+    fn resolve(self) -> anyhow::Result<Option<validate::Thresholds>> {
+        if !self.disabled {
+            return Ok(Some(self.thresholds));
+        }
+        if let Some(flag) = self.given.first() {
+            anyhow::bail!("--no-validate and {flag} contradict each other");
+        }
+        anyhow::ensure!(!validate::strict(), "--no-validate and --strict contradict each other");
+        Ok(None)
+    }
+}
+
+/// A whole number given to `flag`, e.g. `--retries 3`.
+fn count<T: std::str::FromStr>(flag: &str, value: &str) -> anyhow::Result<T> {
+    value.parse().map_err(|_| anyhow::anyhow!("{flag}: `{value}` is not a whole number"))
+}
+
+/// A non-negative number of hours, fractions allowed.
+fn hours(value: &str) -> anyhow::Result<Duration> {
+    let hours: f64 = value.parse().map_err(|_| anyhow::anyhow!("`{value}` is not a number of hours"))?;
+    Duration::try_from_secs_f64(hours * 3_600.0)
+        .map_err(|_| anyhow::anyhow!("`{value}` hours is negative, not a number or too large"))
+}
+
+/// A whole number of minutes given to `flag`, e.g. `--offset 10`.
+fn minutes(flag: &str, value: &str) -> anyhow::Result<Duration> {
+    let minutes: u64 = value.parse().map_err(|_| anyhow::anyhow!("{flag}: `{value}` is not a whole number of minutes"))?;
+    let secs = minutes.checked_mul(60).ok_or_else(|| anyhow::anyhow!("{flag}: {minutes} minutes is too large"))?;
+    Ok(Duration::from_secs(secs))
+}
+
+/// `stats [--format text|json|markdown] [--top N]` — print a network report to stdout.
+fn run_stats(global: &Global, args: &[String]) -> anyhow::Result<()> {
+    let mut format = stats::Format::Text;
+    let mut top    = 20usize;
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--format" => format = next_value(&mut it, arg)?.parse()?,
+            "--top"    => top    = next_value(&mut it, arg)?.parse()?,
+            other      => anyhow::bail!("stats: unknown argument `{other}`"),
+        }
+    }
+
+    let response = global.source.load()?;
+    let report = stats::Report::build(&response);
+    print!("{}", report.render(format, top)?);
+    Ok(())
+}
+
+/// `diversity [--format …] [--top N] [--snapshots DIR]` — concentration
+/// metrics for the current document, or a time series over saved snapshots.
+fn run_diversity(global: &Global, args: &[String]) -> anyhow::Result<()> {
+    let mut format    = stats::Format::Text;
+    let mut top       = 5usize;
+    let mut snapshots = None;
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--format"    => format    = next_value(&mut it, arg)?.parse()?,
+            "--top"       => top       = next_value(&mut it, arg)?.parse()?,
+            "--snapshots" => snapshots = Some(PathBuf::from(next_value(&mut it, arg)?)),
+            other         => anyhow::bail!("diversity: unknown argument `{other}`"),
+        }
+    }
+
+    let responses = match snapshots {
+        Some(dir) => read_snapshots(&dir)?,
+        None      => vec![global.source.load()?],
+    };
+    anyhow::ensure!(!responses.is_empty(), "no snapshots found");
+
+    let metrics: Vec<_> = responses
+        .iter()
+        .map(|r| diversity::Snapshot::build(r, top))
+        .collect();
+    print!("{}", diversity::render(&metrics, format, top)?);
+    Ok(())
+}
+
+/// `simulate [--circuits N] [--seed S] [--by country,as,operator] [--target KEY]…
+/// [--format …] [--top N]` — Monte Carlo guard/exit compromise estimate.
+fn run_simulate(global: &Global, args: &[String]) -> anyhow::Result<()> {
+    let mut format = stats::Format::Text;
+    let mut top    = 15usize;
+    let mut opts   = simulate::Options {
+        circuits: 100_000,
+        seed:     SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        entities: simulate::Entity::ALL.to_vec(),
+        targets:  Vec::new(),
+    };
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--format"   => format        = next_value(&mut it, arg)?.parse()?,
+            "--top"      => top           = next_value(&mut it, arg)?.parse()?,
+            "--circuits" => opts.circuits = next_value(&mut it, arg)?.parse()?,
+            "--seed"     => opts.seed     = next_value(&mut it, arg)?.parse()?,
+            "--target"   => opts.targets.push(next_value(&mut it, arg)?.to_string()),
+            "--by" => {
+                opts.entities = next_value(&mut it, arg)?
+                    .split(',')
+                    .map(str::parse)
+                    .collect::<anyhow::Result<_>>()?;
+            }
+            other => anyhow::bail!("simulate: unknown argument `{other}`"),
+        }
+    }
+
+    let response = global.source.load()?;
+    info!("Sampling {} circuits...", opts.circuits);
+    let simulation = simulate::Simulation::run(&response.relays, &opts)?;
+    print!("{}", simulation.render(format, top)?);
+    Ok(())
+}
+
+/// `metrics [--textfile PATH] [--listen ADDR] [--interval SECS]` — Prometheus
+/// exposition, written once to a textfile or served continuously over HTTP.
+fn run_metrics(global: &Global, args: &[String]) -> anyhow::Result<()> {
+    let mut textfile = None;
+    let mut listen   = None;
+    let mut interval = 300u64;
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--textfile" => textfile = Some(next_value(&mut it, arg)?.to_string()),
+            "--listen"   => listen   = Some(next_value(&mut it, arg)?.to_string()),
+            "--interval" => interval = next_value(&mut it, arg)?.parse()?,
+            other        => anyhow::bail!("metrics: unknown argument `{other}`"),
+        }
+    }
+
+    if let Some(listen) = listen {
+        // `serve` with no outputs is exactly the old metrics-only server.
+        let opts = serve::Options {
+            listen,
+            interval: Duration::from_secs(interval),
+            config:   Config { output_dir: None, compress: Vec::new(), outputs: Vec::new() },
+            output:   output::Options::default(),
+        };
+        let source = global.source.clone();
+        return serve::run(opts, move || source.load());
+    }
+
+    let mut exporter = metrics::Exporter::default();
+    exporter.refresh(|| global.source.load());
+    match textfile {
+        Some(path) => metrics::write_textfile(&exporter, &path)?,
+        None       => print!("{}", exporter.render()),
+    }
+    // Still publish the error counter, but make the failure visible to cron.
+    anyhow::ensure!(exporter.has_snapshot(), "fetch failed; metrics contain no relay data");
+    Ok(())
+}
+
+/// `serve [--listen ADDR] [--interval SECS] [--config FILE] [--name TEMPLATE]…`
+/// — serve every output of the config and `/metrics` over HTTP, refreshed
+/// in the background.
+fn run_serve(global: &Global, args: &[String]) -> anyhow::Result<()> {
+    let mut listen      = "127.0.0.1:9877".to_string();
+    let mut interval    = 300u64;
+    let mut config_path = None;
+    let mut out         = global.output();
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--listen"   => listen      = next_value(&mut it, arg)?.to_string(),
+            "--interval" => interval    = next_value(&mut it, arg)?.parse()?,
+            "--config"   => config_path = Some(next_value(&mut it, arg)?),
+            "--name"     => out.templates.push(next_value(&mut it, arg)?.to_string()),
+            other        => anyhow::bail!("serve: unknown argument `{other}`"),
+        }
+    }
+    let config = config_path.map_or_else(|| Ok(Config::default()), Config::load)?;
+    config.apply(&mut out)?;
+    out.check()?;
+
+    let opts = serve::Options {
+        listen,
+        interval: Duration::from_secs(interval),
+        config,
+        output:   out,
+    };
+    let source = global.source.clone();
+    serve::run(opts, move || source.load())
+}
+
+/// `watch [--interval MIN] [--offset MIN] [--max-backoff MIN] [--config FILE]
+/// [--no-csv] [--map NAME] [--stats NAME] [--stats-format F] [--metrics PATH]`,
+/// plus the output and validation flags — refresh and regenerate outputs
+/// forever.
+fn run_watch(global: &Global, args: &[String]) -> anyhow::Result<()> {
+    let mut opts         = daemon::Options { output: global.output(), ..Default::default() };
+    let mut config_path  = None;
+    let mut no_csv       = false;
+    let mut map_path     = None;
+    let mut stats_format = "markdown";
+    let mut stats_path   = None;
+    let mut validation   = Validation::default();
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--interval"     => opts.interval    = minutes(arg, next_value(&mut it, arg)?)?,
+            "--offset"       => opts.offset      = minutes(arg, next_value(&mut it, arg)?)?,
+            "--max-backoff"  => opts.max_backoff = minutes(arg, next_value(&mut it, arg)?)?,
+            "--config"       => config_path      = Some(next_value(&mut it, arg)?),
+            "--no-csv"       => no_csv           = true,
+            "--map"          => map_path         = Some(next_value(&mut it, arg)?),
+            "--metrics"      => opts.metrics     = Some(PathBuf::from(next_value(&mut it, arg)?)),
+            "--stats"        => stats_path       = Some(next_value(&mut it, arg)?),
+            "--stats-format" => stats_format     = next_value(&mut it, arg)?,
+            other => {
+                if !validation.parse_flag(other, &mut it)?
+                    && !parse_output_flag(other, &mut it, &mut opts.output)?
+                {
+                    anyhow::bail!("watch: unknown argument `{other}`");
+                }
+            }
+        }
+    }
+    anyhow::ensure!(!opts.interval.is_zero(), "--interval must be at least 1 minute");
+    opts.validation = validation.resolve()?;
+
+    // The flags below predate config files and adjust whichever one is used.
+    let mut config = config_path.map_or_else(|| Ok(Config::default()), Config::load)?;
+    if no_csv {
+        config.outputs.retain(|o| o.format != Format::Csv);
+    }
+    if let Some(path) = map_path {
+        if !config.has(Format::Svg) {
+            config.outputs.push(OutputSpec::new("map", Format::Svg));
+        }
+        for spec in config.outputs.iter_mut().filter(|o| o.format == Format::Svg) {
+            spec.path = vec![path.to_string()];
+        }
+    }
+    if let Some(path) = stats_path {
+        let mut spec = OutputSpec::new("stats", Format::Stats);
+        spec.path = vec![path.to_string()];
+        spec.report = Some(stats_format.to_string());
+        config.outputs.push(spec);
+    }
+    config.check()?;
+    config.apply(&mut opts.output)?;
+    opts.config = config;
+
+    daemon::run(&opts, &global.source)
+}
+
+fn next_value<'a>(it: &mut impl Iterator<Item = &'a String>, flag: &str) -> anyhow::Result<&'a str> {
+    it.next()
+        .map(String::as_str)
+        .ok_or_else(|| anyhow::anyhow!("{flag} requires a value"))
+}
+
+/// `--categories` names, comma-separated; `none` for none.
+fn category_list(value: &str) -> Vec<String> {
+    match value {
+        "none" => Vec::new(),
+        list   => list.split(',').map(str::to_string).collect(),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Entry point
+// ---------------------------------------------------------------------------
+
+/// Run the command line `args` (without the program name) and exit with
+/// its status: 0, 1 on error, or [`validate::EXIT_VALIDATION`].
+pub fn main(args: Vec<String>) -> ! {
+    let result = run(args);
+    summary::finish(&result);
+    if let Err(e) = result {
+        log::write(Level::Error, format_args!("{e:?}"));
+        let code = if e.is::<validate::ValidationError>() { validate::EXIT_VALIDATION } else { 1 };
+        process::exit(code);
+    }
+    process::exit(0);
+}
+
+fn run(args: Vec<String>) -> anyhow::Result<()> {
+    let (global, args) = take_global(args)?;
+    let rest = args.get(1..).unwrap_or_default();
+    if let Some(path) = &global.summary {
+        let command = args.first().filter(|a| !a.starts_with('-')).map_or("export", String::as_str);
+        summary::begin(path.clone(), command);
+    }
+
+    match args.first().map(String::as_str) {
+        None                                => run_export(&global, &[]),
+        Some(flag) if flag.starts_with('-') && !matches!(flag, "-h" | "--help") => run_export(&global, &args),
+        Some("export")    => run_export(&global, rest),
+        Some("fetch")     => run_fetch(&global, rest),
+        Some("map")       => run_map(&global, rest),
+        Some("timelapse") => run_timelapse(&global, rest),
+        Some("check")     => run_check(&global, rest),
+        Some("diff")      => run_diff(&global, rest),
+        Some("stats")     => run_stats(&global, rest),
+        Some("diversity") => run_diversity(&global, rest),
+        Some("simulate")  => run_simulate(&global, rest),
+        Some("metrics")   => run_metrics(&global, rest),
+        Some("serve")     => run_serve(&global, rest),
+        Some("watch")     => run_watch(&global, rest),
+        Some("help" | "-h" | "--help") => {
+            print!("{USAGE}");
+            Ok(())
+        }
+        Some(other) => anyhow::bail!("unknown command `{other}`; see `tor-node-parser help`"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hours_take_fractions_but_not_nonsense() {
+        assert_eq!(hours("6").unwrap(), Duration::from_secs(6 * 3_600));
+        assert_eq!(hours("0.5").unwrap(), Duration::from_secs(1_800));
+        for bad in ["-1", "nan", "inf", "1e300", "six", ""] {
+            assert!(hours(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn minutes_are_whole_and_bounded() {
+        assert_eq!(minutes("--offset", "10").unwrap(), Duration::from_secs(600));
+        let err = minutes("--interval", "18446744073709551615").unwrap_err();
+        assert_eq!(err.to_string(), "--interval: 18446744073709551615 minutes is too large");
+        for bad in ["-1", "1.5", "ten", ""] {
+            assert!(minutes("--offset", bad).is_err(), "{bad}");
+        }
+    }
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn global_options_are_taken_from_anywhere() {
+        let (global, rest) = take_global(args("stats --output-dir /srv --top 5 --input saved.json")).unwrap();
+        assert_eq!(rest, ["stats", "--top", "5"]);
+        assert_eq!(global.output().dir, PathBuf::from("/srv"));
+        assert!(matches!(global.source, Source::File(ref path) if path == &PathBuf::from("saved.json")));
+
+        let (global, rest) = take_global(args("export --no-cache --min-relays 10")).unwrap();
+        assert_eq!(rest, ["export", "--min-relays", "10"]);
+        assert!(matches!(global.source, Source::Live(ref f) if f.cache_dir.is_none()));
+        assert_eq!(global.output().dir, PathBuf::from("."));
+    }
+
+    #[test]
+    fn fetch_options_override_the_defaults() {
+        let (global, _) = take_global(args("--mirror http://a/ check --mirror http://b/ --timeout 5 --retries 0")).unwrap();
+        let Source::Live(fetcher) = global.source else { panic!("expected a live source") };
+        assert_eq!(fetcher.urls, ["http://a/", "http://b/"]);
+        assert_eq!(fetcher.read_timeout, Duration::from_secs(5));
+        assert_eq!(fetcher.retries, 0);
+
+        for bad in ["--timeout 0", "--timeout -1", "--retries many", "--mirror"] {
+            assert!(take_global(args(bad)).is_err(), "{bad}");
+        }
+    }
+
+    /// Everything that depends on the process-wide strict flag, in one test
+    /// so that no other test sees it set.
+    #[test]
+    fn strict_mode_rejects_anomalies_and_conflicts_with_no_validate() {
+        let flags = |line: &str| {
+            let mut validation = Validation::default();
+            let args = args(line);
+            let mut it = args.iter();
+            while let Some(arg) = it.next() {
+                assert!(validation.parse_flag(arg, &mut it).unwrap(), "{arg}");
+            }
+            validation.resolve()
+        };
+        let malformed: OnionooResponse = serde_json::from_value(serde_json::json!({
+            "relays_published": "2024-05-01 12:00:00",
+            "relays": [{ "fingerprint": "not hex" }],
+        }))
+        .unwrap();
+
+        assert!(flags("--no-validate").unwrap().is_none());
+        assert_eq!(flags("--min-relays 10 --max-drop 50").unwrap().unwrap().max_drop, 0.5);
+        let err = flags("--no-validate --min-exits 1").err().unwrap();
+        assert_eq!(err.to_string(), "--no-validate and --min-exits contradict each other");
+        assert_eq!(validate::report_records(&malformed).unwrap().len(), 1);
+
+        validate::set_strict(true);
+        let err = flags("--no-validate").err().unwrap();
+        assert_eq!(err.to_string(), "--no-validate and --strict contradict each other");
+        // Rejected every time, even once it has been logged.
+        for _ in 0..2 {
+            let err = validate::report_records(&malformed).unwrap_err();
+            assert_eq!(err.problems, ["1 malformed relay records (strict mode)"]);
+        }
+        validate::set_strict(false);
+    }
+}
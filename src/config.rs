@@ -0,0 +1,625 @@
+//! config.rs — declarative description of the outputs a run produces.
+//!
+//! A config is a TOML file with one `[[output]]` table per artifact, each
+//! naming a relay filter, a format, the columns to include and where to
+//! write it. The built-in [`DEFAULT_CONFIG`] is exactly the classic
+//! `all.csv`, `guards.csv`, `exits.csv` and `map.svg` set, so running without
+//! `--config` behaves as before.
+//!
+//! ```toml
+//! output_dir = "/var/www/tor"
+//! compress   = ["gz"]
+//!
+//! [[output]]
+//! category = "exits-de"
+//! format   = "nftables"
+//! filter   = { flags = ["Exit"], countries = ["de"] }
+//! ```
+
+use std::{collections::HashSet, fs, net::IpAddr, path::PathBuf, str::FromStr};
+
+use serde::Deserialize;
+
+use crate::{
+    onionoo::{parse_or_address, TorNode},
+    output::{self, Compression},
+    projection, raster, region, stats, theme, world_map,
+};
+
+/// The built-in config used when no `--config` is given.
+pub const DEFAULT_CONFIG: &str = include_str!("../config/default.toml");
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Used unless `--output-dir` is given.
+    pub output_dir: Option<PathBuf>,
+    /// Used unless `--compress` is given: any of `"gz"`, `"zst"`.
+    #[serde(default)]
+    pub compress: Vec<String>,
+    #[serde(default, rename = "output")]
+    pub outputs: Vec<OutputSpec>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputSpec {
+    /// Substituted for `{category}` in filename templates.
+    pub category: String,
+    pub format: Format,
+    /// Filename templates; defaults to the command's `--name` templates,
+    /// i.e. `{category}.{ext}`.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub path: Vec<String>,
+    #[serde(default)]
+    pub filter: Filter,
+    /// Columns for `csv`, `json` and `mmdb`; see [`Column`].
+    pub columns: Option<Vec<Column>>,
+    /// Report format for `stats`: `markdown` (default), `text` or `json`.
+    pub report: Option<String>,
+    /// nftables table (in the `inet` family) holding the sets; default `tor`.
+    pub table: Option<String>,
+    /// Row order; see [`Sort`].
+    #[serde(default)]
+    pub sort: Sort,
+    /// Map projection for `svg` and `html`, e.g. `"robinson"` or
+    /// `"orthographic:10,50"`; see [`crate::projection`].
+    pub projection: Option<String>,
+    /// Region a map shows instead of the world, e.g. `"europe"`,
+    /// `"de"` or `"5.5,47,15.5,55.5"`; see [`crate::region`].
+    pub region: Option<String>,
+    /// Regions drawn enlarged in the corner of a map.
+    #[serde(default)]
+    pub insets: Vec<String>,
+    /// Shade countries in an `svg` map by `relays`, `guards`, `exits` or
+    /// `consensus-weight` instead of plotting relays.
+    pub choropleth: Option<String>,
+    /// Bin relays in an `svg` map into `hexbin[:SIZE]` or `grid[:SIZE]`
+    /// cells instead of drawing a dot each.
+    pub density: Option<String>,
+    /// What density cells are coloured by: `relays` (default) or `bandwidth`.
+    pub density_by: Option<String>,
+    /// Scale map dots by `bandwidth`, `consensus-weight` or
+    /// `exit-probability`.
+    pub marker_size: Option<String>,
+    /// Relays a dot map sets apart besides guards, exits and middles:
+    /// `guard-exit`, `bad-exit`, `middle-only`, `authority`, `hsdir`,
+    /// `ipv6`, `geolite2` or `all`; by default `guard-exit` and `bad-exit`.
+    pub categories: Option<Vec<String>>,
+    /// Map colours and font: a built-in theme such as `"light"` or
+    /// `"okabe-ito"`, or a theme file; see [`crate::theme`].
+    pub theme: Option<String>,
+    /// Width of a `png` or `webp` map in pixels; by default the SVG's
+    /// 1200 at `dpi`.
+    pub width: Option<u32>,
+    /// Resolution of a `png` or `webp` map, default 96; recorded in PNGs.
+    pub dpi: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Csv,
+    Json,
+    Nftables,
+    Mmdb,
+    Svg,
+    Html,
+    Png,
+    Webp,
+    Stats,
+}
+
+/// Which relays, and which of their addresses, an output covers. All
+/// given conditions must hold; an empty filter matches everything.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filter {
+    /// Flags a relay must all have, e.g. `["Guard", "Stable"]`.
+    pub flags: Vec<String>,
+    /// Flags a relay must not have, e.g. `["BadExit"]`.
+    pub exclude_flags: Vec<String>,
+    /// Two-letter country codes, any of which matches.
+    pub countries: Vec<String>,
+    pub exclude_countries: Vec<String>,
+    /// AS numbers such as `"AS24940"`, any of which matches.
+    pub as_numbers: Vec<String>,
+    pub min_consensus_weight: Option<u64>,
+    /// Keep only `ipv4` or `ipv6` addresses (rows, set entries, …).
+    pub address_family: Option<AddressFamily>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+/// Order of an output's rows (and of the relays drawn on a map). Either
+/// canonical order makes the file depend only on the relays it contains,
+/// not on the order Onionoo happened to list them in, so successive
+/// snapshots diff cleanly and identical inputs give identical bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    /// By fingerprint, then address.
+    #[default]
+    Fingerprint,
+    /// By IP address in numeric order (IPv4 first), then port and fingerprint.
+    Address,
+    /// As listed in the Onionoo document.
+    Document,
+}
+
+/// Per-address columns for tabular formats. Relay-level columns repeat on
+/// every row of a relay with several OR addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Column {
+    Fingerprint,
+    Ipaddr,
+    Port,
+    Flags,
+    Country,
+    #[serde(rename = "as")]
+    AsNumber,
+    AsName,
+    Version,
+    Platform,
+    ConsensusWeight,
+    AdvertisedBandwidth,
+    GuardProbability,
+    MiddleProbability,
+    ExitProbability,
+    Latitude,
+    Longitude,
+    Contact,
+}
+
+fn one_or_many<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(d)? {
+        OneOrMany::One(s)  => vec![s],
+        OneOrMany::Many(v) => v,
+    })
+}
+
+// ---------------------------------------------------------------------------
+// Loading
+// ---------------------------------------------------------------------------
+
+impl Default for Config {
+    fn default() -> Self {
+        DEFAULT_CONFIG.parse().expect("built-in config is valid")
+    }
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let config: Config = toml::from_str(s)?;
+        config.check()?;
+        Ok(config)
+    }
+}
+
+impl Config {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("cannot read config {path}: {e}"))?;
+        text.parse().map_err(|e: anyhow::Error| e.context(format!("invalid config {path}")))
+    }
+
+    /// A config with just the world map, as rendered by `world-map`.
+    pub fn map_only() -> Self {
+        Self {
+            output_dir: None,
+            compress:   Vec::new(),
+            outputs:    vec![OutputSpec::new("map", Format::Svg)],
+        }
+    }
+
+    pub fn check(&self) -> anyhow::Result<()> {
+        for c in &self.compress {
+            c.parse::<Compression>()?;
+        }
+        for spec in &self.outputs {
+            spec.check().map_err(|e| e.context(format!("output `{}`", spec.category)))?;
+        }
+        Ok(())
+    }
+
+    /// Fill in whatever the command line left at its default.
+    pub fn apply(&self, out: &mut output::Options) -> anyhow::Result<()> {
+        if out.dir.as_os_str() == "." {
+            if let Some(dir) = &self.output_dir {
+                out.dir = dir.clone();
+            }
+        }
+        if out.compress.is_empty() {
+            out.compress = self.compress.iter().map(|c| c.parse()).collect::<anyhow::Result<_>>()?;
+        }
+        Ok(())
+    }
+
+    pub fn has(&self, format: Format) -> bool {
+        self.outputs.iter().any(|o| o.format == format)
+    }
+
+    /// Whether any output is a map, and so needs the world GeoJSON.
+    pub fn has_map(&self) -> bool {
+        self.outputs.iter().any(|o| o.format.is_map())
+    }
+}
+
+impl OutputSpec {
+    pub fn new(category: &str, format: Format) -> Self {
+        Self {
+            category: category.to_string(),
+            format,
+            path:    Vec::new(),
+            filter:  Filter::default(),
+            columns: None,
+            report:  None,
+            table:   None,
+            sort:    Sort::default(),
+            projection: None,
+            region:  None,
+            insets:  Vec::new(),
+            choropleth: None,
+            density: None,
+            density_by: None,
+            marker_size: None,
+            categories: None,
+            theme:   None,
+            width:   None,
+            dpi:     None,
+        }
+    }
+
+    fn check(&self) -> anyhow::Result<()> {
+        self.path.iter().try_for_each(|t| output::check_template(t))?;
+        if self.columns.is_some() {
+            anyhow::ensure!(
+                matches!(self.format, Format::Csv | Format::Json | Format::Mmdb),
+                "`columns` only applies to csv, json and mmdb"
+            );
+        }
+        if self.report.is_some() {
+            anyhow::ensure!(self.format == Format::Stats, "`report` only applies to stats");
+        }
+        self.report_format()?;
+        if self.table.is_some() {
+            anyhow::ensure!(self.format == Format::Nftables, "`table` only applies to nftables");
+        }
+        if let Some(spec) = &self.projection {
+            anyhow::ensure!(self.format.is_map(), "`projection` only applies to maps");
+            projection::parse(spec)?;
+        }
+        if let Some(spec) = &self.region {
+            anyhow::ensure!(self.format.is_map(), "`region` only applies to maps");
+            region::parse(spec)?;
+        }
+        if !self.insets.is_empty() {
+            anyhow::ensure!(self.format.is_map(), "`insets` only applies to maps");
+            anyhow::ensure!(
+                self.insets.len() <= world_map::MAX_INSETS,
+                "at most {} insets fit on a map",
+                world_map::MAX_INSETS
+            );
+            self.insets.iter().try_for_each(|spec| region::parse(spec).map(drop))?;
+        }
+        if let Some(metric) = &self.choropleth {
+            anyhow::ensure!(self.format.is_image(), "`choropleth` only applies to svg, png and webp");
+            metric.parse::<world_map::Choropleth>()?;
+        }
+        if self.density_by.is_some() {
+            anyhow::ensure!(self.density.is_some(), "`density_by` needs `density`");
+        }
+        if let Some(spec) = &self.density {
+            anyhow::ensure!(self.format.is_image(), "`density` only applies to svg, png and webp");
+            anyhow::ensure!(self.choropleth.is_none(), "`density` and `choropleth` are different map modes; pick one");
+            world_map::Density::parse(spec, self.density_by.as_deref())?;
+        }
+        if let Some(metric) = &self.marker_size {
+            anyhow::ensure!(self.format.is_map(), "`marker_size` only applies to maps");
+            anyhow::ensure!(
+                self.choropleth.is_none() && self.density.is_none(),
+                "`marker_size` only applies to maps with a dot per relay, not `choropleth` or `density`"
+            );
+            metric.parse::<world_map::MarkerSize>()?;
+        }
+        if let Some(names) = &self.categories {
+            anyhow::ensure!(self.format.is_map(), "`categories` only applies to maps");
+            anyhow::ensure!(
+                self.choropleth.is_none() && self.density.is_none(),
+                "`categories` only applies to maps with a dot per relay, not `choropleth` or `density`"
+            );
+            world_map::parse_categories(names)?;
+        }
+        if let Some(spec) = &self.theme {
+            anyhow::ensure!(self.format.is_map(), "`theme` only applies to maps");
+            theme::load(spec)?;
+        }
+        if self.width.is_some() || self.dpi.is_some() {
+            anyhow::ensure!(
+                matches!(self.format, Format::Png | Format::Webp),
+                "`width` and `dpi` only apply to png and webp"
+            );
+            self.raster().check()?;
+        }
+        Ok(())
+    }
+
+    pub fn columns(&self) -> Vec<Column> {
+        use Column::*;
+        match (&self.columns, self.format) {
+            (Some(columns), _)  => columns.clone(),
+            (None, Format::Mmdb) => vec![Fingerprint, Flags, Country, AsNumber, AsName],
+            (None, _)            => vec![Fingerprint, Ipaddr, Port],
+        }
+    }
+
+    /// Relays selected by the filter, each listed once, in [`Sort`] order
+    /// (by fingerprint unless `document`).
+    pub fn relays<'a>(&self, nodes: &'a [TorNode]) -> Vec<&'a TorNode> {
+        let mut seen = HashSet::new();
+        let mut relays: Vec<&TorNode> = nodes
+            .iter()
+            .filter(|node| self.filter.matches(node) && seen.insert(node.fingerprint.as_str()))
+            .collect();
+        if self.sort != Sort::Document {
+            relays.sort_by(|a, b| a.fingerprint.cmp(&b.fingerprint));
+        }
+        relays
+    }
+
+    /// The `(relay, ip, port)` rows of this output: [`Filter::rows`] with
+    /// repeated rows removed, in [`Sort`] order.
+    pub fn rows<'a>(&'a self, nodes: &'a [TorNode]) -> Vec<(&'a TorNode, IpAddr, u16)> {
+        let mut seen = HashSet::new();
+        let mut rows: Vec<_> = self
+            .filter
+            .rows(nodes)
+            .filter(|(node, ip, port)| seen.insert((node.fingerprint.as_str(), *ip, *port)))
+            .collect();
+        match self.sort {
+            Sort::Fingerprint => rows.sort_by(|a, b| (&a.0.fingerprint, a.1, a.2).cmp(&(&b.0.fingerprint, b.1, b.2))),
+            Sort::Address     => rows.sort_by(|a, b| (a.1, a.2, &a.0.fingerprint).cmp(&(b.1, b.2, &b.0.fingerprint))),
+            Sort::Document    => {}
+        }
+        rows
+    }
+
+    pub fn raster(&self) -> raster::Options {
+        raster::Options { width: self.width, dpi: self.dpi.unwrap_or(raster::DEFAULT_DPI) }
+    }
+
+    pub fn report_format(&self) -> anyhow::Result<stats::Format> {
+        self.report.as_deref().map_or(Ok(stats::Format::Markdown), str::parse)
+    }
+
+    /// The `{ext}` of this output's files.
+    pub fn extension(&self) -> &'static str {
+        match self.format {
+            Format::Csv      => "csv",
+            Format::Json     => "json",
+            Format::Nftables => "nft",
+            Format::Mmdb     => "mmdb",
+            Format::Svg      => "svg",
+            Format::Html     => "html",
+            Format::Png      => "png",
+            Format::Webp     => "webp",
+            Format::Stats    => match self.report_format() {
+                Ok(stats::Format::Json)     => "json",
+                Ok(stats::Format::Text)     => "txt",
+                _                           => "md",
+            },
+        }
+    }
+}
+
+impl Format {
+    /// Name as written in the config.
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Csv      => "csv",
+            Format::Json     => "json",
+            Format::Nftables => "nftables",
+            Format::Mmdb     => "mmdb",
+            Format::Svg      => "svg",
+            Format::Html     => "html",
+            Format::Png      => "png",
+            Format::Webp     => "webp",
+            Format::Stats    => "stats",
+        }
+    }
+
+    /// Every format drawn by [`world_map`].
+    pub fn is_map(self) -> bool {
+        matches!(self, Format::Svg | Format::Html | Format::Png | Format::Webp)
+    }
+
+    /// The maps that are a still image rather than an interactive page.
+    pub fn is_image(self) -> bool {
+        self.is_map() && self != Format::Html
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Filtering
+// ---------------------------------------------------------------------------
+
+impl Filter {
+    pub fn matches(&self, node: &TorNode) -> bool {
+        let in_list = |list: &[String], value: Option<&str>| {
+            value.is_some_and(|v| list.iter().any(|l| l.eq_ignore_ascii_case(v)))
+        };
+        self.flags.iter().all(|f| node.has_flag(f))
+            && !self.exclude_flags.iter().any(|f| node.has_flag(f))
+            && (self.countries.is_empty() || in_list(&self.countries, node.country.as_deref()))
+            && !in_list(&self.exclude_countries, node.country.as_deref())
+            && (self.as_numbers.is_empty() || in_list(&self.as_numbers, node.as_number.as_deref()))
+            && self.min_consensus_weight.is_none_or(|min| node.consensus_weight >= min)
+    }
+
+    /// `node`'s OR addresses allowed by `address_family`, each once.
+    /// IPv4-mapped IPv6 addresses (`[::ffff:1.2.3.4]`) count as the IPv4
+    /// address they stand for.
+    pub fn addresses<'a>(&'a self, node: &'a TorNode) -> impl Iterator<Item = (IpAddr, u16)> + 'a {
+        let mut seen = HashSet::new();
+        node.or_addresses
+            .iter()
+            .filter_map(|addr| parse_or_address(addr))
+            .map(|(ip, port)| (ip.to_canonical(), port))
+            .filter(move |&addr| seen.insert(addr))
+            .filter(move |(ip, _)| match self.address_family {
+                None                        => true,
+                Some(AddressFamily::Ipv4)   => ip.is_ipv4(),
+                Some(AddressFamily::Ipv6)   => ip.is_ipv6(),
+            })
+    }
+
+    /// Every `(relay, ip, port)` row this filter selects from `nodes`, in
+    /// document order; [`OutputSpec::rows`] is what outputs are written from.
+    pub fn rows<'a>(&'a self, nodes: &'a [TorNode]) -> impl Iterator<Item = (&'a TorNode, IpAddr, u16)> + 'a {
+        nodes
+            .iter()
+            .filter(|node| self.matches(node))
+            .flat_map(move |node| self.addresses(node).map(move |(ip, port)| (node, ip, port)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn relay(value: serde_json::Value) -> TorNode {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn error(toml: &str) -> String {
+        format!("{:#}", toml.parse::<Config>().unwrap_err())
+    }
+
+    #[test]
+    fn default_config_is_the_classic_set() {
+        let config = Config::default();
+        let outputs: Vec<_> = config.outputs.iter().map(|o| (o.category.as_str(), o.format)).collect();
+        assert_eq!(
+            outputs,
+            [("all", Format::Csv), ("guards", Format::Csv), ("exits", Format::Csv), ("map", Format::Svg)]
+        );
+        assert!(config.has(Format::Svg));
+    }
+
+    #[test]
+    fn path_takes_one_template_or_many() {
+        let config: Config = r#"
+            [[output]]
+            category = "a"
+            format   = "csv"
+            path     = "x/{category}.{ext}"
+
+            [[output]]
+            category = "b"
+            format   = "json"
+            path     = ["{category}.{ext}", "{date}/{category}.{ext}"]
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(config.outputs[0].path, ["x/{category}.{ext}"]);
+        assert_eq!(config.outputs[1].path.len(), 2);
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        let cases = [
+            ("[[outputs]]\ncategory = \"a\"\nformat = \"csv\"", "unknown field"),
+            ("[[output]]\ncategory = \"a\"\nformat = \"xml\"", "unknown variant"),
+            ("[[output]]\ncategory = \"a\"\nformat = \"csv\"\nfilter = { flag = [\"Exit\"] }", "unknown field"),
+            ("compress = [\"bz2\"]", "bz2"),
+            ("[[output]]\ncategory = \"a\"\nformat = \"svg\"\ncolumns = [\"fingerprint\"]", "`columns` only applies"),
+            ("[[output]]\ncategory = \"a\"\nformat = \"csv\"\nreport = \"json\"", "`report` only applies"),
+            ("[[output]]\ncategory = \"a\"\nformat = \"csv\"\ntable = \"t\"", "`table` only applies"),
+            ("[[output]]\ncategory = \"a\"\nformat = \"csv\"\npath = \"../a.csv\"", "relative path"),
+            ("[[output]]\ncategory = \"a\"\nformat = \"csv\"\npath = \"{nope}.csv\"", "unknown placeholder"),
+        ];
+        for (toml, expected) in cases {
+            let message = error(toml);
+            assert!(message.contains(expected), "{toml:?}: {message}");
+        }
+        assert!(error("[[output]]\ncategory = \"bad\"\nformat = \"csv\"\ntable = \"t\"").contains("output `bad`"));
+    }
+
+    #[test]
+    fn filter_conditions_all_hold() {
+        let exit_de = relay(json!({
+            "fingerprint": "A", "flags": ["Exit", "Stable"], "country": "de",
+            "as": "AS3320", "consensus_weight": 500,
+        }));
+        let guard_us = relay(json!({ "fingerprint": "B", "flags": ["Guard"], "country": "us" }));
+
+        let filter: Filter = toml::from_str("flags = [\"exit\"]\ncountries = [\"DE\"]").unwrap();
+        assert!(filter.matches(&exit_de));
+        assert!(!filter.matches(&guard_us));
+
+        let filter: Filter = toml::from_str("exclude_flags = [\"Stable\"]").unwrap();
+        assert!(!filter.matches(&exit_de));
+        assert!(filter.matches(&guard_us));
+
+        let filter: Filter = toml::from_str("as_numbers = [\"AS3320\"]\nmin_consensus_weight = 500").unwrap();
+        assert!(filter.matches(&exit_de));
+        assert!(!filter.matches(&guard_us));
+
+        let filter: Filter = toml::from_str("exclude_countries = [\"us\"]").unwrap();
+        assert!(filter.matches(&exit_de));
+        assert!(!filter.matches(&guard_us));
+
+        assert!(Filter::default().matches(&guard_us));
+    }
+
+    #[test]
+    fn addresses_are_canonical_unique_and_filtered_by_family() {
+        let node = relay(json!({
+            "fingerprint": "A",
+            "or_addresses": ["1.2.3.4:9001", "[::ffff:1.2.3.4]:9001", "[2001:db8::1]:443", "bogus"],
+        }));
+        let all: Vec<_> = Filter::default().addresses(&node).map(|(ip, port)| format!("{ip}:{port}")).collect();
+        assert_eq!(all, ["1.2.3.4:9001", "2001:db8::1:443"]);
+
+        let v6: Filter = toml::from_str("address_family = \"ipv6\"").unwrap();
+        assert_eq!(v6.addresses(&node).count(), 1);
+    }
+
+    #[test]
+    fn rows_are_deduplicated_and_sorted() {
+        let nodes = [
+            relay(json!({ "fingerprint": "B", "or_addresses": ["10.0.0.2:9001", "[::ffff:10.0.0.2]:9001", "[2001:db8::1]:443"] })),
+            relay(json!({ "fingerprint": "A", "or_addresses": ["10.0.0.9:443"] })),
+            relay(json!({ "fingerprint": "B", "or_addresses": ["10.0.0.2:9001"] })),
+            relay(json!({ "fingerprint": "C", "or_addresses": ["9.0.0.1:80"] })),
+        ];
+        let rows = |sort: Sort| {
+            let mut spec = OutputSpec::new("all", Format::Csv);
+            spec.sort = sort;
+            spec.rows(&nodes).iter().map(|(n, ip, port)| format!("{} {ip}:{port}", n.fingerprint)).collect::<Vec<_>>()
+        };
+
+        assert_eq!(rows(Sort::Fingerprint), ["A 10.0.0.9:443", "B 10.0.0.2:9001", "B 2001:db8::1:443", "C 9.0.0.1:80"]);
+        assert_eq!(rows(Sort::Address), ["C 9.0.0.1:80", "B 10.0.0.2:9001", "A 10.0.0.9:443", "B 2001:db8::1:443"]);
+        assert_eq!(rows(Sort::Document), ["B 10.0.0.2:9001", "B 2001:db8::1:443", "A 10.0.0.9:443", "C 9.0.0.1:80"]);
+
+        let spec = OutputSpec::new("all", Format::Csv);
+        let relays: Vec<_> = spec.relays(&nodes).iter().map(|n| n.fingerprint.as_str()).collect();
+        assert_eq!(relays, ["A", "B", "C"]);
+    }
+}
@@ -0,0 +1,86 @@
+//! csv.rs — CSV outputs, by default `fingerprint,ipaddr,port`.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    net::IpAddr,
+    path::Path,
+};
+
+use crate::{config::Column, onionoo::TorNode};
+
+/// Write a header of `columns` and one line per `(relay, ip, port)` row.
+pub fn write<'a>(
+    out: &mut impl Write,
+    columns: &[Column],
+    rows: impl Iterator<Item = (&'a TorNode, IpAddr, u16)>,
+) -> anyhow::Result<()> {
+    let header: Vec<&str> = columns.iter().map(|c| c.name()).collect();
+    writeln!(out, "{}", header.join(","))?;
+    for (node, ip, port) in rows {
+        let fields: Vec<String> = columns.iter().map(|c| escape(&c.text(node, ip, port))).collect();
+        writeln!(out, "{}", fields.join(","))?;
+    }
+    Ok(())
+}
+
+/// Quote a field per RFC 4180 when it contains a separator, quote or newline.
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Data rows of the CSV at `path`, `None` where the file doesn't exist yet.
+pub fn existing_rows(path: &Path) -> Option<usize> {
+    let file = File::open(path).ok()?;
+    Some(records(BufReader::new(file)).saturating_sub(1))
+}
+
+/// Records in `input`, header included, read with the quoting [`escape`]
+/// writes: a newline inside a quoted field does not end its record.
+fn records(input: impl BufRead) -> usize {
+    let mut records = 0;
+    let mut quoted  = false;
+    let mut pending = false;
+    for byte in input.bytes().map_while(Result::ok) {
+        match byte {
+            b'\n' if !quoted => {
+                records += 1;
+                pending = false;
+                continue;
+            }
+            b'"' => quoted = !quoted,
+            _ => {}
+        }
+        pending = true;
+    }
+    records + usize::from(pending)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_newlines_do_not_end_a_record() {
+        let text = "fingerprint,contact\nA,\"line one\nline two\"\nB,\"say \"\"hi\"\"\"\n";
+        assert_eq!(records(text.as_bytes()), 3);
+    }
+
+    #[test]
+    fn a_missing_final_newline_still_ends_the_last_record() {
+        assert_eq!(records("a\nb\nc".as_bytes()), 3);
+        assert_eq!(records("a\nb\nc\n".as_bytes()), 3);
+        assert_eq!(records("".as_bytes()), 0);
+    }
+
+    #[test]
+    fn escaped_fields_count_back_to_their_rows() {
+        let fields = ["plain", "a,b", "multi\nline", "\"quoted\""];
+        let text: String = fields.iter().map(|f| format!("{}\n", escape(f))).collect();
+        assert_eq!(records(text.as_bytes()), fields.len());
+    }
+}
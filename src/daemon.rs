@@ -0,0 +1,251 @@
+//! daemon.rs — long-running `watch` mode.
+//!
+//! Onionoo refreshes its documents roughly once an hour, shortly after each
+//! consensus is published on the hour. Rather than polling blindly, each
+//! cycle is scheduled at a fixed `offset` past an `interval`-aligned slot
+//! (by default 10 minutes past every hour), so the run lines up with fresh
+//! data. A document whose `relays_published` hasn't moved since the last
+//! cycle is not re-rendered.
+//!
+//! Documents failing [`validate::check`] are treated like fetch errors: the
+//! existing outputs stay untouched and the cycle is retried with backoff.
+//!
+//! Every output of the [`Config`] is rendered each cycle through one
+//! [`output::Transaction`], so they are published together or not at all.
+//! On errors the loop retries with exponential backoff (1 min, 2 min, 4 min,
+//! … up to `max_backoff`) before falling back to the regular schedule. With
+//! `--summary` each cycle, including failed ones, rewrites the run summary.
+
+use std::{
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    config::Config,
+    export,
+    metrics::{self, Exporter},
+    onionoo::{OnionooResponse, Source},
+    output, summary,
+    validate::{self, Thresholds},
+    world_map,
+};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(60);
+
+pub struct Options {
+    pub interval: Duration,
+    pub offset: Duration,
+    pub max_backoff: Duration,
+    /// Output directory, layout, filename templates and compression.
+    pub output: output::Options,
+    pub config: Config,
+    pub metrics: Option<PathBuf>,
+    /// `None` skips validation entirely.
+    pub validation: Option<Thresholds>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            interval:    Duration::from_secs(60 * 60),
+            offset:      Duration::from_secs(10 * 60),
+            max_backoff: Duration::from_secs(30 * 60),
+            output:      output::Options::default(),
+            config:      Config::default(),
+            metrics:     None,
+            validation:  Some(Thresholds::default()),
+        }
+    }
+}
+
+fn now_unix() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// The first instant strictly after `now` that lies `offset` past a multiple
+/// of `interval` (all in Unix seconds).
+fn next_slot(now: u64, interval: u64, offset: u64) -> u64 {
+    let interval = interval.max(1);
+    let offset = offset % interval;
+    let base = now.saturating_sub(offset) / interval * interval + offset;
+    if base > now { base } else { base + interval }
+}
+
+/// `hh:mm:ss` UTC for log lines.
+fn clock(unix: u64) -> String {
+    format!("{:02}:{:02}:{:02}", unix / 3600 % 24, unix / 60 % 60, unix % 60)
+}
+
+/// Loop forever: load from `source`, regenerate outputs, sleep until the
+/// next slot.
+pub fn run(opts: &Options, source: &Source) -> anyhow::Result<()> {
+    opts.output.check()?;
+    let geojson = opts.config.has_map().then(world_map::world_geojson).transpose()?;
+    let mut exporter = Exporter::default();
+    let mut last_published: Option<String> = None;
+    let mut failures = 0u32;
+
+    info!(
+        "Watch mode: refreshing every {}s at +{}s offset.",
+        opts.interval.as_secs(),
+        opts.offset.as_secs() % opts.interval.as_secs().max(1),
+    );
+
+    for cycle in 1u64.. {
+        summary::restart();
+        let started = Instant::now();
+        let result = match source.load() {
+            Ok(response) => {
+                exporter.record_success(&response, started.elapsed());
+                regenerate(cycle, response, opts, geojson.as_ref(), &mut last_published, started)
+            }
+            Err(e) => {
+                exporter.record_failure(started.elapsed());
+                Err(e)
+            }
+        };
+
+        summary::finish(&result);
+        let sleep = match result {
+            Ok(()) => {
+                failures = 0;
+                let now = now_unix();
+                let next = next_slot(now, opts.interval.as_secs(), opts.offset.as_secs());
+                info!("Next refresh at {} UTC.", clock(next));
+                Duration::from_secs(next - now)
+            }
+            Err(e) => {
+                failures += 1;
+                let backoff = INITIAL_BACKOFF
+                    .saturating_mul(1 << (failures - 1).min(16))
+                    .min(opts.max_backoff);
+                warn!(
+                    "Cycle {cycle} failed ({failures} in a row): {e:#}. Retrying in {}s.",
+                    backoff.as_secs(),
+                );
+                backoff
+            }
+        };
+
+        if let Some(path) = &opts.metrics {
+            if let Err(e) = metrics::write_textfile(&exporter, &path.to_string_lossy()) {
+                warn!("Could not write metrics: {e:#}");
+            }
+        }
+        thread::sleep(sleep);
+    }
+    Ok(())
+}
+
+/// Regenerate outputs for a freshly fetched document unless it is the same
+/// publication as last cycle's.
+fn regenerate(
+    cycle: u64,
+    response: OnionooResponse,
+    opts: &Options,
+    geojson: Option<&serde_json::Value>,
+    last_published: &mut Option<String>,
+    started: Instant,
+) -> anyhow::Result<()> {
+    if response.relays_published.is_some() && response.relays_published == *last_published {
+        info!(
+            "Cycle {cycle}: relays_published unchanged ({}), outputs left as-is.",
+            response.relays_published.as_deref().unwrap_or_default(),
+        );
+        return Ok(());
+    }
+    if let Some(thresholds) = &opts.validation {
+        validate::check(&response, thresholds, &opts.config, &opts.output)?;
+    }
+    let written = write_outputs(&response, opts, geojson)?;
+    info!(
+        "Cycle {cycle}: {} relays (published {}), wrote {} in {:.1}s.",
+        response.relays.len(),
+        response.relays_published.as_deref().unwrap_or("unknown"),
+        written.join(", "),
+        started.elapsed().as_secs_f64(),
+    );
+    *last_published = response.relays_published;
+    Ok(())
+}
+
+/// Render every configured output into one transaction and commit it.
+/// Returns the names of the files written.
+fn write_outputs(
+    response: &OnionooResponse,
+    opts: &Options,
+    geojson: Option<&serde_json::Value>,
+) -> anyhow::Result<Vec<String>> {
+    let mut tx = opts.output.begin()?;
+    export::write_all(&mut tx, &opts.config, response, &opts.output, geojson)?;
+    let written = tx.files().to_vec();
+    summary::timed("commit", || tx.commit())?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    const HOUR: u64 = 3_600;
+    const MINUTE: u64 = 60;
+
+    #[test]
+    fn slots_are_offset_past_the_interval() {
+        let midnight = 1_714_521_600; // 2024-05-01 00:00:00 UTC
+        assert_eq!(next_slot(midnight, HOUR, 10 * MINUTE), midnight + 10 * MINUTE);
+        assert_eq!(next_slot(midnight + 9 * MINUTE, HOUR, 10 * MINUTE), midnight + 10 * MINUTE);
+        // Strictly after `now`, even exactly on a slot.
+        assert_eq!(next_slot(midnight + 10 * MINUTE, HOUR, 10 * MINUTE), midnight + HOUR + 10 * MINUTE);
+        assert_eq!(next_slot(midnight + 59 * MINUTE, HOUR, 10 * MINUTE), midnight + HOUR + 10 * MINUTE);
+    }
+
+    #[test]
+    fn odd_intervals_and_offsets_are_tamed() {
+        // An offset longer than the interval wraps around it.
+        assert_eq!(next_slot(1_000, 600, 1_300), 1_300);
+        // A zero interval counts as one second rather than dividing by zero.
+        assert_eq!(next_slot(1_000, 0, 0), 1_001);
+        // Shortly after the epoch, before the first offset.
+        assert_eq!(next_slot(5, HOUR, 10 * MINUTE), 10 * MINUTE);
+    }
+
+    #[test]
+    fn clock_is_utc_time_of_day() {
+        assert_eq!(clock(1_714_564_800 + 7 * MINUTE + 5), "12:07:05");
+    }
+
+    #[test]
+    fn unchanged_publication_is_not_rewritten() {
+        let dir = std::env::temp_dir().join(format!("tor-node-parser-{}-daemon", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let config: Config = "[[output]]\ncategory = \"all\"\nformat = \"csv\"".parse().unwrap();
+        let opts = Options {
+            output: output::Options { dir: dir.clone(), ..Default::default() },
+            config,
+            validation: None,
+            ..Default::default()
+        };
+        let response = |published: &str| -> OnionooResponse {
+            serde_json::from_value(serde_json::json!({ "relays_published": published, "relays": [] })).unwrap()
+        };
+
+        let mut last = None;
+        regenerate(1, response("2024-05-01 12:00:00"), &opts, None, &mut last, Instant::now()).unwrap();
+        assert_eq!(last.as_deref(), Some("2024-05-01 12:00:00"));
+        assert!(dir.join("all.csv").exists());
+
+        fs::remove_file(dir.join("all.csv")).unwrap();
+        regenerate(2, response("2024-05-01 12:00:00"), &opts, None, &mut last, Instant::now()).unwrap();
+        assert!(!dir.join("all.csv").exists());
+
+        regenerate(3, response("2024-05-01 13:00:00"), &opts, None, &mut last, Instant::now()).unwrap();
+        assert!(dir.join("all.csv").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+//! diff.rs — what changed between two details documents, for the `diff`
+//! subcommand.
+//!
+//! Relays are matched by fingerprint. The report lists relays that joined or
+//! left, and for relays present in both, the flags they gained or lost and
+//! OR addresses that appeared or disappeared. Per-flag relay counts are shown
+//! side by side so shifts like "200 fewer exits" stand out.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Write,
+};
+
+use serde::Serialize;
+
+use crate::{
+    onionoo::{OnionooResponse, TorNode},
+    stats::Format,
+};
+
+#[derive(Debug, Serialize)]
+pub struct Side {
+    pub relays_published: Option<String>,
+    pub relays: usize,
+    /// Relays carrying each flag.
+    pub flags: BTreeMap<String, usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Relay {
+    pub fingerprint: String,
+    pub country: Option<String>,
+    pub flags: Vec<String>,
+    pub or_addresses: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Change {
+    pub fingerprint: String,
+    pub flags_gained: Vec<String>,
+    pub flags_lost: Vec<String>,
+    pub addresses_added: Vec<String>,
+    pub addresses_removed: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Diff {
+    pub old: Side,
+    pub new: Side,
+    pub added: Vec<Relay>,
+    pub removed: Vec<Relay>,
+    pub changed: Vec<Change>,
+}
+
+fn side(response: &OnionooResponse) -> Side {
+    let mut flags = BTreeMap::new();
+    for node in &response.relays {
+        for flag in &node.flags {
+            *flags.entry(flag.clone()).or_default() += 1;
+        }
+    }
+    Side {
+        relays_published: response.relays_published.clone(),
+        relays: response.relays.len(),
+        flags,
+    }
+}
+
+fn relay(node: &TorNode) -> Relay {
+    Relay {
+        fingerprint:  node.fingerprint.clone(),
+        country:      node.country.clone(),
+        flags:        node.flags.clone(),
+        or_addresses: node.or_addresses.clone(),
+    }
+}
+
+/// Items of `a` not in `b`, sorted.
+fn minus(a: &[String], b: &[String]) -> Vec<String> {
+    let b: BTreeSet<&String> = b.iter().collect();
+    let only: BTreeSet<&String> = a.iter().filter(|x| !b.contains(x)).collect();
+    only.into_iter().cloned().collect()
+}
+
+impl Diff {
+    pub fn build(old: &OnionooResponse, new: &OnionooResponse) -> Self {
+        let old_by_fp: HashMap<&str, &TorNode> = old.relays.iter().map(|n| (n.fingerprint.as_str(), n)).collect();
+        let new_by_fp: HashMap<&str, &TorNode> = new.relays.iter().map(|n| (n.fingerprint.as_str(), n)).collect();
+
+        let mut added: Vec<Relay> = new.relays.iter().filter(|n| !old_by_fp.contains_key(n.fingerprint.as_str())).map(relay).collect();
+        let mut removed: Vec<Relay> = old.relays.iter().filter(|n| !new_by_fp.contains_key(n.fingerprint.as_str())).map(relay).collect();
+        added.sort_by(|a, b| a.fingerprint.cmp(&b.fingerprint));
+        removed.sort_by(|a, b| a.fingerprint.cmp(&b.fingerprint));
+
+        let mut changed: Vec<Change> = new
+            .relays
+            .iter()
+            .filter_map(|n| {
+                let o = old_by_fp.get(n.fingerprint.as_str())?;
+                let change = Change {
+                    fingerprint:       n.fingerprint.clone(),
+                    flags_gained:      minus(&n.flags, &o.flags),
+                    flags_lost:        minus(&o.flags, &n.flags),
+                    addresses_added:   minus(&n.or_addresses, &o.or_addresses),
+                    addresses_removed: minus(&o.or_addresses, &n.or_addresses),
+                };
+                let unchanged = change.flags_gained.is_empty()
+                    && change.flags_lost.is_empty()
+                    && change.addresses_added.is_empty()
+                    && change.addresses_removed.is_empty();
+                (!unchanged).then_some(change)
+            })
+            .collect();
+        changed.sort_by(|a, b| a.fingerprint.cmp(&b.fingerprint));
+
+        Self { old: side(old), new: side(new), added, removed, changed }
+    }
+
+    /// Render the diff. `top` limits each relay list in the text and
+    /// Markdown formats; JSON always contains everything.
+    pub fn render(&self, format: Format, top: usize) -> anyhow::Result<String> {
+        match format {
+            Format::Json     => Ok(serde_json::to_string_pretty(self)? + "\n"),
+            Format::Text     => Ok(self.render_text(top)),
+            Format::Markdown => Ok(self.render_markdown(top)),
+        }
+    }
+
+    /// `(label, old, new)` for the side-by-side counts.
+    fn count_rows(&self) -> Vec<(String, usize, usize)> {
+        let mut rows = vec![("Relays".to_string(), self.old.relays, self.new.relays)];
+        let flags: BTreeSet<&String> = self.old.flags.keys().chain(self.new.flags.keys()).collect();
+        for flag in flags {
+            let count = |side: &Side| side.flags.get(flag).copied().unwrap_or(0);
+            rows.push((flag.clone(), count(&self.old), count(&self.new)));
+        }
+        rows
+    }
+
+    fn published(side: &Side) -> &str {
+        side.relays_published.as_deref().unwrap_or("unknown")
+    }
+
+    fn render_text(&self, top: usize) -> String {
+        let mut s = String::new();
+        s.push_str("Tor relay list changes\n======================\n\n");
+        let _ = writeln!(s, "{:<14}{:>20}  {:>20}", "", "old", "new");
+        let _ = writeln!(s, "{:<14}{:>20}  {:>20}", "Published:", Self::published(&self.old), Self::published(&self.new));
+        for (label, old, new) in self.count_rows() {
+            let _ = writeln!(s, "{:<14}{old:>20}  {new:>20}  {:>+6}", format!("{label}:"), new as i64 - old as i64);
+        }
+
+        for (heading, relays) in [("Added", &self.added), ("Removed", &self.removed)] {
+            let _ = writeln!(s, "\n{heading} ({})", relays.len());
+            if !relays.is_empty() {
+                s.push('\n');
+            }
+            for r in relays.iter().take(top) {
+                let _ = writeln!(
+                    s,
+                    "  {}  {:<3} {}",
+                    r.fingerprint,
+                    r.country.as_deref().unwrap_or("??").to_uppercase(),
+                    r.flags.join(" "),
+                );
+            }
+            if relays.len() > top {
+                let _ = writeln!(s, "  … {} more", relays.len() - top);
+            }
+        }
+
+        let _ = writeln!(s, "\nChanged ({})", self.changed.len());
+        if !self.changed.is_empty() {
+            s.push('\n');
+        }
+        for c in self.changed.iter().take(top) {
+            let _ = writeln!(s, "  {}  {}", c.fingerprint, change_summary(c));
+        }
+        if self.changed.len() > top {
+            let _ = writeln!(s, "  … {} more", self.changed.len() - top);
+        }
+        s
+    }
+
+    fn render_markdown(&self, top: usize) -> String {
+        let mut s = String::new();
+        s.push_str("# Tor relay list changes\n\n| | Old | New | Δ |\n|---|---:|---:|---:|\n");
+        let _ = writeln!(s, "| Published | {} | {} | |", Self::published(&self.old), Self::published(&self.new));
+        for (label, old, new) in self.count_rows() {
+            let _ = writeln!(s, "| {label} | {old} | {new} | {:+} |", new as i64 - old as i64);
+        }
+
+        for (heading, relays) in [("Added", &self.added), ("Removed", &self.removed)] {
+            let _ = writeln!(s, "\n## {heading} ({})\n", relays.len());
+            if relays.is_empty() {
+                continue;
+            }
+            s.push_str("| Fingerprint | Country | Flags |\n|---|---|---|\n");
+            for r in relays.iter().take(top) {
+                let country = r.country.as_deref().unwrap_or("??").to_uppercase();
+                let _ = writeln!(s, "| `{}` | {country} | {} |", r.fingerprint, r.flags.join(" "));
+            }
+            if relays.len() > top {
+                let _ = writeln!(s, "\n_… {} more not shown._", relays.len() - top);
+            }
+        }
+
+        let _ = writeln!(s, "\n## Changed ({})\n", self.changed.len());
+        if !self.changed.is_empty() {
+            s.push_str("| Fingerprint | Changes |\n|---|---|\n");
+            for c in self.changed.iter().take(top) {
+                let _ = writeln!(s, "| `{}` | {} |", c.fingerprint, change_summary(c));
+            }
+            if self.changed.len() > top {
+                let _ = writeln!(s, "\n_… {} more not shown._", self.changed.len() - top);
+            }
+        }
+        s
+    }
+}
+
+/// `+Guard -Stable +1.2.3.4:443 -5.6.7.8:9001`
+fn change_summary(c: &Change) -> String {
+    let signed = |sign: char, items: &[String]| items.iter().map(move |x| format!("{sign}{x}")).collect::<Vec<_>>();
+    [
+        signed('+', &c.flags_gained),
+        signed('-', &c.flags_lost),
+        signed('+', &c.addresses_added),
+        signed('-', &c.addresses_removed),
+    ]
+    .concat()
+    .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn document(relays: serde_json::Value) -> OnionooResponse {
+        serde_json::from_value(json!({ "relays": relays })).unwrap()
+    }
+
+    #[test]
+    fn relays_are_matched_by_fingerprint() {
+        let old = document(json!([
+            { "fingerprint": "A", "flags": ["Guard", "Running"], "or_addresses": ["1.1.1.1:9001"] },
+            { "fingerprint": "B", "flags": ["Exit"] },
+            { "fingerprint": "C", "flags": ["Running"] },
+        ]));
+        let new = document(json!([
+            { "fingerprint": "C", "flags": ["Running"] },
+            { "fingerprint": "D", "flags": ["Exit"] },
+            { "fingerprint": "A", "flags": ["Running", "Exit"], "or_addresses": ["1.1.1.1:9001", "[2001:db8::1]:9001"] },
+        ]));
+        let diff = Diff::build(&old, &new);
+
+        assert_eq!(diff.added.iter().map(|r| r.fingerprint.as_str()).collect::<Vec<_>>(), ["D"]);
+        assert_eq!(diff.removed.iter().map(|r| r.fingerprint.as_str()).collect::<Vec<_>>(), ["B"]);
+        assert_eq!(diff.changed.len(), 1, "C is unchanged");
+        let a = &diff.changed[0];
+        assert_eq!((a.flags_gained.as_slice(), a.flags_lost.as_slice()), (&["Exit".to_string()][..], &["Guard".to_string()][..]));
+        assert_eq!(a.addresses_added, ["[2001:db8::1]:9001"]);
+        assert!(a.addresses_removed.is_empty());
+    }
+
+    #[test]
+    fn flag_counts_cover_both_sides() {
+        let old = document(json!([{ "fingerprint": "A", "flags": ["Guard"] }]));
+        let new = document(json!([{ "fingerprint": "A", "flags": ["Exit"] }, { "fingerprint": "B" }]));
+        let rows = Diff::build(&old, &new).count_rows();
+        assert_eq!(rows, [("Relays".to_string(), 1, 2), ("Exit".to_string(), 0, 1), ("Guard".to_string(), 1, 0)]);
+    }
+}
@@ -0,0 +1,325 @@
+//! diversity.rs — concentration metrics for the `diversity` subcommand.
+//!
+//! For each path position (guard / middle / exit) the Onionoo selection
+//! probabilities are summed per country and per AS, then summarised as:
+//!
+//!   * Shannon entropy in bits — higher means more evenly spread;
+//!   * Herfindahl–Hirschman index on the usual 0–10 000 scale — higher means
+//!     more concentrated (> 2 500 is "highly concentrated" in antitrust terms);
+//!   * the combined share of the N largest buckets.
+
+use std::{collections::HashMap, fmt::Write};
+
+use serde::Serialize;
+
+use crate::{onionoo::{OnionooResponse, TorNode}, stats::{self, Format}};
+
+// ---------------------------------------------------------------------------
+// Dimensions
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Position {
+    Guard,
+    Middle,
+    Exit,
+}
+
+impl Position {
+    const ALL: [Self; 3] = [Self::Guard, Self::Middle, Self::Exit];
+
+    fn probability(self, node: &TorNode) -> f64 {
+        match self {
+            Self::Guard  => node.guard_probability,
+            Self::Middle => node.middle_probability,
+            Self::Exit   => node.exit_probability,
+        }
+        .unwrap_or(0.0)
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Guard  => "guard",
+            Self::Middle => "middle",
+            Self::Exit   => "exit",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dimension {
+    Country,
+    As,
+}
+
+impl Dimension {
+    const ALL: [Self; 2] = [Self::Country, Self::As];
+
+    fn key(self, node: &TorNode) -> String {
+        let value = match self {
+            Self::Country => node.country.as_deref().map(str::to_uppercase),
+            Self::As      => node.as_number.clone(),
+        };
+        stats::or_unknown(value.as_deref())
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Country => "country",
+            Self::As      => "AS",
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Metrics
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Serialize)]
+pub struct Bucket {
+    pub key: String,
+    pub share: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Concentration {
+    pub dimension: Dimension,
+    pub position: Position,
+    pub buckets: usize,
+    pub entropy_bits: f64,
+    pub hhi: f64,
+    pub top_n_share: f64,
+    pub top: Vec<Bucket>,
+}
+
+impl Concentration {
+    fn compute(nodes: &[TorNode], dimension: Dimension, position: Position, top_n: usize) -> Self {
+        let mut weights: HashMap<String, f64> = HashMap::new();
+        for node in nodes {
+            let p = position.probability(node);
+            if p > 0.0 {
+                *weights.entry(dimension.key(node)).or_insert(0.0) += p;
+            }
+        }
+
+        // Onionoo probabilities already sum to ~1.0 but renormalise anyway so
+        // the metrics stay meaningful on partial or filtered documents.
+        let total: f64 = weights.values().sum();
+        let mut shares: Vec<Bucket> = weights
+            .into_iter()
+            .map(|(key, w)| Bucket { key, share: if total > 0.0 { w / total } else { 0.0 } })
+            .collect();
+        shares.sort_by(|a, b| b.share.total_cmp(&a.share).then_with(|| a.key.cmp(&b.key)));
+
+        let entropy_bits = -shares
+            .iter()
+            .filter(|b| b.share > 0.0)
+            .map(|b| b.share * b.share.log2())
+            .sum::<f64>();
+        let hhi = shares.iter().map(|b| (b.share * 100.0).powi(2)).sum();
+        let top_n_share = shares.iter().take(top_n).map(|b| b.share).sum();
+
+        Self {
+            dimension,
+            position,
+            buckets: shares.len(),
+            entropy_bits,
+            hhi,
+            top_n_share,
+            top: shares.into_iter().take(top_n).collect(),
+        }
+    }
+}
+
+/// All concentration metrics for one Onionoo document.
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    pub relays_published: Option<String>,
+    pub relays: usize,
+    pub metrics: Vec<Concentration>,
+}
+
+impl Snapshot {
+    pub fn build(response: &OnionooResponse, top_n: usize) -> Self {
+        let metrics = Dimension::ALL
+            .iter()
+            .flat_map(|&d| Position::ALL.iter().map(move |&p| (d, p)))
+            .map(|(d, p)| Concentration::compute(&response.relays, d, p, top_n))
+            .collect();
+        Self {
+            relays_published: response.relays_published.clone(),
+            relays: response.relays.len(),
+            metrics,
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Rendering
+// ---------------------------------------------------------------------------
+
+/// Render one or more snapshots. A single snapshot is shown as a metrics
+/// table with the largest buckets; several are shown as a time series,
+/// oldest first.
+pub fn render(snapshots: &[Snapshot], format: Format, top_n: usize) -> anyhow::Result<String> {
+    match format {
+        Format::Json => Ok(serde_json::to_string_pretty(snapshots)? + "\n"),
+        Format::Text | Format::Markdown => {
+            let md = matches!(format, Format::Markdown);
+            Ok(match snapshots {
+                [single] => render_single(single, md, top_n),
+                many     => render_series(many, md, top_n),
+            })
+        }
+    }
+}
+
+fn published(snapshot: &Snapshot) -> &str {
+    snapshot.relays_published.as_deref().unwrap_or("unknown")
+}
+
+/// Write one table row, either Markdown (`| a | b |`) or fixed-width text.
+fn row(s: &mut String, md: bool, cells: &[String]) {
+    if md {
+        let _ = writeln!(s, "| {} |", cells.join(" | "));
+    } else {
+        let _ = write!(s, "  {:<20}", cells[0]);
+        for cell in &cells[1..] {
+            let _ = write!(s, "  {cell:>12}");
+        }
+        s.push('\n');
+    }
+}
+
+fn header(s: &mut String, md: bool, cells: &[&str]) {
+    let owned: Vec<String> = cells.iter().map(|c| c.to_string()).collect();
+    row(s, md, &owned);
+    if md {
+        let _ = writeln!(s, "|{}", "---|".repeat(cells.len()));
+    }
+}
+
+fn render_single(snapshot: &Snapshot, md: bool, top_n: usize) -> String {
+    let mut s = String::new();
+    let top_label = format!("top-{top_n}");
+    if md {
+        let _ = writeln!(s, "# Tor network diversity\n\nPublished: {}\n", published(snapshot));
+    } else {
+        let _ = writeln!(s, "Tor network diversity — published {}\n", published(snapshot));
+    }
+
+    for dimension in Dimension::ALL {
+        let _ = writeln!(s, "{}By {}\n", if md { "## " } else { "" }, dimension.label());
+        header(&mut s, md, &["position", "buckets", "entropy", "HHI", &top_label, "largest"]);
+        for m in snapshot.metrics.iter().filter(|m| m.dimension == dimension) {
+            let largest = m
+                .top
+                .first()
+                .map_or_else(|| "-".to_string(), |b| format!("{} {:.1}%", b.key, b.share * 100.0));
+            row(&mut s, md, &[
+                m.position.label().to_string(),
+                m.buckets.to_string(),
+                format!("{:.3}", m.entropy_bits),
+                format!("{:.0}", m.hhi),
+                format!("{:.1}%", m.top_n_share * 100.0),
+                largest,
+            ]);
+        }
+        s.push('\n');
+    }
+    s
+}
+
+fn render_series(snapshots: &[Snapshot], md: bool, top_n: usize) -> String {
+    let mut s = String::new();
+    let top_label = format!("top-{top_n}");
+    if md {
+        let _ = writeln!(s, "# Tor network diversity over time\n");
+    } else {
+        let _ = writeln!(s, "Tor network diversity over {} snapshots\n", snapshots.len());
+    }
+
+    for dimension in Dimension::ALL {
+        for position in Position::ALL {
+            let _ = writeln!(
+                s,
+                "{}{} probability by {}\n",
+                if md { "## " } else { "" },
+                position.label(),
+                dimension.label(),
+            );
+            header(&mut s, md, &["published", "entropy", "HHI", &top_label]);
+            for snapshot in snapshots {
+                let Some(m) = snapshot.metrics.iter().find(|m| m.dimension == dimension && m.position == position) else {
+                    continue;
+                };
+                row(&mut s, md, &[
+                    published(snapshot).to_string(),
+                    format!("{:.3}", m.entropy_bits),
+                    format!("{:.0}", m.hhi),
+                    format!("{:.1}%", m.top_n_share * 100.0),
+                ]);
+            }
+            s.push('\n');
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn relays(exit_probabilities: &[(&str, f64)]) -> Vec<TorNode> {
+        exit_probabilities
+            .iter()
+            .enumerate()
+            .map(|(i, (country, p))| {
+                serde_json::from_value(json!({
+                    "fingerprint": i.to_string(), "or_addresses": [], "flags": [],
+                    "country": country, "exit_probability": p,
+                }))
+                .unwrap()
+            })
+            .collect()
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn one_bucket_is_fully_concentrated() {
+        let c = Concentration::compute(&relays(&[("de", 0.3), ("de", 0.7)]), Dimension::Country, Position::Exit, 5);
+        assert_eq!(c.buckets, 1);
+        assert!(close(c.entropy_bits, 0.0));
+        assert!(close(c.hhi, 10_000.0));
+        assert!(close(c.top_n_share, 1.0));
+        assert_eq!(c.top[0].key, "DE");
+    }
+
+    #[test]
+    fn even_buckets_maximise_entropy() {
+        let nodes = relays(&[("de", 0.25), ("us", 0.25), ("fr", 0.25), ("nl", 0.25)]);
+        let c = Concentration::compute(&nodes, Dimension::Country, Position::Exit, 2);
+        assert!(close(c.entropy_bits, 2.0));
+        assert!(close(c.hhi, 2_500.0));
+        assert!(close(c.top_n_share, 0.5));
+        // Ties are broken by key.
+        let keys: Vec<_> = c.top.iter().map(|b| b.key.as_str()).collect();
+        assert_eq!(keys, ["DE", "FR"]);
+    }
+
+    #[test]
+    fn shares_are_renormalised_and_zero_probabilities_ignored() {
+        let nodes = relays(&[("de", 0.02), ("us", 0.06), ("fr", 0.0)]);
+        let c = Concentration::compute(&nodes, Dimension::Country, Position::Exit, 5);
+        assert_eq!(c.buckets, 2);
+        assert!(close(c.top[0].share, 0.75));
+        let none = Concentration::compute(&nodes, Dimension::Country, Position::Guard, 5);
+        assert_eq!(none.buckets, 0);
+    }
+}
@@ -0,0 +1,249 @@
+//! export.rs — render every output of a [`Config`] into one transaction.
+//!
+//! Each [`OutputSpec`] is rendered from the same fetched document: its
+//! filter selects relays (and addresses), its format decides the bytes, and
+//! its path templates decide the file names.
+
+use std::{fmt::Write as _, net::IpAddr};
+
+use serde_json::{json, Value};
+
+use crate::{
+    config::{Column, Config, Format, OutputSpec},
+    csv, mmdb,
+    onionoo::{OnionooResponse, TorNode},
+    output::{self, Transaction, Vars},
+    raster, region,
+    stats::Report,
+    summary, theme, world_map,
+};
+
+// ---------------------------------------------------------------------------
+// Columns
+// ---------------------------------------------------------------------------
+
+impl Column {
+    /// Header / key used for this column.
+    pub fn name(self) -> &'static str {
+        match self {
+            Column::Fingerprint         => "fingerprint",
+            Column::Ipaddr              => "ipaddr",
+            Column::Port                => "port",
+            Column::Flags               => "flags",
+            Column::Country             => "country",
+            Column::AsNumber            => "as",
+            Column::AsName              => "as_name",
+            Column::Version             => "version",
+            Column::Platform            => "platform",
+            Column::ConsensusWeight     => "consensus_weight",
+            Column::AdvertisedBandwidth => "advertised_bandwidth",
+            Column::GuardProbability    => "guard_probability",
+            Column::MiddleProbability   => "middle_probability",
+            Column::ExitProbability     => "exit_probability",
+            Column::Latitude            => "latitude",
+            Column::Longitude           => "longitude",
+            Column::Contact             => "contact",
+        }
+    }
+
+    /// Typed value for JSON and MaxMind DB outputs; missing data is `null`.
+    pub fn value(self, node: &TorNode, ip: IpAddr, port: u16) -> Value {
+        match self {
+            Column::Fingerprint         => json!(node.fingerprint),
+            Column::Ipaddr              => json!(ip.to_string()),
+            Column::Port                => json!(port),
+            Column::Flags               => json!(node.flags),
+            Column::Country             => json!(node.country),
+            Column::AsNumber            => json!(node.as_number),
+            Column::AsName              => json!(node.as_name),
+            Column::Version             => json!(node.version),
+            Column::Platform            => json!(node.platform),
+            Column::ConsensusWeight     => json!(node.consensus_weight),
+            Column::AdvertisedBandwidth => json!(node.advertised_bandwidth),
+            Column::GuardProbability    => json!(node.guard_probability),
+            Column::MiddleProbability   => json!(node.middle_probability),
+            Column::ExitProbability     => json!(node.exit_probability),
+            Column::Latitude            => json!(node.latitude),
+            Column::Longitude           => json!(node.longitude),
+            Column::Contact             => json!(node.contact),
+        }
+    }
+
+    /// Plain-text value for CSV: flags space-separated, missing data empty.
+    pub fn text(self, node: &TorNode, ip: IpAddr, port: u16) -> String {
+        match self.value(node, ip, port) {
+            Value::Null        => String::new(),
+            Value::String(s)   => s,
+            Value::Array(list) => list.iter().filter_map(Value::as_str).collect::<Vec<_>>().join(" "),
+            other              => other.to_string(),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Rendering
+// ---------------------------------------------------------------------------
+
+/// Names `spec` is published under, primary name first: its own `path`
+/// templates, else the command's `--name` templates.
+pub fn names(spec: &OutputSpec, out: &output::Options, vars: &Vars) -> Vec<String> {
+    if spec.path.is_empty() {
+        out.names(&spec.category, spec.extension(), vars)
+    } else {
+        spec.path.iter().map(|t| vars.render(t, &spec.category, spec.extension())).collect()
+    }
+}
+
+/// Stage every output of `config` in `tx`. `geojson` must be given when the
+/// config contains a map (`svg`, `html`, `png` or `webp`). Returns the primary names.
+pub fn write_all(
+    tx: &mut Transaction,
+    config: &Config,
+    response: &OnionooResponse,
+    out: &output::Options,
+    geojson: Option<&Value>,
+) -> anyhow::Result<Vec<String>> {
+    let vars = Vars::new(response);
+    let mut written = Vec::new();
+    for spec in &config.outputs {
+        let bytes = summary::timed("render", || render(spec, response, geojson))
+            .map_err(|e| e.context(format!("cannot render output `{}`", spec.category)))?;
+        let names = names(spec, out, &vars);
+        tx.write(&names[0], &bytes)?;
+        for alias in &names[1..] {
+            tx.copy(&names[0], alias)?;
+        }
+        summary::record(|s| {
+            s.outputs.push(summary::Output {
+                name:     names[0].clone(),
+                category: spec.category.clone(),
+                format:   spec.format.name(),
+                relays:   spec.relays(&response.relays).len(),
+                rows:     spec.rows(&response.relays).len(),
+                bytes:    bytes.len(),
+            })
+        });
+        written.push(names[0].clone());
+    }
+    Ok(written)
+}
+
+/// The bytes of one output. `geojson` is needed for maps.
+pub fn render(spec: &OutputSpec, response: &OnionooResponse, geojson: Option<&Value>) -> anyhow::Result<Vec<u8>> {
+    let relays = &response.relays;
+    let mut bytes = Vec::new();
+    match spec.format {
+        Format::Csv => csv::write(&mut bytes, &spec.columns(), spec.rows(relays).into_iter())?,
+        Format::Json => {
+            let columns = spec.columns();
+            let rows: Vec<Value> = spec
+                .rows(relays)
+                .into_iter()
+                .map(|(node, ip, port)| {
+                    columns.iter().map(|c| (c.name().to_string(), c.value(node, ip, port))).collect()
+                })
+                .collect();
+            serde_json::to_writer_pretty(&mut bytes, &rows)?;
+            bytes.push(b'\n');
+        }
+        Format::Nftables => bytes = render_nftables(spec, response).into_bytes(),
+        Format::Mmdb => {
+            let columns = spec.columns();
+            let description = format!("Tor relays ({}) from Onionoo", spec.category);
+            let mut db = mmdb::Writer::new(&format!("Tor-Relays-{}", spec.category), &description);
+            for (node, ip, port) in spec.rows(relays) {
+                let record = mmdb::Value::Map(
+                    columns
+                        .iter()
+                        .filter_map(|c| {
+                            let value = c.value(node, ip, port);
+                            (!value.is_null()).then(|| (c.name().to_string(), mmdb::Value::from(&value)))
+                        })
+                        .collect(),
+                );
+                db.insert(ip, &record);
+            }
+            bytes = db.finish();
+        }
+        Format::Svg | Format::Html | Format::Png | Format::Webp => {
+            let geojson = geojson.ok_or_else(|| anyhow::anyhow!("world GeoJSON not loaded"))?;
+            let selected: Vec<TorNode> = spec.relays(relays).into_iter().cloned().collect();
+            let mut opts = world_map::Options::new(spec.projection.as_deref())?;
+            let area = |spec: &String| region::parse(spec)?.resolve(geojson);
+            opts.region = spec.region.as_ref().map(area).transpose()?;
+            opts.insets = spec.insets.iter().map(area).collect::<anyhow::Result<_>>()?;
+            opts.choropleth = spec.choropleth.as_deref().map(str::parse).transpose()?;
+            opts.density = spec
+                .density
+                .as_deref()
+                .map(|d| world_map::Density::parse(d, spec.density_by.as_deref()))
+                .transpose()?;
+            opts.marker_size = spec.marker_size.as_deref().map(str::parse).transpose()?;
+            if let Some(names) = &spec.categories {
+                opts.categories = world_map::parse_categories(names)?;
+            }
+            if let Some(name) = &spec.theme {
+                opts.theme = theme::load(name)?;
+            }
+            let svg = || world_map::render_svg(&selected, geojson, &opts);
+            bytes = match spec.format {
+                Format::Html => world_map::render_html(&selected, geojson, &opts).into_bytes(),
+                Format::Png  => raster::render(&svg(), raster::Encoding::Png, &spec.raster())?,
+                Format::Webp => raster::render(&svg(), raster::Encoding::Webp, &spec.raster())?,
+                _            => svg().into_bytes(),
+            };
+        }
+        Format::Stats => {
+            let selected = OnionooResponse {
+                version:          response.version.clone(),
+                relays_published: response.relays_published.clone(),
+                relays:           spec.relays(relays).into_iter().cloned().collect(),
+            };
+            bytes = Report::build(&selected).render(spec.report_format()?, 20)?.into_bytes();
+        }
+    }
+    Ok(bytes)
+}
+
+/// An `nft -f` script that (re)creates one IPv4 and one IPv6 set named after
+/// the category, e.g. `exits_v4` / `exits_v6` in `table inet tor`.
+fn render_nftables(spec: &OutputSpec, response: &OnionooResponse) -> String {
+    let table = spec.table.as_deref().unwrap_or("tor");
+    let set: String = spec
+        .category
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    let mut v4: Vec<IpAddr> = Vec::new();
+    let mut v6: Vec<IpAddr> = Vec::new();
+    for (_, ip, _) in spec.rows(&response.relays) {
+        if ip.is_ipv4() { v4.push(ip) } else { v6.push(ip) }
+    }
+    for list in [&mut v4, &mut v6] {
+        list.sort();
+        list.dedup();
+    }
+
+    let mut s = String::new();
+    let _ = writeln!(s, "#!/usr/sbin/nft -f");
+    let _ = writeln!(
+        s,
+        "# Tor relays `{}` from Onionoo, published {}: {} IPv4 and {} IPv6 addresses.",
+        spec.category,
+        response.relays_published.as_deref().unwrap_or("unknown"),
+        v4.len(),
+        v6.len(),
+    );
+    let _ = writeln!(s, "add table inet {table}");
+    for (suffix, kind, list) in [("v4", "ipv4_addr", &v4), ("v6", "ipv6_addr", &v6)] {
+        let name = format!("{set}_{suffix}");
+        let _ = writeln!(s, "add set inet {table} {name} {{ type {kind}; }}");
+        let _ = writeln!(s, "flush set inet {table} {name}");
+        for chunk in list.chunks(256) {
+            let elements: Vec<String> = chunk.iter().map(IpAddr::to_string).collect();
+            let _ = writeln!(s, "add element inet {table} {name} {{ {} }}", elements.join(", "));
+        }
+    }
+    s
+}
@@ -10,12 +10,12 @@ const MMDB_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/GeoLite2-Ci
 fn db() -> Option<&'static Reader<Vec<u8>>> {
     DB.get_or_init(|| {
         if !Path::new(MMDB_PATH).exists() {
-            eprintln!("[geo] GeoLite2-City.mmdb not found at {MMDB_PATH} — geo-fallback disabled.");
+            info!("GeoLite2-City.mmdb not found at {MMDB_PATH} — geo-fallback disabled.");
             return None;
         }
         match Reader::open_readfile(MMDB_PATH) {
-            Ok(r)  => { eprintln!("[geo] Opened {MMDB_PATH}"); Some(r) }
-            Err(e) => { eprintln!("[geo] Failed to open mmdb: {e}"); None }
+            Ok(r)  => { debug!("Opened {MMDB_PATH}"); Some(r) }
+            Err(e) => { warn!("Failed to open {MMDB_PATH}: {e}"); None }
         }
     })
     .as_ref()
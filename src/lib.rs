@@ -0,0 +1,29 @@
+//! Shared library behind the `tor-node-parser` and `world-map` binaries:
+//! the Onionoo data model, every output and report built from it, and the
+//! command-line interface both binaries run.
+
+#[macro_use]
+pub mod log;
+
+pub mod cli;
+pub mod config;
+pub mod csv;
+pub mod daemon;
+pub mod diff;
+pub mod diversity;
+pub mod export;
+pub mod geo;
+pub mod metrics;
+pub mod mmdb;
+pub mod onionoo;
+pub mod output;
+pub mod projection;
+pub mod raster;
+pub mod region;
+pub mod serve;
+pub mod simulate;
+pub mod stats;
+pub mod summary;
+pub mod theme;
+pub mod validate;
+pub mod world_map;
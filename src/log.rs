@@ -0,0 +1,159 @@
+//! log.rs — leveled progress messages on stderr.
+//!
+//! Every diagnostic goes through [`info!`], [`warn!`] or [`debug!`], which
+//! keep the `[*]` / `[!]` prefixes the tools have always printed. The level
+//! is process-wide and set once from `-q` / `-v`; stdout stays reserved for
+//! command output such as reports.
+//!
+//! With `--log-format json` each message is instead one JSON object per
+//! line, for log shippers:
+//!
+//! ```text
+//! {"time":1792332000.125,"level":"info","msg":"Got 7123 relays."}
+//! ```
+
+use std::{
+    fmt,
+    io::Write,
+    str::FromStr,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// The error that ends the run; always shown.
+    Error,
+    /// Warnings and errors only (`-q`).
+    Warn,
+    /// Progress messages too (the default).
+    Info,
+    /// Everything, including per-request and per-file detail (`-v`).
+    Debug,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn  => "warn",
+            Level::Info  => "info",
+            Level::Debug => "debug",
+        }
+    }
+
+    fn prefix(self) -> &'static str {
+        match self {
+            Level::Error => "Error: ",
+            Level::Warn  => "[!] ",
+            Level::Info  => "[*] ",
+            Level::Debug => "[-] ",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            other  => anyhow::bail!("unknown log format `{other}` (expected text or json)"),
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn set_format(format: Format) {
+    JSON.store(format == Format::Json, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Write one message at `level` if it is enabled. Called by the macros.
+pub fn write(level: Level, args: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    let line = line(level, args, JSON.load(Ordering::Relaxed));
+    // One write per line so concurrent threads don't interleave.
+    let _ = writeln!(std::io::stderr().lock(), "{line}");
+}
+
+/// One message as printed: prefixed text, or a JSON object if `json`.
+fn line(level: Level, args: fmt::Arguments, json: bool) -> String {
+    if json {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64());
+        let msg = serde_json::Value::String(args.to_string());
+        format!(r#"{{"time":{time:.3},"level":"{}","msg":{msg}}}"#, level.name())
+    } else {
+        format!("{}{args}", level.prefix())
+    }
+}
+
+/// Progress message, hidden by `-q`.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Info, format_args!($($arg)*))
+    };
+}
+
+/// Something went wrong but the run continues; always shown.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+/// Detail only shown with `-v`.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Debug, format_args!($($arg)*))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_lines_keep_the_old_prefixes() {
+        assert_eq!(line(Level::Info, format_args!("Got {} relays.", 3), false), "[*] Got 3 relays.");
+        assert_eq!(line(Level::Warn, format_args!("careful"), false), "[!] careful");
+    }
+
+    #[test]
+    fn json_lines_are_one_escaped_object() {
+        let text = line(Level::Debug, format_args!("a \"quoted\"\nmessage"), true);
+        assert!(!text.contains('\n'));
+        let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(value["level"], "debug");
+        assert_eq!(value["msg"], "a \"quoted\"\nmessage");
+        assert!(value["time"].as_f64().unwrap() > 0.0);
+    }
+
+    #[test]
+    fn levels_and_formats() {
+        assert!(Level::Error < Level::Warn && Level::Info < Level::Debug);
+        assert_eq!("json".parse::<Format>().unwrap(), Format::Json);
+        assert!("yaml".parse::<Format>().is_err());
+    }
+}
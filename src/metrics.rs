@@ -0,0 +1,218 @@
+//! metrics.rs — Prometheus exposition for the `metrics` subcommand.
+//!
+//! Two ways to publish:
+//!   * `--textfile PATH` writes one scrape to a file for node_exporter's
+//!     textfile collector (written to `PATH.tmp` then renamed, as the
+//!     collector requires);
+//!   * `--listen ADDR` serves `/metrics` over HTTP through [`crate::serve`],
+//!     refreshing the snapshot in the background every `--interval` seconds.
+
+use std::{
+    fmt::Write as _,
+    fs,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{onionoo::OnionooResponse, stats::{Report, Share}};
+
+// ---------------------------------------------------------------------------
+// Exporter state
+// ---------------------------------------------------------------------------
+
+/// Everything needed to render one scrape.
+#[derive(Default)]
+pub struct Exporter {
+    report: Option<Report>,
+    published_at: Option<u64>,
+    fetch_duration: Option<Duration>,
+    fetch_errors: u64,
+    last_success: Option<u64>,
+}
+
+fn now_unix() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+impl Exporter {
+    /// Run `fetch`, timing it and recording either the new snapshot or the
+    /// error. The previous snapshot is kept on failure.
+    pub fn refresh(&mut self, fetch: impl FnOnce() -> anyhow::Result<OnionooResponse>) {
+        let start = Instant::now();
+        match fetch() {
+            Ok(response) => self.record_success(&response, start.elapsed()),
+            Err(e) => {
+                warn!("Fetch failed: {e:#}");
+                self.record_failure(start.elapsed());
+            }
+        }
+    }
+
+    pub fn record_success(&mut self, response: &OnionooResponse, fetch_duration: Duration) {
+        self.fetch_duration = Some(fetch_duration);
+        self.published_at = response.published_at();
+        self.report = Some(Report::build(response));
+        self.last_success = Some(now_unix());
+    }
+
+    pub fn record_failure(&mut self, fetch_duration: Duration) {
+        self.fetch_duration = Some(fetch_duration);
+        self.fetch_errors += 1;
+    }
+
+    pub fn has_snapshot(&self) -> bool {
+        self.report.is_some()
+    }
+
+    /// Render the Prometheus text exposition format (version 0.0.4).
+    pub fn render(&self) -> String {
+        let mut s = String::new();
+
+        metric(&mut s, "tor_onionoo_fetch_errors_total", "counter",
+            "Failed Onionoo fetches since the exporter started.");
+        let _ = writeln!(s, "tor_onionoo_fetch_errors_total {}", self.fetch_errors);
+
+        if let Some(d) = self.fetch_duration {
+            metric(&mut s, "tor_onionoo_fetch_duration_seconds", "gauge",
+                "Wall-clock duration of the most recent Onionoo fetch.");
+            let _ = writeln!(s, "tor_onionoo_fetch_duration_seconds {:.3}", d.as_secs_f64());
+        }
+        if let Some(t) = self.last_success {
+            metric(&mut s, "tor_onionoo_last_success_timestamp_seconds", "gauge",
+                "Unix time of the most recent successful fetch.");
+            let _ = writeln!(s, "tor_onionoo_last_success_timestamp_seconds {t}");
+        }
+        if let Some(published) = self.published_at {
+            metric(&mut s, "tor_network_published_timestamp_seconds", "gauge",
+                "Unix time of the snapshot's relays_published field.");
+            let _ = writeln!(s, "tor_network_published_timestamp_seconds {published}");
+            metric(&mut s, "tor_network_snapshot_age_seconds", "gauge",
+                "Seconds between relays_published and this scrape.");
+            let _ = writeln!(s, "tor_network_snapshot_age_seconds {}", now_unix().saturating_sub(published));
+        }
+
+        let Some(report) = &self.report else { return s };
+
+        metric(&mut s, "tor_network_relays", "gauge", "Running relays in the snapshot.");
+        let _ = writeln!(s, "tor_network_relays {}", report.total_relays);
+
+        metric(&mut s, "tor_network_advertised_bandwidth_bytes", "gauge",
+            "Sum of advertised bandwidth over all relays, in bytes per second.");
+        let _ = writeln!(s, "tor_network_advertised_bandwidth_bytes {}", report.total_advertised_bandwidth);
+
+        metric(&mut s, "tor_network_consensus_weight", "gauge", "Sum of consensus weight over all relays.");
+        let _ = writeln!(s, "tor_network_consensus_weight {}", report.total_consensus_weight);
+
+        metric(&mut s, "tor_network_relays_without_position", "gauge",
+            "Relays with no Onionoo latitude/longitude.");
+        let _ = writeln!(s, "tor_network_relays_without_position {}", report.missing_geo.coordinates);
+
+        for (name, label, help, rows) in [
+            ("tor_network_relays_by_flag",    "flag",    "Running relays per relay flag.",       &report.by_flag),
+            ("tor_network_relays_by_country", "country", "Running relays per country.",          &report.by_country),
+            ("tor_network_relays_by_as",      "as",      "Running relays per autonomous system.", &report.by_as),
+            ("tor_network_relays_by_version", "version", "Running relays per Tor version.",      &report.by_version),
+        ] {
+            labelled(&mut s, name, label, help, rows);
+        }
+        s
+    }
+}
+
+fn metric(s: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(s, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+fn labelled(s: &mut String, name: &str, label: &str, help: &str, rows: &[Share]) {
+    metric(s, name, "gauge", help);
+    for row in rows {
+        let _ = writeln!(s, "{name}{{{label}=\"{}\"}} {}", escape_label(&row.key), row.relays);
+    }
+}
+
+/// Escape a label value per the exposition format: `\`, `"` and newline.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// ---------------------------------------------------------------------------
+// Outputs
+// ---------------------------------------------------------------------------
+
+/// Write one scrape to `path` atomically for the node_exporter textfile collector.
+pub fn write_textfile(exporter: &Exporter, path: &str) -> anyhow::Result<()> {
+    let tmp = format!("{path}.tmp");
+    fs::write(&tmp, exporter.render())?;
+    fs::rename(&tmp, path)?;
+    info!("Wrote metrics to {path}.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use serde_json::json;
+
+    fn response() -> OnionooResponse {
+        serde_json::from_value(json!({
+            "relays_published": "2024-05-01 12:00:00",
+            "relays": [
+                { "fingerprint": "A", "flags": ["Guard", "Running"], "country": "de", "consensus_weight": 10,
+                  "or_addresses": [] },
+                { "fingerprint": "B", "flags": ["Running"], "version": "0.4.8\"x\\y", "consensus_weight": 5,
+                  "or_addresses": [] },
+            ],
+        }))
+        .unwrap()
+    }
+
+    /// The value of the sample line `name` (labels included), if present.
+    fn sample<'s>(text: &'s str, name: &str) -> Option<&'s str> {
+        text.lines().find_map(|l| l.strip_prefix(name)?.strip_prefix(' '))
+    }
+
+    #[test]
+    fn errors_are_counted_before_any_snapshot() {
+        let mut exporter = Exporter::default();
+        exporter.refresh(|| anyhow::bail!("offline"));
+        exporter.refresh(|| anyhow::bail!("still offline"));
+        let text = exporter.render();
+        assert!(!exporter.has_snapshot());
+        assert_eq!(sample(&text, "tor_onionoo_fetch_errors_total"), Some("2"));
+        assert!(sample(&text, "tor_network_relays").is_none());
+    }
+
+    #[test]
+    fn snapshot_gauges_and_labels() {
+        let mut exporter = Exporter::default();
+        exporter.record_success(&response(), Duration::from_millis(1500));
+        exporter.record_failure(Duration::from_millis(10));
+        let text = exporter.render();
+
+        assert_eq!(sample(&text, "tor_network_relays"), Some("2"));
+        assert_eq!(sample(&text, "tor_network_consensus_weight"), Some("15"));
+        assert_eq!(sample(&text, "tor_network_published_timestamp_seconds"), Some("1714564800"));
+        assert_eq!(sample(&text, "tor_onionoo_fetch_duration_seconds"), Some("0.010"));
+        assert_eq!(sample(&text, "tor_onionoo_fetch_errors_total"), Some("1"));
+        assert_eq!(sample(&text, "tor_network_relays_by_flag{flag=\"Running\"}"), Some("2"));
+        assert_eq!(sample(&text, "tor_network_relays_by_country{country=\"DE\"}"), Some("1"));
+        assert_eq!(sample(&text, r#"tor_network_relays_by_version{version="0.4.8\"x\\y"}"#), Some("1"));
+    }
+
+    #[test]
+    fn every_metric_has_help_and_type() {
+        let mut exporter = Exporter::default();
+        exporter.record_success(&response(), Duration::ZERO);
+        let text = exporter.render();
+        let names: HashSet<&str> = text
+            .lines()
+            .filter(|l| !l.starts_with('#'))
+            .map(|l| l.split(['{', ' ']).next().unwrap())
+            .collect();
+        for name in names {
+            assert!(text.contains(&format!("# HELP {name} ")), "{name}");
+            assert!(text.contains(&format!("# TYPE {name} ")), "{name}");
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! mmdb.rs — minimal MaxMind DB (format 2.0) writer.
+//!
+//! Builds an IPv6 database with 32-bit records in which every relay address
+//! is a single-host network (`/128`, IPv4 as `::a.b.c.d/128` the way MaxMind
+//! readers look IPv4 up in IPv6 trees). Each address points at a map of the
+//! requested columns. The result opens with any MaxMind DB reader, including
+//! the `maxminddb` crate used by `geo.rs`.
+//!
+//! Layout: search tree, 16 zero bytes, data section, then the
+//! `\xAB\xCD\xEFMaxMind.com` marker followed by the metadata map.
+
+use std::{
+    net::IpAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+
+/// A value in the MaxMind DB data section.
+#[derive(Debug, Clone)]
+pub enum Value {
+    String(String),
+    Double(f64),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    Bool(bool),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+impl From<&serde_json::Value> for Value {
+    fn from(v: &serde_json::Value) -> Self {
+        use serde_json::Value as J;
+        match v {
+            J::Null          => Value::String(String::new()),
+            J::Bool(b)       => Value::Bool(*b),
+            J::Number(n)     => match n.as_u64() {
+                Some(u) => u32::try_from(u).map_or(Value::U64(u), Value::U32),
+                None    => Value::Double(n.as_f64().unwrap_or_default()),
+            },
+            J::String(s)     => Value::String(s.clone()),
+            J::Array(items)  => Value::Array(items.iter().map(Value::from).collect()),
+            J::Object(map)   => Value::Map(map.iter().map(|(k, v)| (k.clone(), v.into())).collect()),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Record {
+    Empty,
+    Node(u32),
+    /// Offset into the data section.
+    Data(u32),
+}
+
+pub struct Writer {
+    database_type: String,
+    description: String,
+    nodes: Vec<[Record; 2]>,
+    data: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new(database_type: &str, description: &str) -> Self {
+        Self {
+            database_type: database_type.to_string(),
+            description:   description.to_string(),
+            nodes:         vec![[Record::Empty; 2]],
+            data:          Vec::new(),
+        }
+    }
+
+    /// Map `ip` to `value`. The first insert of an address wins.
+    pub fn insert(&mut self, ip: IpAddr, value: &Value) {
+        let key = match ip {
+            IpAddr::V4(v4) => u128::from(u32::from(v4)),
+            IpAddr::V6(v6) => u128::from(v6),
+        };
+
+        let mut node = 0usize;
+        for depth in 0..128 {
+            let bit = ((key >> (127 - depth)) & 1) as usize;
+            let last = depth == 127;
+            match self.nodes[node][bit] {
+                Record::Node(next) if !last => node = next as usize,
+                Record::Empty if last => {
+                    let offset = self.data.len() as u32;
+                    encode(value, &mut self.data);
+                    self.nodes[node][bit] = Record::Data(offset);
+                }
+                Record::Empty => {
+                    self.nodes.push([Record::Empty; 2]);
+                    let next = self.nodes.len() - 1;
+                    self.nodes[node][bit] = Record::Node(next as u32);
+                    node = next;
+                }
+                // Duplicate address, or a host below an existing entry.
+                _ => return,
+            }
+        }
+    }
+
+    /// Serialise the complete database.
+    pub fn finish(self) -> Vec<u8> {
+        let node_count = self.nodes.len() as u32;
+        let resolve = |r: Record| match r {
+            Record::Empty     => node_count,
+            Record::Node(n)   => n,
+            Record::Data(off) => node_count + 16 + off,
+        };
+
+        let mut out = Vec::with_capacity(self.nodes.len() * 8 + 16 + self.data.len() + 256);
+        for [left, right] in &self.nodes {
+            out.extend_from_slice(&resolve(*left).to_be_bytes());
+            out.extend_from_slice(&resolve(*right).to_be_bytes());
+        }
+        out.extend_from_slice(&[0; 16]);
+        out.extend_from_slice(&self.data);
+
+        let build_epoch = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let metadata = Value::Map(vec![
+            ("binary_format_major_version".into(), Value::U16(2)),
+            ("binary_format_minor_version".into(), Value::U16(0)),
+            ("build_epoch".into(),                 Value::U64(build_epoch)),
+            ("database_type".into(),               Value::String(self.database_type)),
+            ("description".into(),                 Value::Map(vec![("en".into(), Value::String(self.description))])),
+            ("ip_version".into(),                  Value::U16(6)),
+            ("languages".into(),                   Value::Array(vec![Value::String("en".into())])),
+            ("node_count".into(),                  Value::U32(node_count)),
+            ("record_size".into(),                 Value::U16(32)),
+        ]);
+        out.extend_from_slice(METADATA_MARKER);
+        encode(&metadata, &mut out);
+        out
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Data section encoding
+// ---------------------------------------------------------------------------
+
+/// Type numbers from the spec; 8 and above are "extended" types.
+const TYPE_STRING: u8 = 2;
+const TYPE_DOUBLE: u8 = 3;
+const TYPE_U16: u8 = 5;
+const TYPE_U32: u8 = 6;
+const TYPE_MAP: u8 = 7;
+const TYPE_U64: u8 = 9;
+const TYPE_ARRAY: u8 = 11;
+const TYPE_BOOL: u8 = 14;
+
+fn encode(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::String(s) => {
+            control(TYPE_STRING, s.len(), out);
+            out.extend_from_slice(s.as_bytes());
+        }
+        Value::Double(d) => {
+            control(TYPE_DOUBLE, 8, out);
+            out.extend_from_slice(&d.to_be_bytes());
+        }
+        Value::U16(n) => unsigned(TYPE_U16, u64::from(*n), out),
+        Value::U32(n) => unsigned(TYPE_U32, u64::from(*n), out),
+        Value::U64(n) => unsigned(TYPE_U64, *n, out),
+        Value::Bool(b) => control(TYPE_BOOL, usize::from(*b), out),
+        Value::Array(items) => {
+            control(TYPE_ARRAY, items.len(), out);
+            for item in items {
+                encode(item, out);
+            }
+        }
+        Value::Map(entries) => {
+            control(TYPE_MAP, entries.len(), out);
+            for (key, item) in entries {
+                encode(&Value::String(key.clone()), out);
+                encode(item, out);
+            }
+        }
+    }
+}
+
+/// Unsigned integers are stored big-endian without leading zero bytes.
+fn unsigned(kind: u8, n: u64, out: &mut Vec<u8>) {
+    let bytes = n.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count();
+    control(kind, 8 - skip, out);
+    out.extend_from_slice(&bytes[skip..]);
+}
+
+/// Control byte (type in the top 3 bits, size in the low 5), then the
+/// extended type byte and size extension bytes where needed.
+fn control(kind: u8, size: usize, out: &mut Vec<u8>) {
+    let (size_bits, extra): (u8, Vec<u8>) = match size {
+        0..=28        => (size as u8, vec![]),
+        29..=284      => (29, vec![(size - 29) as u8]),
+        285..=65_820  => (30, ((size - 285) as u16).to_be_bytes().to_vec()),
+        _             => (31, ((size - 65_821) as u32).to_be_bytes()[1..].to_vec()),
+    };
+    if kind < 8 {
+        out.push(kind << 5 | size_bits);
+    } else {
+        out.push(size_bits);
+        out.push(kind - 7);
+    }
+    out.extend_from_slice(&extra);
+}
@@ -0,0 +1,703 @@
+//! onionoo.rs — Onionoo `details` document model and fetching.
+
+use std::{
+    env,
+    fs::{self, File},
+    io::{BufReader, Read},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use serde::Deserialize;
+
+use crate::{summary, validate};
+
+// ---------------------------------------------------------------------------
+// Constants
+// ---------------------------------------------------------------------------
+
+pub const ONIONOO_URL: &str =
+    "https://onionoo.torproject.org/details?search=type:relay%20running:true";
+
+/// Where the last details document is cached, relative to the working
+/// directory. Override with `ONIONOO_CACHE_DIR`; set it empty to disable.
+/// (`ONIONOO_URLS`, `ONIONOO_TIMEOUT` and `ONIONOO_RETRIES` tune the client
+/// the same way; see [`Fetcher::default`]. The command line's `--cache`,
+/// `--mirror`, `--timeout` and `--retries` override them all.)
+pub const DEFAULT_CACHE_DIR: &str = ".onionoo-cache";
+
+const CACHE_BODY: &str          = "details.json";
+const CACHE_LAST_MODIFIED: &str = "details.last-modified";
+
+// ---------------------------------------------------------------------------
+// Data model
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OnionooResponse {
+    /// Onionoo protocol version, e.g. `"8.0"`.
+    pub version: Option<String>,
+    pub relays_published: Option<String>,
+    pub relays: Vec<TorNode>,
+}
+
+impl OnionooResponse {
+    /// `relays_published` as seconds since the Unix epoch.
+    pub fn published_at(&self) -> Option<u64> {
+        parse_utc_timestamp(self.relays_published.as_deref()?)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TorNode {
+    pub fingerprint: String,
+    pub nickname: Option<String>,
+    #[serde(default)]
+    pub or_addresses: Vec<String>,
+    #[serde(default)]
+    pub flags: Vec<String>,
+    pub country:   Option<String>,
+    pub latitude:  Option<f64>,
+    pub longitude: Option<f64>,
+    #[serde(rename = "as")]
+    pub as_number: Option<String>,
+    pub as_name:   Option<String>,
+    pub version:   Option<String>,
+    pub platform:  Option<String>,
+    #[serde(default)]
+    pub consensus_weight: u64,
+    pub advertised_bandwidth: Option<u64>,
+    pub guard_probability:    Option<f64>,
+    pub middle_probability:   Option<f64>,
+    pub exit_probability:     Option<f64>,
+    pub contact:          Option<String>,
+    pub effective_family: Option<Vec<String>>,
+}
+
+impl TorNode {
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f.eq_ignore_ascii_case(flag))
+    }
+
+    pub fn is_guard(&self) -> bool { self.has_flag("Guard") }
+    pub fn is_exit(&self)  -> bool { self.has_flag("Exit")  }
+
+    pub fn ips(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        self.or_addresses
+            .iter()
+            .filter_map(|addr| parse_or_address(addr))
+            .map(|(ip, _)| ip)
+    }
+
+    /// The IP of the first OR address, ignoring the port.
+    pub fn primary_ip(&self) -> Option<IpAddr> {
+        parse_or_address(self.or_addresses.first()?).map(|(ip, _)| ip)
+    }
+
+    pub fn has_ipv6(&self) -> bool {
+        self.ips().any(|ip| ip.is_ipv6())
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Parsing helpers
+// ---------------------------------------------------------------------------
+
+/// Parse an Onionoo OR-address string into `(IpAddr, port)`.
+///
+/// Onionoo uses two formats:
+///   IPv4 — `"1.2.3.4:9001"`
+///   IPv6 — `"[dead:beef::1]:443"`
+///
+/// Port 0 is rejected along with anything unparsable.
+pub fn parse_or_address(addr: &str) -> Option<(IpAddr, u16)> {
+    let parsed = if let Some(addr) = addr.strip_prefix('[') {
+        // IPv6
+        let (ip_str, rest) = addr.split_once(']')?;
+        let port_str = rest.strip_prefix(':')?;
+        Some((IpAddr::from_str(ip_str).ok()?, port_str.parse().ok()?))
+    } else {
+        // IPv4
+        let (ip_str, port_str) = addr.rsplit_once(':')?;
+        Some((IpAddr::from_str(ip_str).ok()?, port_str.parse().ok()?))
+    };
+    parsed.filter(|&(_, port)| port != 0)
+}
+
+/// Parse Onionoo's `"YYYY-MM-DD hh:mm:ss"` UTC timestamps into Unix seconds.
+pub fn parse_utc_timestamp(s: &str) -> Option<u64> {
+    let (date, time) = s.trim().split_once(' ')?;
+    let mut d = date.splitn(3, '-').map(|p| p.parse::<i64>().ok());
+    let (y, m, day) = (d.next()??, d.next()??, d.next()??);
+    let mut t = time.splitn(3, ':').map(|p| p.parse::<i64>().ok());
+    let (hh, mm, ss) = (t.next()??, t.next()??, t.next()??);
+    if !(1..=12).contains(&m) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Days since 1970-01-01 in the proleptic Gregorian calendar
+    // (Howard Hinnant's `days_from_civil`).
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    u64::try_from(days * 86_400 + hh * 3_600 + mm * 60 + ss).ok()
+}
+
+/// The inverse of [`parse_utc_timestamp`]: Unix seconds to
+/// `"YYYY-MM-DD hh:mm:ss"` UTC.
+pub fn format_utc_timestamp(unix: u64) -> String {
+    // Howard Hinnant's `civil_from_days`.
+    let z = (unix / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+
+    let secs = unix % 86_400;
+    format!("{y:04}-{m:02}-{day:02} {:02}:{:02}:{:02}", secs / 3_600, secs / 60 % 60, secs % 60)
+}
+
+// ---------------------------------------------------------------------------
+// Loading
+// ---------------------------------------------------------------------------
+
+/// Fetch with the default [`Fetcher`] (cache enabled).
+pub fn fetch() -> anyhow::Result<OnionooResponse> {
+    Fetcher::default().fetch()
+}
+
+/// Where a command reads its details document from.
+#[derive(Clone)]
+pub enum Source {
+    /// Onionoo itself, through the [`Fetcher`]'s cache and mirrors.
+    Live(Fetcher),
+    /// A saved document; `-` reads stdin.
+    File(PathBuf),
+}
+
+impl Source {
+    pub fn load(&self) -> anyhow::Result<OnionooResponse> {
+        let response = summary::timed("fetch", || self.parse())?;
+        summary::record(|s| {
+            s.relays_published = response.relays_published.clone();
+            s.relays           = Some(response.relays.len());
+        });
+        validate::report_records(&response)?;
+        Ok(response)
+    }
+
+    fn parse(&self) -> anyhow::Result<OnionooResponse> {
+        match self {
+            Source::Live(fetcher) => fetcher.fetch(),
+            Source::File(path) => {
+                let body = self.load_raw()?;
+                let parsed: OnionooResponse = serde_json::from_slice(&body)
+                    .map_err(|e| anyhow::anyhow!("cannot parse {}: {e}", path.display()))?;
+                let from = if path.as_os_str() == "-" { "stdin".into() } else { path.display().to_string() };
+                info!("Read {} relays from {from}.", parsed.relays.len());
+                Ok(parsed)
+            }
+        }
+    }
+
+    /// The document's bytes, unparsed.
+    pub fn load_raw(&self) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Source::Live(fetcher) => fetcher.fetch_raw(),
+            Source::File(path) if path.as_os_str() == "-" => {
+                let mut body = Vec::new();
+                std::io::stdin().read_to_end(&mut body)?;
+                Ok(body)
+            }
+            Source::File(path) => {
+                fs::read(path).map_err(|e| anyhow::anyhow!("cannot read {}: {e}", path.display()))
+            }
+        }
+    }
+}
+
+/// Onionoo client with retries, mirror fallback and a conditional-GET
+/// response cache.
+///
+/// The raw body of the last `200 OK` is kept in `cache_dir` together with
+/// its `Last-Modified` header. The next request sends `If-Modified-Since`
+/// and a `304 Not Modified` answer is served from the cache, so several
+/// runs in a row (e.g. the CSV export and then the map) download the
+/// multi-megabyte document only once per Onionoo publication.
+///
+/// Each URL in `urls` is tried in order. Connection errors, timeouts and
+/// 5xx answers are retried up to `retries` times with exponential backoff
+/// starting at `backoff`; other errors move straight on to the next mirror.
+/// If every mirror fails, the cached document is used with a staleness
+/// warning rather than aborting the run.
+#[derive(Clone)]
+pub struct Fetcher {
+    pub urls: Vec<String>,
+    pub cache_dir: Option<PathBuf>,
+    /// Skip the request entirely while the cache is younger than this.
+    pub max_age: Duration,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub retries: u32,
+    pub backoff: Duration,
+}
+
+impl Default for Fetcher {
+    /// Defaults, overridable through the environment:
+    ///   `ONIONOO_CACHE_DIR` — cache location (empty disables the cache)
+    ///   `ONIONOO_URLS`      — comma-separated mirrors, tried in order
+    ///   `ONIONOO_TIMEOUT`   — read timeout in seconds (default 60)
+    ///   `ONIONOO_RETRIES`   — retries per mirror (default 3)
+    fn default() -> Self {
+        let cache_dir = match env::var_os("ONIONOO_CACHE_DIR") {
+            Some(dir) if dir.is_empty() => None,
+            Some(dir) => Some(PathBuf::from(dir)),
+            None      => Some(PathBuf::from(DEFAULT_CACHE_DIR)),
+        };
+        let urls = env::var("ONIONOO_URLS")
+            .ok()
+            .map(|v| v.split(',').map(str::trim).filter(|u| !u.is_empty()).map(String::from).collect())
+            .filter(|v: &Vec<String>| !v.is_empty())
+            .unwrap_or_else(|| vec![ONIONOO_URL.to_string()]);
+        let env_number = |name: &str, default: u64| {
+            env::var(name).ok().and_then(|v| v.trim().parse().ok()).unwrap_or(default)
+        };
+        Self {
+            urls,
+            cache_dir,
+            max_age:         Duration::from_secs(5 * 60),
+            connect_timeout: Duration::from_secs(15),
+            read_timeout:    Duration::from_secs(env_number("ONIONOO_TIMEOUT", 60)),
+            retries:         env_number("ONIONOO_RETRIES", 3) as u32,
+            backoff:         Duration::from_secs(2),
+        }
+    }
+}
+
+/// Outcome of one successful HTTP exchange.
+enum Download {
+    NotModified,
+    Body { body: Vec<u8>, last_modified: Option<String> },
+}
+
+impl Fetcher {
+    pub fn fetch(&self) -> anyhow::Result<OnionooResponse> {
+        let (_, parsed) = self.fetch_document()?;
+        info!("Got {} relays.", parsed.relays.len());
+        Ok(parsed)
+    }
+
+    /// The details document exactly as served (or cached).
+    pub fn fetch_raw(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(self.fetch_document()?.0)
+    }
+
+    fn cache_path(&self, name: &str) -> Option<PathBuf> {
+        self.cache_dir.as_ref().map(|dir| dir.join(name))
+    }
+
+    /// Age of the cached body, if there is one.
+    fn cache_age(&self) -> Option<Duration> {
+        let modified = fs::metadata(self.cache_path(CACHE_BODY)?).ok()?.modified().ok()?;
+        SystemTime::now().duration_since(modified).ok()
+    }
+
+    /// The cached body and its parse.
+    fn read_cache(&self) -> anyhow::Result<(Vec<u8>, OnionooResponse)> {
+        let path = self.cache_path(CACHE_BODY).ok_or_else(|| anyhow::anyhow!("cache disabled"))?;
+        let body = fs::read(&path).map_err(|e| anyhow::anyhow!("cannot read cache {}: {e}", path.display()))?;
+        let parsed = parse_document(&body).map_err(|e| e.context(format!("cache {}", path.display())))?;
+        Ok((body, parsed))
+    }
+
+    /// The document's bytes and their parse. A body that does not parse
+    /// counts as a failed download: it is never cached, and the next
+    /// mirror is tried.
+    fn fetch_document(&self) -> anyhow::Result<(Vec<u8>, OnionooResponse)> {
+        // An unreadable cache is ignored, and never revalidated.
+        let mut cache_usable = true;
+        if let Some(age) = self.cache_age().filter(|age| *age < self.max_age) {
+            info!("Using cached relay list ({}s old).", age.as_secs());
+            match self.read_cache() {
+                Ok(document) => return Ok(document),
+                Err(e) => {
+                    warn!("Ignoring the response cache: {e:#}");
+                    cache_usable = false;
+                }
+            }
+        }
+
+        // Only send a validator when the body it refers to is still there.
+        let mut last_modified = self
+            .cache_age()
+            .filter(|_| cache_usable)
+            .and_then(|_| fs::read_to_string(self.cache_path(CACHE_LAST_MODIFIED)?).ok())
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty());
+
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(self.connect_timeout)
+            .timeout_read(self.read_timeout)
+            .build();
+
+        let mut last_error = anyhow::anyhow!("no Onionoo URLs configured");
+        for url in &self.urls {
+            info!("Fetching relay list from {}...", host(url));
+            // Twice if a 304 refers to a cached copy that turns out unreadable.
+            loop {
+                debug!("GET {url} (If-Modified-Since: {})", last_modified.as_deref().unwrap_or("-"));
+                match self.download_with_retries(&agent, url, last_modified.as_deref()) {
+                    Ok(Download::NotModified) if last_modified.is_some() => match self.read_cache() {
+                        Ok(document) => {
+                            info!("Not modified since {}; using cached copy.", last_modified.unwrap_or_default());
+                            // Touch the cache so `max_age` counts from this confirmation.
+                            if let Some(path) = self.cache_path(CACHE_BODY) {
+                                let _ = File::options().append(true).open(path).and_then(|f| f.set_modified(SystemTime::now()));
+                            }
+                            return Ok(document);
+                        }
+                        Err(e) => {
+                            warn!("Not modified, but the response cache is unusable ({e:#}); downloading it again.");
+                            last_modified = None;
+                            continue;
+                        }
+                    },
+                    Ok(Download::NotModified) => {
+                        last_error = anyhow::anyhow!("{} answered 304 Not Modified to an unconditional request", host(url));
+                        warn!("{last_error}");
+                    }
+                    Ok(Download::Body { body, last_modified }) => {
+                        debug!("Downloaded {} bytes (Last-Modified: {}).", body.len(), last_modified.as_deref().unwrap_or("-"));
+                        match parse_document(&body) {
+                            Ok(parsed) => {
+                                if let Err(e) = self.write_cache(&body, last_modified.as_deref()) {
+                                    warn!("Could not update response cache: {e:#}");
+                                }
+                                return Ok((body, parsed));
+                            }
+                            Err(e) => {
+                                warn!("{} sent an unusable document: {e:#}", host(url));
+                                last_error = e;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        warn!("{} failed: {e:#}", host(url));
+                        last_error = e;
+                    }
+                }
+                break;
+            }
+        }
+
+        match (self.cache_age(), self.read_cache()) {
+            (Some(age), Ok(document)) => {
+                warn!(
+                    "Every Onionoo mirror failed; falling back to the cached \
+                     document last confirmed {} ago. Outputs may be stale.",
+                    format_age(age),
+                );
+                Ok(document)
+            }
+            _ => Err(last_error.context("every Onionoo mirror failed and no cached document is available")),
+        }
+    }
+
+    fn download_with_retries(
+        &self,
+        agent: &ureq::Agent,
+        url: &str,
+        since: Option<&str>,
+    ) -> anyhow::Result<Download> {
+        let mut attempt = 0;
+        loop {
+            match download(agent, url, since) {
+                Ok(d) => return Ok(d),
+                Err(e) if attempt < self.retries && is_retryable(&e) => {
+                    let delay = self.backoff.saturating_mul(1 << attempt.min(16));
+                    attempt += 1;
+                    warn!(
+                        "{e:#} — retry {attempt}/{} in {}s.",
+                        self.retries,
+                        delay.as_secs_f64(),
+                    );
+                    thread::sleep(delay);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn write_cache(&self, body: &[u8], last_modified: Option<&str>) -> anyhow::Result<()> {
+        let Some(dir) = &self.cache_dir else { return Ok(()) };
+        fs::create_dir_all(dir)?;
+
+        let body_path = dir.join(CACHE_BODY);
+        let tmp = dir.join(format!("{CACHE_BODY}.tmp"));
+        fs::write(&tmp, body)?;
+        fs::rename(&tmp, &body_path)?;
+
+        let lm_path = dir.join(CACHE_LAST_MODIFIED);
+        match last_modified {
+            Some(lm) => fs::write(lm_path, lm)?,
+            None     => { let _ = fs::remove_file(lm_path); }
+        }
+        Ok(())
+    }
+}
+
+fn parse_document(body: &[u8]) -> anyhow::Result<OnionooResponse> {
+    serde_json::from_slice(body).map_err(|e| anyhow::anyhow!("not an Onionoo details document: {e}"))
+}
+
+fn download(agent: &ureq::Agent, url: &str, since: Option<&str>) -> anyhow::Result<Download> {
+    let mut request = agent.get(url);
+    if let Some(since) = since {
+        request = request.set("If-Modified-Since", since);
+    }
+    let response = request.call()?;
+    if response.status() == 304 {
+        return Ok(Download::NotModified);
+    }
+    let last_modified = response.header("Last-Modified").map(str::to_string);
+    let mut body = Vec::new();
+    response.into_reader().read_to_end(&mut body)?;
+    Ok(Download::Body { body, last_modified })
+}
+
+/// Connection failures, timeouts (surfacing as I/O errors while reading the
+/// body) and 5xx answers are worth retrying; 4xx answers are not.
+fn is_retryable(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<ureq::Error>() {
+        Some(ureq::Error::Status(code, _)) => *code >= 500,
+        Some(ureq::Error::Transport(_))    => true,
+        None => error.downcast_ref::<std::io::Error>().is_some(),
+    }
+}
+
+/// `scheme://host` of a URL, for log lines that shouldn't print the query.
+fn host(url: &str) -> &str {
+    let after_scheme = url.find("://").map_or(0, |i| i + 3);
+    url[after_scheme..].find('/').map_or(url, |i| &url[..after_scheme + i])
+}
+
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..=5_399     => format!("{} minutes", secs / 60),
+        5_400..=172_799 => format!("{:.1} hours", secs as f64 / 3_600.0),
+        _             => format!("{:.1} days", secs as f64 / 86_400.0),
+    }
+}
+
+/// Load a previously saved Onionoo details document.
+pub fn read_response(path: &Path) -> anyhow::Result<OnionooResponse> {
+    let file = File::open(path)
+        .map_err(|e| anyhow::anyhow!("cannot open {}: {e}", path.display()))?;
+    serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| anyhow::anyhow!("cannot parse {}: {e}", path.display()))
+}
+
+/// Every `*.json` details document in `dir`, oldest `relays_published` first.
+pub fn read_snapshots(dir: &Path) -> anyhow::Result<Vec<OnionooResponse>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| anyhow::anyhow!("cannot read snapshots in {}: {e}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|e| e == "json"))
+        .collect();
+    paths.sort();
+
+    let mut snapshots = paths
+        .iter()
+        .map(|p| read_response(p))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    snapshots.sort_by(|a, b| a.relays_published.cmp(&b.relays_published));
+    info!("Loaded {} snapshots from {}.", snapshots.len(), dir.display());
+    Ok(snapshots)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, net::TcpListener};
+
+    use super::*;
+
+    const DOCUMENT: &str = r#"{"relays_published":"2024-05-01 12:00:00","relays":[{"fingerprint":"A"}]}"#;
+
+    /// An empty directory of its own for each test.
+    fn scratch(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tor-node-parser-{}-{test}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A local server answering one request per entry of `responses`, in
+    /// order, with that status (and any header lines after it) and body.
+    /// Returns its URL.
+    fn server(responses: &[(&str, &str)]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/details", listener.local_addr().unwrap());
+        let responses: Vec<String> = responses
+            .iter()
+            .map(|(status, body)| {
+                format!("HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len())
+            })
+            .collect();
+        thread::spawn(move || {
+            for response in responses {
+                let Ok((mut stream, _)) = listener.accept() else { return };
+                let mut request = Vec::new();
+                let mut byte = [0u8];
+                while !request.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap_or(0) == 1 {
+                    request.push(byte[0]);
+                }
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        url
+    }
+
+    fn fetcher(urls: Vec<String>, cache_dir: Option<PathBuf>) -> Fetcher {
+        Fetcher {
+            urls,
+            cache_dir,
+            max_age:         Duration::ZERO,
+            connect_timeout: Duration::from_secs(2),
+            read_timeout:    Duration::from_secs(2),
+            retries:         0,
+            backoff:         Duration::from_millis(1),
+        }
+    }
+
+    #[test]
+    fn or_addresses_parse_with_a_port() {
+        assert_eq!(parse_or_address("1.2.3.4:9001"), Some(("1.2.3.4".parse().unwrap(), 9001)));
+        assert_eq!(parse_or_address("[2001:db8::1]:443"), Some(("2001:db8::1".parse().unwrap(), 443)));
+        for bad in ["1.2.3.4", "1.2.3.4:0", "1.2.3.4:65536", "[2001:db8::1]", "host:80", ""] {
+            assert_eq!(parse_or_address(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn timestamps_parse_as_utc() {
+        assert_eq!(parse_utc_timestamp("1970-01-01 00:00:00"), Some(0));
+        assert_eq!(parse_utc_timestamp("2024-05-01 12:00:00"), Some(1_714_564_800));
+        assert_eq!(parse_utc_timestamp(" 2000-02-29 23:59:59 "), Some(951_868_799));
+        for bad in ["", "2024-05-01", "2024-13-01 00:00:00", "2024-05-01T12:00:00", "1969-12-31 23:59:59"] {
+            assert_eq!(parse_utc_timestamp(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn timestamps_format_back() {
+        for stamp in ["1970-01-01 00:00:00", "2000-02-29 23:59:59", "2024-05-01 12:07:05", "2100-12-31 00:00:01"] {
+            assert_eq!(format_utc_timestamp(parse_utc_timestamp(stamp).unwrap()), stamp);
+        }
+    }
+
+    #[test]
+    fn fresh_cache_is_used_without_a_request() {
+        let dir = scratch("fresh-cache");
+        let mut f = fetcher(Vec::new(), Some(dir.clone()));
+        f.write_cache(DOCUMENT.as_bytes(), Some("Wed, 01 May 2024 12:00:00 GMT")).unwrap();
+        f.max_age = Duration::from_secs(60);
+
+        assert_eq!(f.fetch_raw().unwrap(), DOCUMENT.as_bytes());
+        assert_eq!(fs::read_to_string(dir.join(CACHE_LAST_MODIFIED)).unwrap(), "Wed, 01 May 2024 12:00:00 GMT");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn downloads_are_cached_and_revalidated() {
+        let dir = scratch("revalidate");
+        let modified = "Wed, 01 May 2024 12:00:00 GMT";
+        let url = server(&[(&format!("200 OK\r\nLast-Modified: {modified}"), DOCUMENT), ("304 Not Modified", "")]);
+        let f = fetcher(vec![url], Some(dir.clone()));
+
+        assert_eq!(f.fetch().unwrap().relays.len(), 1);
+        assert_eq!(fs::read(dir.join(CACHE_BODY)).unwrap(), DOCUMENT.as_bytes());
+        assert_eq!(fs::read_to_string(dir.join(CACHE_LAST_MODIFIED)).unwrap(), modified);
+        // The second answer has no body; it is served from the cache.
+        assert_eq!(f.fetch_raw().unwrap(), DOCUMENT.as_bytes());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unusable_bodies_are_not_cached() {
+        let dir = scratch("unusable");
+        let url = server(&[("200 OK", "<html>oops</html>")]);
+        let f = fetcher(vec![url], Some(dir.clone()));
+
+        let err = f.fetch().unwrap_err();
+        assert!(format!("{err:#}").contains("not an Onionoo details document"), "{err:#}");
+        assert!(!dir.join(CACHE_BODY).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn an_unreadable_cache_is_downloaded_again() {
+        let dir = scratch("corrupt-cache");
+        let modified = "Wed, 01 May 2024 12:00:00 GMT";
+        // A 304 for the corrupt copy, then the full document without a validator.
+        let url = server(&[("304 Not Modified", ""), ("200 OK", DOCUMENT)]);
+        let f = fetcher(vec![url], Some(dir.clone()));
+        f.write_cache(b"{\"relays\": [tru", Some(modified)).unwrap();
+
+        assert_eq!(f.fetch().unwrap().relays.len(), 1);
+        assert_eq!(fs::read(dir.join(CACHE_BODY)).unwrap(), DOCUMENT.as_bytes());
+
+        // A fresh but corrupt cache isn't revalidated either: straight to a download.
+        let mut f = fetcher(vec![server(&[("200 OK", DOCUMENT)])], Some(dir.clone()));
+        f.write_cache(b"", Some(modified)).unwrap();
+        f.max_age = Duration::from_secs(60);
+        assert_eq!(f.fetch_raw().unwrap(), DOCUMENT.as_bytes());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failing_mirrors_are_retried_then_skipped() {
+        let dir = scratch("mirrors");
+        let down = server(&[("503 Service Unavailable", ""), ("503 Service Unavailable", "")]);
+        let missing = server(&[("404 Not Found", "")]);
+        let up = server(&[("200 OK", DOCUMENT)]);
+        let mut f = fetcher(vec![down, missing, up], Some(dir.clone()));
+        f.retries = 1;
+
+        assert_eq!(f.fetch().unwrap().relays.len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_stale_cache_stands_in_when_every_mirror_fails() {
+        let dir = scratch("stale");
+        let f = fetcher(vec![server(&[("500 Internal Server Error", "")])], Some(dir.clone()));
+        f.write_cache(DOCUMENT.as_bytes(), None).unwrap();
+        assert_eq!(f.fetch().unwrap().relays.len(), 1);
+
+        let f = fetcher(vec![server(&[("500 Internal Server Error", "")])], None);
+        let err = f.fetch().unwrap_err();
+        assert!(format!("{err:#}").contains("no cached document"), "{err:#}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn log_helpers() {
+        assert_eq!(host("https://onionoo.torproject.org/details?search=x"), "https://onionoo.torproject.org");
+        assert_eq!(host("http://127.0.0.1:8765"), "http://127.0.0.1:8765");
+        assert_eq!(format_age(Duration::from_secs(600)), "10 minutes");
+        assert_eq!(format_age(Duration::from_secs(3 * 3_600)), "3.0 hours");
+        assert_eq!(format_age(Duration::from_secs(3 * 86_400)), "3.0 days");
+    }
+}
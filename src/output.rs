@@ -0,0 +1,589 @@
+//! output.rs — all-or-nothing commit of a set of output files.
+//!
+//! Every output of a run is written into a staging directory first. Nothing
+//! in the destination changes until [`Transaction::commit`], and a
+//! transaction dropped without committing removes its staging directory, so
+//! an error half-way through a run leaves neither updated files nor stray
+//! temporaries behind.
+//!
+//! Two layouts are supported:
+//!
+//!   * [`Layout::InPlace`] — files keep their usual paths in the destination
+//!     directory. On commit each previous file is moved aside and the staged
+//!     one renamed in, one file at a time, so a reader can briefly find a
+//!     file missing or a mix of old and new files. If any rename fails,
+//!     everything already swapped is rolled back; should that fail as well,
+//!     the staging directory is kept, with the previous files in its
+//!     `.previous`, and the error says where.
+//!   * [`Layout::Versioned`] — each run becomes `releases/<timestamp>/` and
+//!     a `current` symlink is atomically repointed at it, so readers going
+//!     through `current` see either the complete old set or the complete
+//!     new one. The newest `keep` releases are kept.
+//!
+//! [`Options`] decides where a run's outputs go: the output directory,
+//! filename templates with `{category}`, `{ext}`, `{date}` and `{published}`
+//! placeholders, and compressed `.gz` / `.zst` variants, which are produced
+//! from the staged files during commit and published alongside them.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Component, Path, PathBuf},
+    process,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::onionoo::{format_utc_timestamp, OnionooResponse};
+
+// ---------------------------------------------------------------------------
+// Naming
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+        }
+    }
+
+    fn compress(self, src: &Path, dst: &Path) -> anyhow::Result<()> {
+        let mut input = BufReader::new(File::open(src)?);
+        let output = BufWriter::new(File::create(dst)?);
+        match self {
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::best());
+                io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?.flush()?;
+            }
+            Compression::Zstd => {
+                let mut encoder = zstd::Encoder::new(output, 19)?;
+                io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "gz" | "gzip"  => Ok(Compression::Gzip),
+            "zst" | "zstd" => Ok(Compression::Zstd),
+            _ => anyhow::bail!("unknown compression `{s}` (expected gz or zst)"),
+        }
+    }
+}
+
+/// Values substituted into filename templates for one document.
+pub struct Vars {
+    /// `YYYY-MM-DD` of `relays_published`.
+    date: String,
+    /// `relays_published` as `YYYY-MM-DDThhmmssZ`.
+    published: String,
+}
+
+impl Vars {
+    /// Placeholders for `response`, using the current time if it has no
+    /// usable `relays_published`.
+    pub fn new(response: &OnionooResponse) -> Self {
+        let unix = response.published_at().unwrap_or_else(|| {
+            SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+        });
+        let stamp = format_utc_timestamp(unix);
+        Self {
+            date:      stamp[..10].to_string(),
+            published: format!("{}Z", stamp.replace(' ', "T").replace(':', "")),
+        }
+    }
+
+    /// Expand `{category}`, `{ext}`, `{date}` and `{published}` in `template`.
+    pub fn render(&self, template: &str, category: &str, ext: &str) -> String {
+        template
+            .replace("{category}", category)
+            .replace("{ext}", ext)
+            .replace("{date}", &self.date)
+            .replace("{published}", &self.published)
+    }
+}
+
+/// Reject templates with unknown placeholders or that leave the output
+/// directory.
+pub fn check_template(template: &str) -> anyhow::Result<()> {
+    let vars = Vars { date: "d".into(), published: "p".into() };
+    let name = vars.render(template, "c", "e");
+    anyhow::ensure!(
+        !name.contains(['{', '}']),
+        "template `{template}` has an unknown placeholder \
+         (use {{category}}, {{ext}}, {{date}} or {{published}})"
+    );
+    check_name(&name)
+}
+
+/// Filename template used when neither `--name` nor a config `path` is
+/// given, e.g. `all.csv` or `map.svg`.
+pub const DEFAULT_TEMPLATE: &str = "{category}.{ext}";
+
+/// Where and under which names a command publishes its outputs.
+pub struct Options {
+    pub dir: PathBuf,
+    pub layout: Layout,
+    /// Filename templates; every output is published under each of them.
+    /// Empty means [`DEFAULT_TEMPLATE`].
+    pub templates: Vec<String>,
+    pub compress: Vec<Compression>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            dir:       PathBuf::from("."),
+            layout:    Layout::InPlace,
+            templates: Vec::new(),
+            compress:  Vec::new(),
+        }
+    }
+}
+
+impl Options {
+    pub fn check(&self) -> anyhow::Result<()> {
+        self.templates.iter().try_for_each(|t| check_template(t))
+    }
+
+    /// The names `category` is published under, primary name first.
+    pub fn names(&self, category: &str, ext: &str, vars: &Vars) -> Vec<String> {
+        if self.templates.is_empty() {
+            vec![vars.render(DEFAULT_TEMPLATE, category, ext)]
+        } else {
+            self.templates.iter().map(|t| vars.render(t, category, ext)).collect()
+        }
+    }
+
+    /// Where the currently published outputs can be read.
+    pub fn published_dir(&self) -> PathBuf {
+        self.layout.published_dir(&self.dir)
+    }
+
+    pub fn begin(&self) -> anyhow::Result<Transaction> {
+        let mut tx = Transaction::begin(&self.dir, self.layout)?;
+        tx.compress = self.compress.clone();
+        Ok(tx)
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Transactions
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy)]
+pub enum Layout {
+    InPlace,
+    Versioned { keep: usize },
+}
+
+impl Layout {
+    /// Where the currently published outputs under `dest` can be read.
+    pub fn published_dir(&self, dest: &Path) -> PathBuf {
+        match self {
+            Layout::InPlace => dest.to_path_buf(),
+            Layout::Versioned { .. } => dest.join("current"),
+        }
+    }
+}
+
+pub struct Transaction {
+    dest: PathBuf,
+    staging: PathBuf,
+    layout: Layout,
+    files: Vec<String>,
+    compress: Vec<Compression>,
+    committed: bool,
+    /// A failed rollback left files in the staging directory that are the
+    /// only copy of the previous outputs.
+    keep_staging: bool,
+}
+
+fn timestamp() -> String {
+    let d = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{}.{:03}", d.as_secs(), d.subsec_millis())
+}
+
+impl Transaction {
+    /// Start staging outputs destined for `dest`.
+    pub fn begin(dest: impl Into<PathBuf>, layout: Layout) -> anyhow::Result<Self> {
+        let dest = dest.into();
+        let staging = match layout {
+            // A hidden sibling of the outputs, so renames never cross filesystems.
+            Layout::InPlace => dest.join(format!(".staging-{}-{}", process::id(), timestamp())),
+            Layout::Versioned { .. } => dest.join("releases").join(format!(".{}-{}", timestamp(), process::id())),
+        };
+        fs::create_dir_all(&staging)
+            .map_err(|e| anyhow::anyhow!("cannot create staging directory {}: {e}", staging.display()))?;
+        Ok(Self { dest, staging, layout, files: Vec::new(), compress: Vec::new(), committed: false, keep_staging: false })
+    }
+
+    fn staged_path(&mut self, name: &str) -> anyhow::Result<PathBuf> {
+        check_name(name)?;
+        anyhow::ensure!(!self.files.iter().any(|f| f == name), "output `{name}` staged twice");
+        let path = self.staging.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        self.files.push(name.to_string());
+        Ok(path)
+    }
+
+    /// Open a buffered writer for output `name`.
+    pub fn create(&mut self, name: &str) -> anyhow::Result<BufWriter<File>> {
+        let path = self.staged_path(name)?;
+        Ok(BufWriter::new(File::create(path)?))
+    }
+
+    /// Stage output `name` with the given contents.
+    pub fn write(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        let mut writer = self.create(name)?;
+        writer.write_all(contents)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Stage the already staged output `from` a second time as `to`.
+    pub fn copy(&mut self, from: &str, to: &str) -> anyhow::Result<()> {
+        anyhow::ensure!(self.files.iter().any(|f| f == from), "output `{from}` is not staged");
+        let src = self.staging.join(from);
+        let dst = self.staged_path(to)?;
+        fs::copy(src, dst)?;
+        Ok(())
+    }
+
+    /// Names of the outputs staged so far.
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    /// Publish every staged output at once.
+    pub fn commit(mut self) -> anyhow::Result<()> {
+        for name in self.files.clone() {
+            for &variant in &self.compress.clone() {
+                let compressed = format!("{name}.{}", variant.extension());
+                let dst = self.staged_path(&compressed)?;
+                variant.compress(&self.staging.join(&name), &dst)?;
+            }
+        }
+        for name in &self.files {
+            File::open(self.staging.join(name))?.sync_all()?;
+        }
+        match self.layout {
+            Layout::InPlace => self.commit_in_place()?,
+            Layout::Versioned { keep } => self.commit_versioned(keep)?,
+        }
+        self.committed = true;
+        Ok(())
+    }
+
+    fn commit_in_place(&mut self) -> anyhow::Result<()> {
+        let backup = self.staging.join(".previous");
+        fs::create_dir(&backup)?;
+
+        // (name, had a previous version)
+        let mut swapped: Vec<(&str, bool)> = Vec::new();
+        let result = (|| -> anyhow::Result<()> {
+            for name in &self.files {
+                let target = self.dest.join(name);
+                let existed = target.exists();
+                if existed {
+                    let saved = backup.join(name);
+                    if let Some(parent) = saved.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::rename(&target, saved)?;
+                } else if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                swapped.push((name, existed));
+                fs::rename(self.staging.join(name), &target)
+                    .map_err(|e| anyhow::anyhow!("cannot move {name} into place: {e}"))?;
+            }
+            Ok(())
+        })();
+
+        if let Err(e) = result {
+            let stuck = self.roll_back(&backup, swapped);
+            if !stuck.is_empty() {
+                self.keep_staging = true;
+                return Err(e.context(format!(
+                    "output commit failed and could not be rolled back for {}; previous versions, \
+                     where there were any, are in {}",
+                    stuck.join(", "),
+                    backup.display()
+                )));
+            }
+            return Err(e.context("output commit failed; previous files restored"));
+        }
+        Ok(())
+    }
+
+    /// Undo `swapped` (name, had a previous version), newest first: put back
+    /// each previous file from `backup`, or remove the new one. Returns the
+    /// names that could not be restored.
+    fn roll_back(&self, backup: &Path, swapped: Vec<(&str, bool)>) -> Vec<String> {
+        let mut stuck = Vec::new();
+        for (name, existed) in swapped.into_iter().rev() {
+            let target = self.dest.join(name);
+            let restored = if existed {
+                fs::rename(backup.join(name), &target)
+            } else {
+                fs::remove_file(&target).or_else(|e| {
+                    if e.kind() == std::io::ErrorKind::NotFound { Ok(()) } else { Err(e) }
+                })
+            };
+            if let Err(re) = restored {
+                warn!("Rollback of {} failed: {re}", target.display());
+                stuck.push(name.to_string());
+            }
+        }
+        stuck
+    }
+
+    #[cfg(unix)]
+    fn commit_versioned(&self, keep: usize) -> anyhow::Result<()> {
+        let releases = self.dest.join("releases");
+        let name = timestamp();
+        let release = releases.join(&name);
+        fs::rename(&self.staging, &release)?;
+
+        // Build the new link beside the old one, then rename over it.
+        let link = self.dest.join("current");
+        let tmp_link = self.dest.join(format!(".current-{}", process::id()));
+        let _ = fs::remove_file(&tmp_link);
+        std::os::unix::fs::symlink(Path::new("releases").join(&name), &tmp_link)?;
+        fs::rename(&tmp_link, &link)?;
+
+        prune_releases(&releases, keep.max(1));
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn commit_versioned(&self, _keep: usize) -> anyhow::Result<()> {
+        anyhow::bail!("versioned output layout needs symlink support (Unix only)")
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if self.keep_staging {
+            warn!("Kept {} to recover the previous outputs from.", self.staging.display());
+            return;
+        }
+        if self.staging.exists() {
+            if let Err(e) = fs::remove_dir_all(&self.staging) {
+                warn!("Could not remove {}: {e}", self.staging.display());
+            }
+        }
+        if !self.committed && !self.files.is_empty() {
+            warn!("Output transaction abandoned; no files were changed.");
+        }
+    }
+}
+
+/// Outputs are named by paths relative to the output directory; absolute
+/// paths and `..` would escape the transaction.
+pub fn check_name(name: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !name.is_empty() && Path::new(name).components().all(|c| matches!(c, Component::Normal(_))),
+        "output `{name}` must be a relative path inside the output directory"
+    );
+    Ok(())
+}
+
+/// Remove all but the newest `keep` release directories.
+#[cfg(unix)]
+fn prune_releases(releases: &Path, keep: usize) {
+    let Ok(entries) = fs::read_dir(releases) else { return };
+    let mut names: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_dir())
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .filter(|n| !n.starts_with('.'))
+        .collect();
+    // Names are `<secs>.<millis>` — compare numerically, not lexically.
+    names.sort_by(|a, b| {
+        let key = |s: &str| s.parse::<f64>().unwrap_or(0.0);
+        key(b).total_cmp(&key(a))
+    });
+    for old in names.into_iter().skip(keep) {
+        if let Err(e) = fs::remove_dir_all(releases.join(&old)) {
+            warn!("Could not prune release {old}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own for each test.
+    fn scratch(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tor-node-parser-{}-{test}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn read(path: impl AsRef<Path>) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn names_must_stay_inside_the_output_directory() {
+        for ok in ["all.csv", "maps/map.svg", "a/b/c.csv.gz"] {
+            assert!(check_name(ok).is_ok(), "{ok}");
+        }
+        for bad in ["", "/etc/passwd", "../all.csv", "maps/../../x", "./all.csv"] {
+            assert!(check_name(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn in_place_commit_replaces_files_and_removes_staging() {
+        let dir = scratch("in-place");
+        fs::write(dir.join("all.csv"), "old").unwrap();
+
+        let mut tx = Transaction::begin(&dir, Layout::InPlace).unwrap();
+        tx.write("all.csv", b"new").unwrap();
+        tx.write("maps/map.svg", b"<svg/>").unwrap();
+        tx.copy("all.csv", "latest.csv").unwrap();
+        assert!(tx.write("all.csv", b"again").is_err());
+        let staging = tx.staging.clone();
+        tx.commit().unwrap();
+
+        assert_eq!(read(dir.join("all.csv")), "new");
+        assert_eq!(read(dir.join("latest.csv")), "new");
+        assert_eq!(read(dir.join("maps/map.svg")), "<svg/>");
+        assert!(!staging.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn abandoned_transaction_changes_nothing() {
+        let dir = scratch("abandoned");
+        fs::write(dir.join("all.csv"), "old").unwrap();
+
+        let mut tx = Transaction::begin(&dir, Layout::InPlace).unwrap();
+        tx.write("all.csv", b"new").unwrap();
+        let staging = tx.staging.clone();
+        drop(tx);
+
+        assert_eq!(read(dir.join("all.csv")), "old");
+        assert!(!staging.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_commit_rolls_back_what_was_swapped() {
+        let dir = scratch("rollback");
+        fs::write(dir.join("all.csv"), "old").unwrap();
+        // A file where `sub/` should be makes the second rename fail.
+        fs::write(dir.join("sub"), "in the way").unwrap();
+
+        let mut tx = Transaction::begin(&dir, Layout::InPlace).unwrap();
+        tx.write("all.csv", b"new").unwrap();
+        tx.write("new.csv", b"new").unwrap();
+        tx.write("sub/x.csv", b"new").unwrap();
+        let staging = tx.staging.clone();
+        let err = tx.commit().unwrap_err();
+
+        assert!(format!("{err:#}").contains("previous files restored"), "{err:#}");
+        assert_eq!(read(dir.join("all.csv")), "old");
+        assert!(!dir.join("new.csv").exists());
+        assert!(!staging.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stuck_rollback_is_reported_and_keeps_staging() {
+        let dir = scratch("stuck");
+        fs::write(dir.join("all.csv"), "new").unwrap();
+        fs::write(dir.join("new.csv"), "new").unwrap();
+
+        let mut tx = Transaction::begin(&dir, Layout::InPlace).unwrap();
+        let backup = tx.staging.join(".previous");
+        fs::create_dir(&backup).unwrap();
+        // `all.csv` had a previous version, but it is not in the backup.
+        let stuck = tx.roll_back(&backup, vec![("all.csv", true), ("new.csv", false)]);
+        assert_eq!(stuck, ["all.csv"]);
+        assert!(!dir.join("new.csv").exists());
+
+        tx.keep_staging = true;
+        drop(tx);
+        assert!(backup.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn versioned_commit_repoints_current_and_prunes() {
+        let dir = scratch("versioned");
+        for run in ["one", "two", "three"] {
+            let mut tx = Transaction::begin(&dir, Layout::Versioned { keep: 2 }).unwrap();
+            tx.write("all.csv", run.as_bytes()).unwrap();
+            tx.commit().unwrap();
+            // Release names have millisecond resolution.
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+
+        assert_eq!(read(dir.join("current/all.csv")), "three");
+        let releases: Vec<_> = fs::read_dir(dir.join("releases")).unwrap().collect();
+        assert_eq!(releases.len(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn published(relays_published: &str) -> Vars {
+        let response: OnionooResponse =
+            serde_json::from_value(serde_json::json!({ "relays_published": relays_published, "relays": [] })).unwrap();
+        Vars::new(&response)
+    }
+
+    #[test]
+    fn templates_expand_every_placeholder() {
+        let vars = published("2024-05-01 12:07:05");
+        assert_eq!(vars.render("{date}/{category}-{published}.{ext}", "exits", "csv"), "2024-05-01/exits-2024-05-01T120705Z.csv");
+
+        let out = Options { templates: vec!["{category}.{ext}".into(), "latest/{category}.{ext}".into()], ..Default::default() };
+        assert_eq!(out.names("all", "csv", &vars), ["all.csv", "latest/all.csv"]);
+        assert_eq!(Options::default().names("map", "svg", &vars), ["map.svg"]);
+    }
+
+    #[test]
+    fn templates_are_checked() {
+        assert!(check_template("{date}/{category}.{ext}").is_ok());
+        assert!(check_template("{categroy}.{ext}").unwrap_err().to_string().contains("unknown placeholder"));
+        assert!(check_template("/srv/{category}.{ext}").is_err());
+        assert!(check_template("../{category}.{ext}").is_err());
+    }
+
+    #[test]
+    fn compressed_variants_are_published_alongside() {
+        let dir = scratch("compressed");
+        let mut tx = Transaction::begin(&dir, Layout::InPlace).unwrap();
+        tx.compress = vec!["gz".parse().unwrap(), "zstd".parse().unwrap()];
+        tx.write("all.csv", b"fingerprint\nA\n").unwrap();
+        tx.commit().unwrap();
+
+        let gzip = flate2::read::GzDecoder::new(File::open(dir.join("all.csv.gz")).unwrap());
+        assert_eq!(io::read_to_string(gzip).unwrap(), "fingerprint\nA\n");
+        assert_eq!(zstd::decode_all(File::open(dir.join("all.csv.zst")).unwrap()).unwrap(), b"fingerprint\nA\n");
+        assert!("bz2".parse::<Compression>().is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,362 @@
+//! projection.rs — map projections for the world map.
+//!
+//! A [`Projection`] turns longitude/latitude (degrees) into coordinates on
+//! its own plane, x to the east and y to the north, in whatever units suit
+//! it; [`Bounds`] says how far the whole globe extends so the renderer can
+//! fit any projection into the same canvas. Country polygons and relay
+//! dots go through the same projection.
+//!
+//! Projections are chosen by name, with optional parameters after a colon:
+//!
+//! | Spec | Projection |
+//! |------|------------|
+//! | `equirectangular` | Plate carrée (the default) |
+//! | `robinson` | Robinson, by the usual 5° table |
+//! | `natural-earth` | Natural Earth I (Šavrič et al.) |
+//! | `mercator[:MAXLAT]` | Web Mercator, clipped at ±MAXLAT (default 85.05°) |
+//! | `mollweide` | Mollweide equal-area |
+//! | `orthographic[:LON,LAT]` | A globe seen from above LON,LAT (default 0,0) |
+
+use std::f64::consts::{FRAC_PI_2, FRAC_PI_4, PI, SQRT_2};
+
+/// Extent of a projection's plane.
+#[derive(Debug, Clone, Copy)]
+pub struct Bounds {
+    pub x_min: f64,
+    pub x_max: f64,
+    pub y_min: f64,
+    pub y_max: f64,
+}
+
+impl Bounds {
+    fn symmetric(x: f64, y: f64) -> Self {
+        Self { x_min: -x, x_max: x, y_min: -y, y_max: y }
+    }
+}
+
+pub trait Projection: Send + Sync {
+    /// `(x, y)` of a point, or `None` where the projection doesn't show it
+    /// (beyond Mercator's clip latitude, or on the far side of a globe).
+    fn forward(&self, lon: f64, lat: f64) -> Option<(f64, f64)>;
+
+    /// Like [`forward`](Projection::forward), but points that aren't shown
+    /// are moved to the nearest edge of the map instead. Used for polygon
+    /// vertices, so shapes crossing the edge are cut off rather than
+    /// distorted.
+    fn forward_clamped(&self, lon: f64, lat: f64) -> (f64, f64) {
+        self.forward(lon, lat).unwrap_or_default()
+    }
+
+    fn bounds(&self) -> Bounds;
+
+    /// Latitude range shown, and worth drawing graticule lines over.
+    fn lat_range(&self) -> (f64, f64) {
+        (-90.0, 90.0)
+    }
+
+    /// Outline of the whole globe on the plane, as a closed ring. By
+    /// default the antimeridian on both sides plus the northern- and
+    /// southernmost parallels shown.
+    fn outline(&self) -> Vec<(f64, f64)> {
+        let (south, north) = self.lat_range();
+        let steps = 90;
+        let lat = |i: usize| south + (north - south) * i as f64 / steps as f64;
+        let lon = |i: usize| -180.0 + 360.0 * i as f64 / steps as f64;
+        (0..=steps).map(|i| (-180.0, lat(i)))
+            .chain((0..=steps).map(|i| (lon(i), north)))
+            .chain((0..=steps).rev().map(|i| (180.0, lat(i))))
+            .chain((0..=steps).rev().map(|i| (lon(i), south)))
+            .map(|(lon, lat)| self.forward_clamped(lon, lat))
+            .collect()
+    }
+}
+
+/// Parse a projection spec such as `robinson` or `orthographic:10,50`.
+pub fn parse(spec: &str) -> anyhow::Result<Box<dyn Projection>> {
+    let (name, params) = match spec.split_once(':') {
+        Some((name, params)) => (name, Some(params)),
+        None                 => (spec, None),
+    };
+    let numbers = || -> anyhow::Result<Vec<f64>> {
+        params
+            .map(|p| p.split(',').map(|n| n.trim().parse::<f64>()).collect::<Result<_, _>>())
+            .transpose()
+            .map(Option::unwrap_or_default)
+            .map_err(|e| anyhow::anyhow!("invalid parameters in projection `{spec}`: {e}"))
+    };
+    let no_params = |p: Box<dyn Projection>| {
+        anyhow::ensure!(params.is_none(), "projection `{name}` takes no parameters");
+        Ok(p)
+    };
+
+    match name {
+        "equirectangular" | "plate-carree" => no_params(Box::new(Equirectangular)),
+        "robinson"                         => no_params(Box::new(Robinson)),
+        "natural-earth"                    => no_params(Box::new(NaturalEarth)),
+        "mollweide"                        => no_params(Box::new(Mollweide)),
+        "mercator" => match numbers()?.as_slice() {
+            []        => Ok(Box::new(Mercator::new(85.051_128_78))),
+            &[max_lat] => {
+                anyhow::ensure!(max_lat > 0.0 && max_lat < 90.0, "mercator clip latitude must be between 0 and 90");
+                Ok(Box::new(Mercator::new(max_lat)))
+            }
+            _ => anyhow::bail!("mercator takes one parameter, the clip latitude"),
+        },
+        "orthographic" => match numbers()?.as_slice() {
+            []         => Ok(Box::new(Orthographic::new(0.0, 0.0))),
+            &[lon, lat] => {
+                anyhow::ensure!((-90.0..=90.0).contains(&lat), "orthographic centre latitude must be within ±90");
+                Ok(Box::new(Orthographic::new(lon, lat)))
+            }
+            _ => anyhow::bail!("orthographic takes two parameters, the centre LON,LAT"),
+        },
+        other => anyhow::bail!(
+            "unknown projection `{other}` (expected equirectangular, robinson, natural-earth, mercator, mollweide or orthographic)"
+        ),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Projections
+// ---------------------------------------------------------------------------
+
+/// Plate carrée: degrees straight onto the plane.
+pub struct Equirectangular;
+
+impl Projection for Equirectangular {
+    fn forward(&self, lon: f64, lat: f64) -> Option<(f64, f64)> {
+        Some((lon, lat))
+    }
+
+    fn bounds(&self) -> Bounds {
+        Bounds::symmetric(180.0, 90.0)
+    }
+}
+
+/// Robinson, interpolated linearly in the standard 5° table.
+pub struct Robinson;
+
+/// Parallel length (X) and distance from the equator (Y) every 5°.
+const ROBINSON: [(f64, f64); 19] = [
+    (1.0000, 0.0000), (0.9986, 0.0620), (0.9954, 0.1240), (0.9900, 0.1860),
+    (0.9822, 0.2480), (0.9730, 0.3100), (0.9600, 0.3720), (0.9427, 0.4340),
+    (0.9216, 0.4958), (0.8962, 0.5571), (0.8679, 0.6176), (0.8350, 0.6769),
+    (0.7986, 0.7346), (0.7597, 0.7903), (0.7186, 0.8435), (0.6732, 0.8936),
+    (0.6213, 0.9394), (0.5722, 0.9761), (0.5322, 1.0000),
+];
+
+impl Projection for Robinson {
+    fn forward(&self, lon: f64, lat: f64) -> Option<(f64, f64)> {
+        let t = (lat.abs().min(90.0)) / 5.0;
+        let i = (t.floor() as usize).min(ROBINSON.len() - 2);
+        let f = t - i as f64;
+        let (x0, y0) = ROBINSON[i];
+        let (x1, y1) = ROBINSON[i + 1];
+        let x = x0 + (x1 - x0) * f;
+        let y = y0 + (y1 - y0) * f;
+        Some((0.8487 * x * lon.to_radians(), 1.3523 * y * lat.signum()))
+    }
+
+    fn bounds(&self) -> Bounds {
+        Bounds::symmetric(0.8487 * PI, 1.3523)
+    }
+}
+
+/// Natural Earth I, by the polynomial of Šavrič, Jenny, Patterson et al.
+pub struct NaturalEarth;
+
+impl Projection for NaturalEarth {
+    fn forward(&self, lon: f64, lat: f64) -> Option<(f64, f64)> {
+        let (lambda, phi) = (lon.to_radians(), lat.to_radians());
+        let phi2 = phi * phi;
+        let phi4 = phi2 * phi2;
+        let x = lambda * (0.8707 - 0.131979 * phi2 + phi4 * (-0.013791 + phi4 * (0.003971 * phi2 - 0.001529 * phi4)));
+        let y = phi * (1.007226 + phi2 * (0.015085 + phi4 * (-0.044475 + 0.028874 * phi2 - 0.005916 * phi4)));
+        Some((x, y))
+    }
+
+    fn bounds(&self) -> Bounds {
+        let (x, _) = self.forward(180.0, 0.0).unwrap_or_default();
+        let (_, y) = self.forward(0.0, 90.0).unwrap_or_default();
+        Bounds::symmetric(x, y)
+    }
+}
+
+/// Web Mercator, cut off at `±max_lat` where it would run to infinity.
+pub struct Mercator {
+    max_lat: f64,
+}
+
+impl Mercator {
+    pub fn new(max_lat: f64) -> Self {
+        Self { max_lat }
+    }
+
+    fn y(lat: f64) -> f64 {
+        (FRAC_PI_4 + lat.to_radians() / 2.0).tan().ln()
+    }
+}
+
+impl Projection for Mercator {
+    fn forward(&self, lon: f64, lat: f64) -> Option<(f64, f64)> {
+        (lat.abs() <= self.max_lat).then(|| (lon.to_radians(), Self::y(lat)))
+    }
+
+    fn forward_clamped(&self, lon: f64, lat: f64) -> (f64, f64) {
+        (lon.to_radians(), Self::y(lat.clamp(-self.max_lat, self.max_lat)))
+    }
+
+    fn bounds(&self) -> Bounds {
+        Bounds::symmetric(PI, Self::y(self.max_lat))
+    }
+
+    fn lat_range(&self) -> (f64, f64) {
+        (-self.max_lat, self.max_lat)
+    }
+}
+
+/// Mollweide equal-area.
+pub struct Mollweide;
+
+impl Projection for Mollweide {
+    fn forward(&self, lon: f64, lat: f64) -> Option<(f64, f64)> {
+        let phi = lat.to_radians();
+        // Solve 2θ + sin 2θ = π sin φ by Newton's method; θ = ±π/2 at the poles.
+        let theta = if (phi.abs() - FRAC_PI_2).abs() < 1e-9 {
+            phi
+        } else {
+            let target = PI * phi.sin();
+            let mut t = phi;
+            for _ in 0..50 {
+                let delta = (2.0 * t + (2.0 * t).sin() - target) / (2.0 + 2.0 * (2.0 * t).cos());
+                t -= delta;
+                if delta.abs() < 1e-10 {
+                    break;
+                }
+            }
+            t
+        };
+        Some((2.0 * SQRT_2 / PI * lon.to_radians() * theta.cos(), SQRT_2 * theta.sin()))
+    }
+
+    fn bounds(&self) -> Bounds {
+        Bounds::symmetric(2.0 * SQRT_2, SQRT_2)
+    }
+}
+
+/// The globe seen from infinitely far above `(lon0, lat0)`.
+pub struct Orthographic {
+    lon0: f64,
+    sin_lat0: f64,
+    cos_lat0: f64,
+}
+
+impl Orthographic {
+    pub fn new(lon0: f64, lat0: f64) -> Self {
+        let (sin_lat0, cos_lat0) = lat0.to_radians().sin_cos();
+        Self { lon0, sin_lat0, cos_lat0 }
+    }
+
+    /// Plane coordinates and whether the point faces the viewer.
+    fn project(&self, lon: f64, lat: f64) -> ((f64, f64), bool) {
+        let (sin_phi, cos_phi) = lat.to_radians().sin_cos();
+        let (sin_dl, cos_dl) = (lon - self.lon0).to_radians().sin_cos();
+        let visible = self.sin_lat0 * sin_phi + self.cos_lat0 * cos_phi * cos_dl >= 0.0;
+        let x = cos_phi * sin_dl;
+        let y = self.cos_lat0 * sin_phi - self.sin_lat0 * cos_phi * cos_dl;
+        ((x, y), visible)
+    }
+}
+
+impl Projection for Orthographic {
+    fn forward(&self, lon: f64, lat: f64) -> Option<(f64, f64)> {
+        let (xy, visible) = self.project(lon, lat);
+        visible.then_some(xy)
+    }
+
+    fn forward_clamped(&self, lon: f64, lat: f64) -> (f64, f64) {
+        match self.project(lon, lat) {
+            (xy, true) => xy,
+            // Push far-side points out to the horizon along the same bearing.
+            ((x, y), false) => {
+                let r = x.hypot(y);
+                if r > 0.0 { (x / r, y / r) } else { (0.0, 1.0) }
+            }
+        }
+    }
+
+    fn bounds(&self) -> Bounds {
+        Bounds::symmetric(1.0, 1.0)
+    }
+
+    /// The horizon.
+    fn outline(&self) -> Vec<(f64, f64)> {
+        (0..=360).map(|deg| (f64::from(deg).to_radians().cos(), f64::from(deg).to_radians().sin())).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: (f64, f64), b: (f64, f64)) -> bool {
+        (a.0 - b.0).abs() < 1e-6 && (a.1 - b.1).abs() < 1e-6
+    }
+
+    #[test]
+    fn specs_are_parsed_with_their_parameters() {
+        let valid = [
+            "equirectangular", "plate-carree", "robinson", "natural-earth", "mollweide",
+            "mercator", "mercator:80", "orthographic", "orthographic:10,50",
+        ];
+        for spec in valid {
+            assert!(parse(spec).is_ok(), "{spec}");
+        }
+        assert!(parse("mercator:80").unwrap().forward(0.0, 81.0).is_none());
+        assert!(parse("mercator").unwrap().forward(0.0, 85.0).is_some());
+
+        for spec in ["robinson:1", "mercator:90", "mercator:1,2", "mercator:north", "orthographic:10", "orthographic:0,91", "peters"] {
+            assert!(parse(spec).is_err(), "{spec}");
+        }
+    }
+
+    #[test]
+    fn the_globe_fits_each_projections_bounds() {
+        for spec in ["equirectangular", "robinson", "natural-earth", "mercator", "mollweide"] {
+            let projection = parse(spec).unwrap();
+            let b = projection.bounds();
+            for &(lon, lat) in &[(-180.0, 0.0), (180.0, 0.0), (0.0, 90.0), (0.0, -90.0), (180.0, 90.0), (-120.0, 45.0)] {
+                let (x, y) = projection.forward_clamped(lon, lat);
+                assert!(x >= b.x_min - 1e-6 && x <= b.x_max + 1e-6, "{spec} x at {lon},{lat}");
+                assert!(y >= b.y_min - 1e-6 && y <= b.y_max + 1e-6, "{spec} y at {lon},{lat}");
+            }
+            assert!(close(projection.forward_clamped(0.0, 0.0), (0.0, 0.0)), "{spec}");
+            // The corners of the outline reach the edges of the bounds.
+            let outline = projection.outline();
+            assert!(outline.iter().any(|&(x, _)| (x - b.x_min).abs() < 1e-6), "{spec}");
+            assert!(outline.iter().any(|&(_, y)| (y - b.y_max).abs() < 1e-6), "{spec}");
+        }
+    }
+
+    #[test]
+    fn known_points() {
+        assert!(close(Robinson.forward(180.0, 90.0).unwrap(), (0.8487 * 0.5322 * PI, 1.3523)));
+        assert!(close(Mollweide.forward(180.0, 0.0).unwrap(), (2.0 * SQRT_2, 0.0)));
+        assert!(close(Mollweide.forward(0.0, 90.0).unwrap(), (0.0, SQRT_2)));
+        let mercator = Mercator::new(85.051_128_78);
+        assert!(close(mercator.forward(180.0, 0.0).unwrap(), (PI, 0.0)));
+        // Web Mercator's clip latitude makes the map square.
+        assert!((mercator.bounds().y_max - PI).abs() < 1e-6);
+        assert!(close(mercator.forward_clamped(0.0, 89.0), (0.0, mercator.bounds().y_max)));
+    }
+
+    #[test]
+    fn orthographic_hides_the_far_side() {
+        let globe = Orthographic::new(10.0, 50.0);
+        assert!(close(globe.forward(10.0, 50.0).unwrap(), (0.0, 0.0)));
+        assert!(globe.forward(-170.0, -50.0).is_none());
+        let (x, y) = globe.forward_clamped(-170.0, -40.0);
+        assert!((x.hypot(y) - 1.0).abs() < 1e-9);
+        assert_eq!(globe.outline().len(), 361);
+    }
+}
@@ -0,0 +1,190 @@
+//! raster.rs — PNG and WebP images of a rendered SVG map.
+//!
+//! Everything happens in-process: resvg parses and draws the SVG onto a
+//! tiny-skia canvas, which is then encoded as PNG (with a `pHYs` chunk
+//! recording the DPI) or as lossless WebP. Text is set in the system's
+//! fonts, loaded once per process.
+//!
+//! Size: the SVG's own size (1200×600 for the map) at 96 DPI, scaled by
+//! `dpi / 96`; or a fixed pixel `width`, the height following the aspect
+//! ratio, in which case `dpi` is only recorded in the file.
+
+use std::sync::{Arc, OnceLock};
+
+use flate2::Crc;
+use resvg::{tiny_skia, usvg};
+
+/// CSS pixels per inch, the size SVG user units are drawn at.
+pub const DEFAULT_DPI: u32 = 96;
+const DPI_RANGE: std::ops::RangeInclusive<u32> = 24..=2400;
+/// Upper bound on either side of the image, in pixels.
+const MAX_SIDE: u32 = 16_384;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Png,
+    Webp,
+}
+
+/// Resolution of a raster image.
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// Width in pixels; `None` for the SVG's width at `dpi`.
+    pub width: Option<u32>,
+    pub dpi: u32,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self { width: None, dpi: DEFAULT_DPI }
+    }
+}
+
+impl Options {
+    pub fn check(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            DPI_RANGE.contains(&self.dpi),
+            "dpi must be between {} and {}",
+            DPI_RANGE.start(),
+            DPI_RANGE.end()
+        );
+        if let Some(width) = self.width {
+            anyhow::ensure!((1..=MAX_SIDE).contains(&width), "width must be between 1 and {MAX_SIDE} pixels");
+        }
+        Ok(())
+    }
+}
+
+/// Draw `svg` and encode it.
+pub fn render(svg: &str, encoding: Encoding, opts: &Options) -> anyhow::Result<Vec<u8>> {
+    opts.check()?;
+    let usvg_opts = usvg::Options { fontdb: fonts(), ..usvg::Options::default() };
+    let tree = usvg::Tree::from_str(svg, &usvg_opts).map_err(|e| anyhow::anyhow!("cannot parse the SVG: {e}"))?;
+
+    let size = tree.size();
+    let scale = match opts.width {
+        Some(width) => width as f32 / size.width(),
+        None        => opts.dpi as f32 / DEFAULT_DPI as f32,
+    };
+    let (w, h) = ((size.width() * scale).round() as u32, (size.height() * scale).round() as u32);
+    anyhow::ensure!(
+        w.max(h) <= MAX_SIDE,
+        "{w}×{h} pixels is too large (at most {MAX_SIDE} on either side); lower the dpi or width"
+    );
+    let mut pixmap = tiny_skia::Pixmap::new(w.max(1), h.max(1))
+        .ok_or_else(|| anyhow::anyhow!("cannot allocate a {w}×{h} image"))?;
+    resvg::render(&tree, tiny_skia::Transform::from_scale(scale, scale), &mut pixmap.as_mut());
+
+    match encoding {
+        Encoding::Png => {
+            let png = pixmap.encode_png().map_err(|e| anyhow::anyhow!("cannot encode PNG: {e}"))?;
+            Ok(with_dpi(png, opts.dpi))
+        }
+        Encoding::Webp => {
+            let rgba: Vec<u8> = pixmap
+                .pixels()
+                .iter()
+                .flat_map(|p| {
+                    let c = p.demultiply();
+                    [c.red(), c.green(), c.blue(), c.alpha()]
+                })
+                .collect();
+            let mut webp = Vec::new();
+            image_webp::WebPEncoder::new(&mut webp)
+                .encode(&rgba, pixmap.width(), pixmap.height(), image_webp::ColorType::Rgba8)
+                .map_err(|e| anyhow::anyhow!("cannot encode WebP: {e}"))?;
+            Ok(webp)
+        }
+    }
+}
+
+/// System fonts, shared by every render.
+fn fonts() -> Arc<usvg::fontdb::Database> {
+    static FONTS: OnceLock<Arc<usvg::fontdb::Database>> = OnceLock::new();
+    FONTS
+        .get_or_init(|| {
+            let mut db = usvg::fontdb::Database::new();
+            db.load_system_fonts();
+            if db.is_empty() {
+                warn!("No system fonts found — map labels will be missing from raster images.");
+            }
+            // The map's labels ask for `monospace`, which fontdb takes to
+            // mean Courier New unless fontconfig says otherwise.
+            let query = usvg::fontdb::Query { families: &[usvg::fontdb::Family::Monospace], ..Default::default() };
+            if db.query(&query).is_none() {
+                let fallback = db.faces().find(|f| f.monospaced).and_then(|f| f.families.first()).map(|(name, _)| name.clone());
+                if let Some(name) = fallback {
+                    db.set_monospace_family(name);
+                }
+            }
+            Arc::new(db)
+        })
+        .clone()
+}
+
+/// `png` with a `pHYs` chunk saying it is `dpi` dots per inch, right after
+/// `IHDR` (8-byte signature + 25-byte chunk) where the spec wants it.
+fn with_dpi(png: Vec<u8>, dpi: u32) -> Vec<u8> {
+    const AFTER_IHDR: usize = 33;
+    let per_metre = (dpi as f64 / 0.0254).round() as u32;
+    let mut body = b"pHYs".to_vec();
+    body.extend_from_slice(&per_metre.to_be_bytes());
+    body.extend_from_slice(&per_metre.to_be_bytes());
+    body.push(1); // unit: metre
+    let mut crc = Crc::new();
+    crc.update(&body);
+
+    let mut out = Vec::with_capacity(png.len() + 21);
+    out.extend_from_slice(&png[..AFTER_IHDR]);
+    out.extend_from_slice(&9u32.to_be_bytes());
+    out.extend_from_slice(&body);
+    out.extend_from_slice(&crc.sum().to_be_bytes());
+    out.extend_from_slice(&png[AFTER_IHDR..]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SVG: &str = "<svg xmlns='http://www.w3.org/2000/svg' width='120' height='60'><rect width='120' height='60' fill='#123456'/></svg>";
+
+    fn be_u32(bytes: &[u8], at: usize) -> u32 {
+        u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn options_are_checked() {
+        assert!(Options::default().check().is_ok());
+        assert!(Options { dpi: 10, ..Options::default() }.check().is_err());
+        assert!(Options { width: Some(0), ..Options::default() }.check().is_err());
+        assert!(Options { width: Some(MAX_SIDE + 1), ..Options::default() }.check().is_err());
+    }
+
+    #[test]
+    fn png_is_sized_by_dpi_and_records_it() {
+        let png = render(SVG, Encoding::Png, &Options { width: None, dpi: 192 }).unwrap();
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!((be_u32(&png, 16), be_u32(&png, 20)), (240, 120));
+        // pHYs right after IHDR, in dots per metre.
+        assert_eq!(&png[37..41], b"pHYs");
+        assert_eq!((be_u32(&png, 41), png[49]), (7_559, 1));
+
+        // A fixed width keeps the aspect ratio and only records the dpi.
+        let png = render(SVG, Encoding::Png, &Options { width: Some(50), dpi: 300 }).unwrap();
+        assert_eq!((be_u32(&png, 16), be_u32(&png, 20)), (50, 25));
+        assert_eq!(be_u32(&png, 41), 11_811);
+    }
+
+    #[test]
+    fn webp_and_errors() {
+        let webp = render(SVG, Encoding::Webp, &Options::default()).unwrap();
+        assert_eq!((&webp[0..4], &webp[8..12]), (&b"RIFF"[..], &b"WEBP"[..]));
+
+        // The map's own 1200×600 at 2400 dpi.
+        let map = SVG.replace("'120'", "'1200'").replace("'60'", "'600'");
+        let huge = render(&map, Encoding::Png, &Options { width: None, dpi: 2_400 }).err().unwrap();
+        assert!(huge.to_string().contains("too large"), "{huge}");
+        assert!(render("<svg", Encoding::Png, &Options::default()).is_err());
+    }
+}
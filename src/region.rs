@@ -0,0 +1,272 @@
+//! region.rs — the part of the world a map shows.
+//!
+//! A region is a longitude/latitude box, given as one of:
+//!
+//! | Spec | Region |
+//! |------|--------|
+//! | `europe`, `north-america`, `south-america`, `africa`, `asia`, `oceania` | A continent |
+//! | `de`, `us`, … | A country by ISO 3166-1 alpha-2 code, boxed around its mainland and nearby islands |
+//! | `W,S,E,N` | A box in degrees, in GeoJSON `bbox` order, e.g. `5.5,47,15.5,55.5` |
+//!
+//! Country boxes come from the map's own GeoJSON, so they are only known
+//! once it is loaded: [`parse`] checks the spec and [`Region::resolve`]
+//! turns it into an [`Area`].
+
+use serde_json::Value;
+
+/// A longitude/latitude box, in degrees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BBox {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+}
+
+impl BBox {
+    pub const WORLD: BBox = BBox { west: -180.0, south: -90.0, east: 180.0, north: 90.0 };
+
+    /// The larger of the two spans, in degrees.
+    pub fn span(&self) -> f64 {
+        (self.east - self.west).max(self.north - self.south)
+    }
+
+    /// Grown by `margin` degrees on every side, within the world.
+    pub fn grow(&self, margin: f64) -> BBox {
+        BBox {
+            west:  (self.west - margin).max(-180.0),
+            south: (self.south - margin).max(-90.0),
+            east:  (self.east + margin).min(180.0),
+            north: (self.north + margin).min(90.0),
+        }
+    }
+
+    fn intersects(&self, other: &BBox) -> bool {
+        self.west <= other.east && other.west <= self.east && self.south <= other.north && other.south <= self.north
+    }
+
+    fn union(&self, other: &BBox) -> BBox {
+        BBox {
+            west:  self.west.min(other.west),
+            south: self.south.min(other.south),
+            east:  self.east.max(other.east),
+            north: self.north.max(other.north),
+        }
+    }
+}
+
+/// A parsed region spec.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Region {
+    Box { label: String, bbox: BBox },
+    /// Upper-case ISO 3166-1 alpha-2 code.
+    Country(String),
+}
+
+/// A resolved region: what to call it and where it is.
+#[derive(Debug, Clone)]
+pub struct Area {
+    pub label: String,
+    pub bbox: BBox,
+}
+
+const CONTINENTS: [(&str, &str, BBox); 6] = [
+    ("europe",        "Europe",        BBox { west: -25.0,  south: 34.0,  east: 45.0,  north: 72.0 }),
+    ("north-america", "North America", BBox { west: -170.0, south: 7.0,   east: -52.0, north: 72.0 }),
+    ("south-america", "South America", BBox { west: -82.0,  south: -56.0, east: -34.0, north: 13.0 }),
+    ("africa",        "Africa",        BBox { west: -19.0,  south: -35.0, east: 52.0,  north: 38.0 }),
+    ("asia",          "Asia",          BBox { west: 25.0,   south: -11.0, east: 150.0, north: 60.0 }),
+    ("oceania",       "Oceania",       BBox { west: 110.0,  south: -48.0, east: 180.0, north: 0.0 }),
+];
+
+/// Margin around a country's polygons, in degrees.
+const COUNTRY_MARGIN: f64 = 1.0;
+/// How far from a country's largest polygon, as a fraction of its span,
+/// other polygons still count as part of the country's region.
+const NEARBY: f64 = 0.25;
+
+/// Parse a region spec such as `europe`, `de` or `5.5,47,15.5,55.5`.
+pub fn parse(spec: &str) -> anyhow::Result<Region> {
+    let spec = spec.trim();
+    if let Some((_, label, bbox)) = CONTINENTS.iter().find(|(name, ..)| name.eq_ignore_ascii_case(spec)) {
+        return Ok(Region::Box { label: label.to_string(), bbox: *bbox });
+    }
+    if spec.len() == 2 && spec.bytes().all(|b| b.is_ascii_alphabetic()) {
+        return Ok(Region::Country(spec.to_ascii_uppercase()));
+    }
+    if spec.contains(',') {
+        let numbers = spec
+            .split(',')
+            .map(|n| n.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow::anyhow!("invalid bounding box `{spec}`: {e}"))?;
+        let &[west, south, east, north] = numbers.as_slice() else {
+            anyhow::bail!("bounding box `{spec}` needs four numbers, WEST,SOUTH,EAST,NORTH");
+        };
+        anyhow::ensure!(
+            (-180.0..=180.0).contains(&west) && (-180.0..=180.0).contains(&east) && west < east,
+            "bounding box `{spec}`: longitudes must be within ±180, west of east"
+        );
+        anyhow::ensure!(
+            (-90.0..=90.0).contains(&south) && (-90.0..=90.0).contains(&north) && south < north,
+            "bounding box `{spec}`: latitudes must be within ±90, south of north"
+        );
+        return Ok(Region::Box { label: spec.to_string(), bbox: BBox { west, south, east, north } });
+    }
+    anyhow::bail!(
+        "unknown region `{spec}` (expected a continent, an ISO country code or WEST,SOUTH,EAST,NORTH)"
+    )
+}
+
+impl Region {
+    pub fn resolve(&self, geojson: &Value) -> anyhow::Result<Area> {
+        match self {
+            Region::Box { label, bbox } => Ok(Area { label: label.clone(), bbox: *bbox }),
+            Region::Country(code) => country(geojson, code),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Country boxes
+// ---------------------------------------------------------------------------
+
+/// Both the older Natural Earth and the current geo-countries property names.
+const ISO_KEYS:  [&str; 3] = ["ISO_A2_EH", "ISO_A2", "ISO3166-1-Alpha-2"];
+const NAME_KEYS: [&str; 3] = ["ADMIN", "name", "NAME"];
+
+/// Countries Natural Earth gives no ISO code (`-99`) for overseas-territory
+/// reasons, by name.
+const MISSING_ISO: [(&str, &str); 2] = [("France", "FR"), ("Norway", "NO")];
+
+fn property<'a>(feature: &'a Value, keys: &[&str]) -> Option<&'a str> {
+    keys.iter().find_map(|k| feature["properties"][*k].as_str())
+}
+
+/// Upper-case ISO 3166-1 alpha-2 code of a GeoJSON country feature, to
+/// match against Onionoo's `country`.
+pub fn iso_code(feature: &Value) -> Option<String> {
+    ISO_KEYS
+        .iter()
+        .filter_map(|k| feature["properties"][*k].as_str())
+        .find(|c| c.len() == 2 && c.bytes().all(|b| b.is_ascii_alphabetic()))
+        .or_else(|| {
+            let name = property(feature, &NAME_KEYS)?;
+            MISSING_ISO.iter().find(|(n, _)| *n == name).map(|(_, code)| *code)
+        })
+        .map(str::to_ascii_uppercase)
+}
+
+/// Box around the largest polygon of the country `code` and the polygons
+/// close to it, so that `fr` shows France and Corsica but not Réunion.
+fn country(geojson: &Value, code: &str) -> anyhow::Result<Area> {
+    let feature = geojson["features"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|f| iso_code(f).as_deref() == Some(code))
+        .ok_or_else(|| anyhow::anyhow!("no country with ISO code `{code}` in the map data"))?;
+
+    let geometry = &feature["geometry"];
+    let polygons: Vec<&Value> = match geometry["type"].as_str() {
+        Some("MultiPolygon") => geometry["coordinates"].as_array().into_iter().flatten().collect(),
+        _                    => vec![&geometry["coordinates"]],
+    };
+    let extents: Vec<(BBox, f64)> = polygons.into_iter().filter_map(polygon_extent).collect();
+    let (main, _) = extents
+        .iter()
+        .copied()
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .ok_or_else(|| anyhow::anyhow!("country `{code}` has no polygons in the map data"))?;
+    let near = main.grow(main.span() * NEARBY);
+    let bbox = extents
+        .iter()
+        .filter(|(b, _)| b.intersects(&near))
+        .fold(main, |acc, (b, _)| acc.union(b));
+
+    Ok(Area {
+        label: property(feature, &NAME_KEYS).unwrap_or(code).to_string(),
+        bbox: bbox.grow(COUNTRY_MARGIN),
+    })
+}
+
+/// Box of one GeoJSON polygon and the area of its outer ring, in square
+/// degrees.
+fn polygon_extent(polygon: &Value) -> Option<(BBox, f64)> {
+    let mut bbox: Option<BBox> = None;
+    visit_positions(polygon, &mut |lon, lat| {
+        let b = bbox.get_or_insert(BBox { west: lon, south: lat, east: lon, north: lat });
+        b.west  = b.west.min(lon);
+        b.south = b.south.min(lat);
+        b.east  = b.east.max(lon);
+        b.north = b.north.max(lat);
+    });
+    let ring: Vec<(f64, f64)> = polygon[0]
+        .as_array()?
+        .iter()
+        .filter_map(|p| Some((p[0].as_f64()?, p[1].as_f64()?)))
+        .collect();
+    // Shoelace formula.
+    let twice_area: f64 = ring.iter().zip(ring.iter().cycle().skip(1)).map(|(a, b)| a.0 * b.1 - b.0 * a.1).sum();
+    Some((bbox?, twice_area.abs() / 2.0))
+}
+
+/// Call `f` with every `[lon, lat]` position in nested GeoJSON coordinates.
+fn visit_positions(coords: &Value, f: &mut impl FnMut(f64, f64)) {
+    let Some(items) = coords.as_array() else { return };
+    match (items.first().and_then(Value::as_f64), items.get(1).and_then(Value::as_f64)) {
+        (Some(lon), Some(lat)) => f(lon, lat),
+        _ => items.iter().for_each(|c| visit_positions(c, f)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn specs_are_continents_countries_or_boxes() {
+        assert!(matches!(parse("Europe").unwrap(), Region::Box { label, .. } if label == "Europe"));
+        assert_eq!(parse(" de ").unwrap(), Region::Country("DE".into()));
+        assert_eq!(
+            parse("5.5, 47, 15.5, 55.5").unwrap(),
+            Region::Box { label: "5.5, 47, 15.5, 55.5".into(), bbox: BBox { west: 5.5, south: 47.0, east: 15.5, north: 55.5 } },
+        );
+        for spec in ["atlantis", "deu", "1,2,3", "10,0,5,10", "0,10,10,5", "-190,0,10,10", "0,-91,10,10", "a,b,c,d"] {
+            assert!(parse(spec).is_err(), "{spec}");
+        }
+    }
+
+    #[test]
+    fn boxes_grow_within_the_world() {
+        let b = BBox { west: -179.5, south: 10.0, east: 20.0, north: 89.5 };
+        assert_eq!(b.span(), 199.5);
+        assert_eq!(b.grow(1.0), BBox { west: -180.0, south: 9.0, east: 21.0, north: 90.0 });
+    }
+
+    #[test]
+    fn countries_are_boxed_around_their_polygons() {
+        let geojson = json!({ "features": [
+            { "properties": { "ISO_A2": "-99", "ADMIN": "France" },
+              "geometry": { "type": "MultiPolygon", "coordinates": [
+                  [[[-5.0, 43.0], [8.0, 43.0], [8.0, 51.0], [-5.0, 43.0]]],
+                  [[[8.5, 41.4], [9.6, 41.4], [9.5, 43.0], [8.5, 41.4]]],
+                  [[[55.2, -21.4], [55.8, -21.4], [55.5, -20.9], [55.2, -21.4]]],
+              ] } },
+            { "properties": { "ISO_A2_EH": "de", "NAME": "Germany" },
+              "geometry": { "type": "Polygon", "coordinates": [[[6.0, 47.3], [15.0, 47.3], [15.0, 55.0], [6.0, 47.3]]] } },
+        ] });
+        assert_eq!(iso_code(&geojson["features"][0]).as_deref(), Some("FR"));
+
+        let area = parse("de").unwrap().resolve(&geojson).unwrap();
+        assert_eq!(area.label, "Germany");
+        assert_eq!(area.bbox, BBox { west: 5.0, south: 46.3, east: 16.0, north: 56.0 });
+        // Corsica, but not Réunion.
+        let area = parse("fr").unwrap().resolve(&geojson).unwrap();
+        assert_eq!(area.bbox, BBox { west: -6.0, south: 40.4, east: 10.6, north: 52.0 });
+
+        assert!(parse("it").unwrap().resolve(&geojson).is_err());
+    }
+}
@@ -0,0 +1,176 @@
+//! serve.rs — long-running HTTP server for the `serve` subcommand.
+//!
+//! A background thread reloads the details document every `interval` and
+//! renders every output of the [`Config`] in memory. Requests are answered
+//! from the latest snapshot: each output under its primary file name (e.g.
+//! `/all.csv`, `/map.svg`), Prometheus metrics on `/metrics`, and an index of
+//! everything available on `/`. A failed refresh keeps serving the previous
+//! snapshot and shows up in the metrics' error counter.
+
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::Config,
+    export,
+    metrics::Exporter,
+    onionoo::OnionooResponse,
+    output::{self, Vars},
+    world_map,
+};
+
+pub struct Options {
+    pub listen: String,
+    pub interval: Duration,
+    pub config: Config,
+    /// Only the filename templates are used; nothing is written to disk.
+    pub output: output::Options,
+}
+
+#[derive(Default)]
+struct State {
+    exporter: Exporter,
+    /// Primary file name → rendered bytes.
+    files: BTreeMap<String, Vec<u8>>,
+}
+
+/// Serve until the process is stopped, refreshing via `load` every
+/// `interval`, which must be at least a second.
+pub fn run<F>(opts: Options, load: F) -> anyhow::Result<()>
+where
+    F: Fn() -> anyhow::Result<OnionooResponse> + Send + 'static,
+{
+    // A zero interval would reload the document in a busy loop.
+    anyhow::ensure!(opts.interval >= Duration::from_secs(1), "--interval must be at least 1 second");
+    let geojson = opts.config.has_map().then(world_map::world_geojson).transpose()?;
+    let state = Arc::new(Mutex::new(State::default()));
+
+    let shared = Arc::clone(&state);
+    let Options { listen, interval, config, output } = opts;
+    thread::spawn(move || loop {
+        // Fetch and render outside the lock so requests are never blocked.
+        let started = Instant::now();
+        let loaded = load();
+        let elapsed = started.elapsed();
+        let rendered = loaded.as_ref().ok().map(|response| render(&config, response, &output, geojson.as_ref()));
+
+        let mut current = shared.lock().unwrap_or_else(|e| e.into_inner());
+        match loaded {
+            Ok(response) => current.exporter.record_success(&response, elapsed),
+            Err(e) => {
+                warn!("Refresh failed: {e:#}");
+                current.exporter.record_failure(elapsed);
+            }
+        }
+        match rendered {
+            Some(Ok(files)) => {
+                debug!("Rendered {} outputs.", files.len());
+                current.files = files;
+            }
+            Some(Err(e)) => warn!("Rendering failed, serving previous outputs: {e:#}"),
+            None => {}
+        }
+        drop(current);
+        thread::sleep(interval);
+    });
+
+    let listener = TcpListener::bind(&listen)?;
+    info!("Serving on http://{listen}/ (metrics on /metrics).");
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => { warn!("Accept failed: {e}"); continue; }
+        };
+        if let Err(e) = handle(stream, &state) {
+            warn!("Request failed: {e}");
+        }
+    }
+    Ok(())
+}
+
+fn render(
+    config: &Config,
+    response: &OnionooResponse,
+    out: &output::Options,
+    geojson: Option<&serde_json::Value>,
+) -> anyhow::Result<BTreeMap<String, Vec<u8>>> {
+    let vars = Vars::new(response);
+    config
+        .outputs
+        .iter()
+        .map(|spec| {
+            let name = export::names(spec, out, &vars).swap_remove(0);
+            Ok((name, export::render(spec, response, geojson)?))
+        })
+        .collect()
+}
+
+fn content_type(name: &str) -> &'static str {
+    match name.rsplit_once('.').map_or("", |(_, ext)| ext) {
+        "csv"  => "text/csv; charset=utf-8",
+        "json" => "application/json",
+        "svg"  => "image/svg+xml",
+        "html" => "text/html; charset=utf-8",
+        "png"  => "image/png",
+        "webp" => "image/webp",
+        "md"   => "text/markdown; charset=utf-8",
+        "mmdb" => "application/octet-stream",
+        _      => "text/plain; charset=utf-8",
+    }
+}
+
+fn handle(mut stream: TcpStream, state: &Mutex<State>) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let target = request_line.split_whitespace().nth(1).unwrap_or("");
+    let path = target.split('?').next().unwrap_or("");
+    debug!("GET {target}");
+
+    let (status, content_type, body): (&str, &str, Vec<u8>) = {
+        let state = state.lock().unwrap_or_else(|e| e.into_inner());
+        match path {
+            "/metrics" => ("200 OK", "text/plain; version=0.0.4; charset=utf-8", state.exporter.render().into_bytes()),
+            "/" => {
+                let mut index: String = state.files.keys().map(|name| format!("/{name}\n")).collect();
+                index.push_str("/metrics\n");
+                ("200 OK", "text/plain; charset=utf-8", index.into_bytes())
+            }
+            _ => match path.strip_prefix('/').and_then(|name| state.files.get(name)) {
+                Some(bytes) => ("200 OK", content_type(path), bytes.clone()),
+                None => ("404 Not Found", "text/plain; charset=utf-8", b"not found; see / for what is served\n".to_vec()),
+            },
+        }
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len(),
+    )?;
+    stream.write_all(&body)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_interval_is_rejected_before_serving() {
+        let opts = Options {
+            listen:   "127.0.0.1:0".into(),
+            interval: Duration::ZERO,
+            config:   Config::default(),
+            output:   output::Options::default(),
+        };
+        let err = run(opts, || anyhow::bail!("must not be loaded")).err().unwrap();
+        assert!(err.to_string().contains("at least 1 second"), "{err}");
+    }
+}
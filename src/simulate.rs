@@ -0,0 +1,473 @@
+//! simulate.rs — Monte Carlo path selection for the `simulate` subcommand.
+//!
+//! Circuits are sampled the way Tor builds them: exit first (by
+//! `exit_probability`), then guard (by `guard_probability`), then middle
+//! (by `middle_probability`), rejecting any combination that reuses a relay,
+//! puts two members of the same declared family on the path, or puts two
+//! relays in the same IPv4 /16 or IPv6 /32.
+//!
+//! For every country, AS and operator the simulator then reports how often
+//! that entity held the guard, the exit, and — the question that matters for
+//! end-to-end correlation — both at once.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    net::IpAddr,
+    str::FromStr,
+};
+
+use serde::Serialize;
+
+use crate::{onionoo::TorNode, stats::Format};
+
+/// Give up on a circuit after this many rejected samples.
+const MAX_ATTEMPTS: usize = 100;
+
+// ---------------------------------------------------------------------------
+// Entities
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Entity {
+    Country,
+    As,
+    Operator,
+}
+
+impl Entity {
+    pub const ALL: [Self; 3] = [Self::Country, Self::As, Self::Operator];
+
+    /// The entity `node` belongs to, or `None` for a relay without a
+    /// country or AS, which is left out rather than pooled with the others
+    /// into one made-up adversary. Operators are identified by their contact
+    /// string; relays without one fall back to their declared family, then
+    /// to their own fingerprint.
+    fn key(self, node: &TorNode) -> Option<String> {
+        match self {
+            Self::Country => node.country.as_deref().filter(|c| !c.is_empty()).map(str::to_uppercase),
+            Self::As      => node.as_number.clone().filter(|a| !a.is_empty()),
+            Self::Operator => Some(match node.contact.as_deref().map(str::trim) {
+                Some(c) if !c.is_empty() => c.to_lowercase(),
+                _ => family_members(node)
+                    .min()
+                    .unwrap_or(&node.fingerprint)
+                    .to_string(),
+            }),
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Country  => "country",
+            Self::As       => "AS",
+            Self::Operator => "operator",
+        }
+    }
+}
+
+impl FromStr for Entity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "country"  => Ok(Self::Country),
+            "as"       => Ok(Self::As),
+            "operator" => Ok(Self::Operator),
+            other => anyhow::bail!("unknown entity `{other}` (expected country, as or operator)"),
+        }
+    }
+}
+
+/// Fingerprints from `effective_family`, with Onionoo's `$` prefix removed.
+fn family_members(node: &TorNode) -> impl Iterator<Item = &str> {
+    node.effective_family
+        .iter()
+        .flatten()
+        .map(|fp| fp.trim_start_matches('$'))
+}
+
+// ---------------------------------------------------------------------------
+// Random sampling
+// ---------------------------------------------------------------------------
+
+/// SplitMix64 — small, fast and good enough for Monte Carlo sampling.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Cumulative distribution over relay indices with a non-zero probability.
+struct Weighted {
+    indices: Vec<usize>,
+    cumulative: Vec<f64>,
+}
+
+impl Weighted {
+    fn new(nodes: &[TorNode], probability: fn(&TorNode) -> Option<f64>) -> Self {
+        let mut indices = Vec::new();
+        let mut cumulative = Vec::new();
+        let mut total = 0.0;
+        for (i, node) in nodes.iter().enumerate() {
+            let p = probability(node).unwrap_or(0.0);
+            if p > 0.0 {
+                total += p;
+                indices.push(i);
+                cumulative.push(total);
+            }
+        }
+        Self { indices, cumulative }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    fn sample(&self, rng: &mut Rng) -> usize {
+        let total = self.cumulative.last().copied().unwrap_or(0.0);
+        let r = rng.next_f64() * total;
+        let pos = self.cumulative.partition_point(|&c| c <= r);
+        self.indices[pos.min(self.indices.len() - 1)]
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Path constraints
+// ---------------------------------------------------------------------------
+
+/// IPv4 /16 and IPv6 /32 prefixes, as used by Tor's `EnforceDistinctSubnets`.
+fn subnets(node: &TorNode) -> HashSet<(bool, u32)> {
+    node.ips()
+        .map(|ip| match ip {
+            IpAddr::V4(v4) => (false, u32::from(v4) >> 16),
+            IpAddr::V6(v6) => (true, (u128::from(v6) >> 96) as u32),
+        })
+        .collect()
+}
+
+struct Constraints<'a> {
+    nodes: &'a [TorNode],
+    subnets: Vec<HashSet<(bool, u32)>>,
+    families: Vec<HashSet<&'a str>>,
+}
+
+impl<'a> Constraints<'a> {
+    fn new(nodes: &'a [TorNode]) -> Self {
+        Self {
+            nodes,
+            subnets: nodes.iter().map(subnets).collect(),
+            families: nodes.iter().map(|n| family_members(n).collect()).collect(),
+        }
+    }
+
+    /// Whether relays `a` and `b` may appear on the same circuit.
+    fn compatible(&self, a: usize, b: usize) -> bool {
+        a != b
+            && self.subnets[a].is_disjoint(&self.subnets[b])
+            && !self.families[a].contains(self.nodes[b].fingerprint.as_str())
+            && !self.families[b].contains(self.nodes[a].fingerprint.as_str())
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Simulation
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Serialize)]
+pub struct EntityResult {
+    pub key: String,
+    pub guard: f64,
+    pub exit: f64,
+    pub both: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EntityReport {
+    pub entity: Entity,
+    /// Probability that any single entity held both guard and exit.
+    pub any_both: f64,
+    /// Relays without this kind of entity, left out of the results.
+    pub excluded: usize,
+    pub results: Vec<EntityResult>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Simulation {
+    pub circuits: usize,
+    pub failed: usize,
+    pub seed: u64,
+    pub reports: Vec<EntityReport>,
+}
+
+pub struct Options {
+    pub circuits: usize,
+    pub seed: u64,
+    pub entities: Vec<Entity>,
+    /// Only report these keys (case-insensitive); empty means all.
+    pub targets: Vec<String>,
+}
+
+impl Simulation {
+    pub fn run(nodes: &[TorNode], opts: &Options) -> anyhow::Result<Self> {
+        let guards  = Weighted::new(nodes, |n| n.guard_probability);
+        let middles = Weighted::new(nodes, |n| n.middle_probability);
+        let exits   = Weighted::new(nodes, |n| n.exit_probability);
+        anyhow::ensure!(
+            !guards.is_empty() && !middles.is_empty() && !exits.is_empty(),
+            "document has no guard/middle/exit probabilities to sample from"
+        );
+
+        let constraints = Constraints::new(nodes);
+        let keys: Vec<Vec<Option<String>>> = opts
+            .entities
+            .iter()
+            .map(|&e| nodes.iter().map(|n| e.key(n)).collect())
+            .collect();
+
+        // Per entity kind: key → (guard hits, exit hits, both hits).
+        let mut tallies: Vec<HashMap<&str, [usize; 3]>> = vec![HashMap::new(); opts.entities.len()];
+        let mut rng = Rng(opts.seed);
+        let mut failed = 0;
+
+        for _ in 0..opts.circuits {
+            let Some((guard, exit)) = sample_circuit(&guards, &middles, &exits, &constraints, &mut rng) else {
+                failed += 1;
+                continue;
+            };
+            for (tally, keys) in tallies.iter_mut().zip(&keys) {
+                let (g, e) = (keys[guard].as_deref(), keys[exit].as_deref());
+                if let Some(g) = g {
+                    tally.entry(g).or_default()[0] += 1;
+                }
+                if let Some(e) = e {
+                    tally.entry(e).or_default()[1] += 1;
+                }
+                if let Some(both) = g.filter(|&g| Some(g) == e) {
+                    tally.entry(both).or_default()[2] += 1;
+                }
+            }
+        }
+
+        let built = (opts.circuits - failed).max(1) as f64;
+        let targets: Vec<String> = opts.targets.iter().map(|t| t.to_lowercase()).collect();
+        let reports = opts
+            .entities
+            .iter()
+            .zip(tallies)
+            .zip(&keys)
+            .map(|((&entity, tally), keys)| {
+                let excluded = keys.iter().filter(|k| k.is_none()).count();
+                let any_both = tally.values().map(|t| t[2]).sum::<usize>() as f64 / built;
+                let mut results: Vec<EntityResult> = tally
+                    .into_iter()
+                    .filter(|(key, _)| targets.is_empty() || targets.contains(&key.to_lowercase()))
+                    .map(|(key, [g, e, b])| EntityResult {
+                        key: key.to_string(),
+                        guard: g as f64 / built,
+                        exit:  e as f64 / built,
+                        both:  b as f64 / built,
+                    })
+                    .collect();
+                results.sort_by(|a, b| {
+                    b.both
+                        .total_cmp(&a.both)
+                        .then(b.guard.max(b.exit).total_cmp(&a.guard.max(a.exit)))
+                        .then_with(|| a.key.cmp(&b.key))
+                });
+                EntityReport { entity, any_both, excluded, results }
+            })
+            .collect();
+
+        Ok(Self { circuits: opts.circuits, failed, seed: opts.seed, reports })
+    }
+
+    pub fn render(&self, format: Format, top: usize) -> anyhow::Result<String> {
+        if let Format::Json = format {
+            return Ok(serde_json::to_string_pretty(self)? + "\n");
+        }
+        let md = matches!(format, Format::Markdown);
+        let mut s = String::new();
+
+        if md {
+            let _ = writeln!(s, "# Guard/exit compromise simulation\n");
+        } else {
+            let _ = writeln!(s, "Guard/exit compromise simulation");
+        }
+        let _ = writeln!(
+            s,
+            "{} circuits sampled (seed {}), {} could not be built.\n",
+            self.circuits, self.seed, self.failed,
+        );
+
+        for report in &self.reports {
+            let heading = format!(
+                "By {} — any single {} holds guard and exit: {:.3}%",
+                report.entity.label(),
+                report.entity.label(),
+                report.any_both * 100.0,
+            );
+            let excluded = match report.excluded {
+                0 => String::new(),
+                n => format!("{n} relays with no known {} left out.\n\n", report.entity.label()),
+            };
+            if md {
+                let _ = writeln!(s, "## {heading}\n");
+                s.push_str(&excluded);
+                let _ = writeln!(s, "| {} | Guard | Exit | Both |\n|---|---:|---:|---:|", report.entity.label());
+            } else {
+                let _ = writeln!(s, "{heading}\n");
+                s.push_str(&excluded);
+                let _ = writeln!(s, "  {:<40}{:>10}{:>10}{:>10}", report.entity.label(), "guard", "exit", "both");
+            }
+            for r in report.results.iter().take(top) {
+                let pct = |v: f64| format!("{:.3}%", v * 100.0);
+                if md {
+                    let key = r.key.replace('|', "\\|");
+                    let _ = writeln!(s, "| {key} | {} | {} | {} |", pct(r.guard), pct(r.exit), pct(r.both));
+                } else {
+                    let key: String = r.key.chars().take(38).collect();
+                    let _ = writeln!(s, "  {key:<40}{:>10}{:>10}{:>10}", pct(r.guard), pct(r.exit), pct(r.both));
+                }
+            }
+            s.push('\n');
+        }
+        Ok(s)
+    }
+}
+
+/// Sample one valid circuit and return `(guard, exit)` indices, or `None`
+/// if no valid combination turned up within [`MAX_ATTEMPTS`].
+fn sample_circuit(
+    guards: &Weighted,
+    middles: &Weighted,
+    exits: &Weighted,
+    constraints: &Constraints,
+    rng: &mut Rng,
+) -> Option<(usize, usize)> {
+    let exit = exits.sample(rng);
+    let guard = (0..MAX_ATTEMPTS)
+        .map(|_| guards.sample(rng))
+        .find(|&g| constraints.compatible(g, exit))?;
+    (0..MAX_ATTEMPTS)
+        .map(|_| middles.sample(rng))
+        .find(|&m| constraints.compatible(m, exit) && constraints.compatible(m, guard))?;
+    Some((guard, exit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn relay(fingerprint: &str, address: &str, extra: serde_json::Value) -> TorNode {
+        let mut value = json!({ "fingerprint": fingerprint, "or_addresses": [address], "flags": [] });
+        value.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn options(circuits: usize) -> Options {
+        Options { circuits, seed: 7, entities: Entity::ALL.to_vec(), targets: Vec::new() }
+    }
+
+    #[test]
+    fn same_subnet_family_and_relay_are_incompatible() {
+        let nodes = [
+            relay("A", "10.1.0.1:9001", json!({ "effective_family": ["$C"] })),
+            relay("B", "10.1.200.1:9001", json!({})),
+            relay("C", "10.2.0.1:9001", json!({})),
+            relay("D", "[2001:db8:1::1]:9001", json!({})),
+            relay("E", "[2001:db8:2::1]:9001", json!({})),
+        ];
+        let c = Constraints::new(&nodes);
+        assert!(!c.compatible(0, 0));
+        assert!(!c.compatible(0, 1), "same /16");
+        assert!(!c.compatible(2, 0), "family, declared by one side only");
+        assert!(c.compatible(1, 2));
+        assert!(!c.compatible(3, 4), "same /32");
+    }
+
+    #[test]
+    fn operators_fall_back_to_family_then_fingerprint() {
+        let with_contact = relay("B", "1.1.1.1:1", json!({ "contact": " Ops <ops@example.org> " }));
+        let with_family = relay("B", "1.1.1.1:1", json!({ "effective_family": ["$B", "$A"] }));
+        let alone = relay("B", "1.1.1.1:1", json!({}));
+        assert_eq!(Entity::Operator.key(&with_contact).as_deref(), Some("ops <ops@example.org>"));
+        assert_eq!(Entity::Operator.key(&with_family).as_deref(), Some("A"));
+        assert_eq!(Entity::Operator.key(&alone).as_deref(), Some("B"));
+        assert_eq!(Entity::Country.key(&alone), None);
+    }
+
+    #[test]
+    fn one_country_holding_guard_and_exit_always_has_both() {
+        let nodes = [
+            relay("G", "10.1.0.1:9001", json!({ "country": "de", "guard_probability": 1.0 })),
+            relay("M", "10.2.0.1:9001", json!({ "country": "us", "middle_probability": 1.0 })),
+            relay("X", "10.3.0.1:9001", json!({ "country": "de", "exit_probability": 1.0 })),
+        ];
+        let sim = Simulation::run(&nodes, &options(50)).unwrap();
+        assert_eq!(sim.failed, 0);
+        let countries = &sim.reports[0];
+        assert_eq!(countries.results[0].key, "DE");
+        assert_eq!(countries.results[0].both, 1.0);
+        assert_eq!(countries.any_both, 1.0);
+    }
+
+    #[test]
+    fn impossible_circuits_are_counted_as_failed() {
+        // The only guard shares a /16 with the only exit.
+        let nodes = [
+            relay("G", "10.1.0.1:9001", json!({ "guard_probability": 1.0 })),
+            relay("M", "10.2.0.1:9001", json!({ "middle_probability": 1.0 })),
+            relay("X", "10.1.0.2:9001", json!({ "exit_probability": 1.0 })),
+        ];
+        let sim = Simulation::run(&nodes, &options(10)).unwrap();
+        assert_eq!(sim.failed, 10);
+        assert!(Simulation::run(&nodes[..2], &options(10)).is_err());
+    }
+
+    #[test]
+    fn the_seed_makes_runs_reproducible() {
+        let nodes: Vec<TorNode> = (0..20)
+            .map(|i| {
+                let country = ["de", "us", "fr"][i % 3];
+                relay(&format!("R{i}"), &format!("10.{i}.0.1:9001"), json!({
+                    "country": country,
+                    "guard_probability": 0.05, "middle_probability": 0.05, "exit_probability": 0.05,
+                }))
+            })
+            .collect();
+        let a = Simulation::run(&nodes, &options(500)).unwrap().render(Format::Json, 10).unwrap();
+        let b = Simulation::run(&nodes, &options(500)).unwrap().render(Format::Json, 10).unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn relays_without_a_country_or_as_are_left_out() {
+        let nodes = [
+            relay("G", "10.1.0.1:9001", json!({ "as": "AS1", "guard_probability": 1.0 })),
+            relay("M", "10.2.0.1:9001", json!({ "country": "us", "as": "AS2", "middle_probability": 1.0 })),
+            relay("X", "10.3.0.1:9001", json!({ "exit_probability": 1.0 })),
+        ];
+        let sim = Simulation::run(&nodes, &options(20)).unwrap();
+        let (countries, ases) = (&sim.reports[0], &sim.reports[1]);
+        // Neither end has a country: no "unknown" country holds both.
+        assert_eq!((countries.excluded, countries.any_both), (2, 0.0));
+        assert!(countries.results.is_empty());
+        assert_eq!(ases.excluded, 1);
+        assert_eq!(ases.results.iter().map(|r| r.key.as_str()).collect::<Vec<_>>(), ["AS1"]);
+        assert!(sim.render(Format::Text, 10).unwrap().contains("2 relays with no known country left out."));
+    }
+}
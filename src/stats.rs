@@ -0,0 +1,395 @@
+//! stats.rs — aggregate network statistics for the `stats` subcommand.
+//!
+//! Every breakdown reports both the relay count and the share of total
+//! consensus weight, since the latter is what actually drives path selection.
+
+use std::{collections::HashMap, fmt::Write, str::FromStr};
+
+use serde::Serialize;
+
+use crate::onionoo::{OnionooResponse, TorNode};
+
+// ---------------------------------------------------------------------------
+// Output format
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    Text,
+    Json,
+    Markdown,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "text"             => Ok(Self::Text),
+            "json"             => Ok(Self::Json),
+            "markdown" | "md"  => Ok(Self::Markdown),
+            other => anyhow::bail!("unknown format `{other}` (expected text, json or markdown)"),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Report model
+// ---------------------------------------------------------------------------
+
+/// Relay count and consensus-weight share for one bucket (a country, AS, …).
+#[derive(Debug, Serialize)]
+pub struct Share {
+    pub key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub relays: usize,
+    pub consensus_weight: u64,
+    pub weight_fraction: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Ipv6Adoption {
+    pub relays: usize,
+    pub fraction: f64,
+}
+
+/// Advertised bandwidth (bytes/s) of relays carrying the `Exit` flag.
+#[derive(Debug, Serialize)]
+pub struct ExitCapacity {
+    pub relays: usize,
+    pub advertised_bandwidth: u64,
+    pub fraction_of_total: f64,
+}
+
+/// Summed Onionoo selection probabilities — each should be close to 1.0.
+#[derive(Debug, Serialize)]
+pub struct Probabilities {
+    pub guard: f64,
+    pub middle: f64,
+    pub exit: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MissingGeo {
+    pub country: usize,
+    pub coordinates: usize,
+    #[serde(rename = "as")]
+    pub as_number: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub relays_published: Option<String>,
+    pub total_relays: usize,
+    pub total_consensus_weight: u64,
+    pub total_advertised_bandwidth: u64,
+    pub by_country: Vec<Share>,
+    pub by_as: Vec<Share>,
+    pub by_flag: Vec<Share>,
+    pub by_version: Vec<Share>,
+    pub by_platform: Vec<Share>,
+    pub ipv6: Ipv6Adoption,
+    pub exit_capacity: ExitCapacity,
+    pub probabilities: Probabilities,
+    pub missing_geo: MissingGeo,
+}
+
+// ---------------------------------------------------------------------------
+// Aggregation
+// ---------------------------------------------------------------------------
+
+/// Extract the operating system from an Onionoo platform string,
+/// e.g. `"Tor 0.4.8.10 on Linux"` → `"Linux"`.
+fn platform_os(platform: &str) -> &str {
+    platform.split_once(" on ").map_or(platform, |(_, os)| os).trim()
+}
+
+fn fraction(part: f64, total: f64) -> f64 {
+    if total > 0.0 { part / total } else { 0.0 }
+}
+
+/// Group relays by zero or more keys each and sort buckets by weight, then count.
+fn shares<'a, K>(nodes: &'a [TorNode], total_weight: u64, keys: K) -> Vec<Share>
+where
+    K: Fn(&'a TorNode) -> Vec<(String, Option<String>)>,
+{
+    let mut map: HashMap<String, (Option<String>, usize, u64)> = HashMap::new();
+    for node in nodes {
+        for (key, name) in keys(node) {
+            let entry = map.entry(key).or_insert((name, 0, 0));
+            entry.1 += 1;
+            entry.2 += node.consensus_weight;
+        }
+    }
+    let mut out: Vec<Share> = map
+        .into_iter()
+        .map(|(key, (name, relays, weight))| Share {
+            key,
+            name,
+            relays,
+            consensus_weight: weight,
+            weight_fraction: fraction(weight as f64, total_weight as f64),
+        })
+        .collect();
+    out.sort_by(|a, b| {
+        b.consensus_weight
+            .cmp(&a.consensus_weight)
+            .then(b.relays.cmp(&a.relays))
+            .then(a.key.cmp(&b.key))
+    });
+    out
+}
+
+/// `value`, or `unknown` if it is missing or empty — the one label every
+/// report uses for relays without a country, AS, version or platform.
+pub fn or_unknown(value: Option<&str>) -> String {
+    match value {
+        Some(v) if !v.is_empty() => v.to_string(),
+        _ => "unknown".to_string(),
+    }
+}
+
+impl Report {
+    pub fn build(response: &OnionooResponse) -> Self {
+        let nodes = &response.relays;
+        let total_weight: u64 = nodes.iter().map(|n| n.consensus_weight).sum();
+        let total_bw: u64 = nodes.iter().filter_map(|n| n.advertised_bandwidth).sum();
+
+        let by_country = shares(nodes, total_weight, |n| {
+            vec![(or_unknown(n.country.as_deref().map(str::to_uppercase).as_deref()), None)]
+        });
+        let by_as = shares(nodes, total_weight, |n| {
+            vec![(or_unknown(n.as_number.as_deref()), n.as_name.clone())]
+        });
+        let by_flag = shares(nodes, total_weight, |n| {
+            n.flags.iter().map(|f| (f.clone(), None)).collect()
+        });
+        let by_version = shares(nodes, total_weight, |n| {
+            vec![(or_unknown(n.version.as_deref()), None)]
+        });
+        let by_platform = shares(nodes, total_weight, |n| {
+            vec![(or_unknown(n.platform.as_deref().map(platform_os)), None)]
+        });
+
+        let ipv6_relays = nodes.iter().filter(|n| n.has_ipv6()).count();
+
+        let exits: Vec<&TorNode> = nodes.iter().filter(|n| n.has_flag("exit")).collect();
+        let exit_bw: u64 = exits.iter().filter_map(|n| n.advertised_bandwidth).sum();
+
+        let sum = |f: fn(&TorNode) -> Option<f64>| nodes.iter().filter_map(f).sum::<f64>();
+
+        Self {
+            relays_published: response.relays_published.clone(),
+            total_relays: nodes.len(),
+            total_consensus_weight: total_weight,
+            total_advertised_bandwidth: total_bw,
+            by_country,
+            by_as,
+            by_flag,
+            by_version,
+            by_platform,
+            ipv6: Ipv6Adoption {
+                relays: ipv6_relays,
+                fraction: fraction(ipv6_relays as f64, nodes.len() as f64),
+            },
+            exit_capacity: ExitCapacity {
+                relays: exits.len(),
+                advertised_bandwidth: exit_bw,
+                fraction_of_total: fraction(exit_bw as f64, total_bw as f64),
+            },
+            probabilities: Probabilities {
+                guard:  sum(|n| n.guard_probability),
+                middle: sum(|n| n.middle_probability),
+                exit:   sum(|n| n.exit_probability),
+            },
+            missing_geo: MissingGeo {
+                country:     nodes.iter().filter(|n| n.country.is_none()).count(),
+                coordinates: nodes.iter().filter(|n| n.latitude.is_none() || n.longitude.is_none()).count(),
+                as_number:   nodes.iter().filter(|n| n.as_number.is_none()).count(),
+            },
+        }
+    }
+
+    /// Render the report. `top` limits each breakdown table in the text and
+    /// Markdown formats; JSON always contains every bucket.
+    pub fn render(&self, format: Format, top: usize) -> anyhow::Result<String> {
+        match format {
+            Format::Json     => Ok(serde_json::to_string_pretty(self)? + "\n"),
+            Format::Text     => Ok(self.render_text(top)),
+            Format::Markdown => Ok(self.render_markdown(top)),
+        }
+    }
+
+    /// `(column title, section heading, rows)` for each breakdown table.
+    fn sections(&self) -> [(&'static str, &'static str, &[Share]); 5] {
+        [
+            ("Country",  "By country",  &self.by_country),
+            ("AS",       "By AS",       &self.by_as),
+            ("Flag",     "By flag",     &self.by_flag),
+            ("Version",  "By version",  &self.by_version),
+            ("Platform", "By platform", &self.by_platform),
+        ]
+    }
+
+    fn summary_lines(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("Published",         self.relays_published.clone().unwrap_or_else(|| "unknown".into())),
+            ("Relays",            self.total_relays.to_string()),
+            ("Consensus weight",  self.total_consensus_weight.to_string()),
+            ("Advertised bw",     format_bandwidth(self.total_advertised_bandwidth)),
+            ("IPv6 relays",       format!("{} ({:.1}%)", self.ipv6.relays, self.ipv6.fraction * 100.0)),
+            ("Exit relays",       self.exit_capacity.relays.to_string()),
+            ("Exit capacity",     format!(
+                "{} ({:.1}% of advertised)",
+                format_bandwidth(self.exit_capacity.advertised_bandwidth),
+                self.exit_capacity.fraction_of_total * 100.0,
+            )),
+            ("Guard prob. total",  format!("{:.4}", self.probabilities.guard)),
+            ("Middle prob. total", format!("{:.4}", self.probabilities.middle)),
+            ("Exit prob. total",   format!("{:.4}", self.probabilities.exit)),
+            ("Missing country",   self.missing_geo.country.to_string()),
+            ("Missing lat/lon",   self.missing_geo.coordinates.to_string()),
+            ("Missing AS",        self.missing_geo.as_number.to_string()),
+        ]
+    }
+
+    fn render_text(&self, top: usize) -> String {
+        let mut s = String::new();
+        s.push_str("Tor network statistics\n======================\n\n");
+        for (label, value) in self.summary_lines() {
+            let _ = writeln!(s, "{:<20}{value}", format!("{label}:"));
+        }
+        for (title, heading, rows) in self.sections() {
+            let _ = writeln!(s, "\n{heading}\n");
+            let _ = writeln!(s, "  {:<28}{:>8}{:>10}", title, "relays", "weight");
+            for row in rows.iter().take(top) {
+                let _ = writeln!(
+                    s,
+                    "  {:<28}{:>8}{:>9.2}%",
+                    share_label(row, 28),
+                    row.relays,
+                    row.weight_fraction * 100.0,
+                );
+            }
+            if rows.len() > top {
+                let _ = writeln!(s, "  … {} more", rows.len() - top);
+            }
+        }
+        s
+    }
+
+    fn render_markdown(&self, top: usize) -> String {
+        let mut s = String::new();
+        s.push_str("# Tor network statistics\n\n| Metric | Value |\n|--------|-------|\n");
+        for (label, value) in self.summary_lines() {
+            let _ = writeln!(s, "| {label} | {value} |");
+        }
+        for (title, heading, rows) in self.sections() {
+            let _ = writeln!(s, "\n## {heading}\n");
+            let _ = writeln!(s, "| {title} | Relays | Weight share |\n|---|---:|---:|");
+            for row in rows.iter().take(top) {
+                let label = share_label(row, usize::MAX).replace('|', "\\|");
+                let _ = writeln!(s, "| {label} | {} | {:.2}% |", row.relays, row.weight_fraction * 100.0);
+            }
+            if rows.len() > top {
+                let _ = writeln!(s, "\n_… {} more not shown._", rows.len() - top);
+            }
+        }
+        s
+    }
+}
+
+/// `key` or `key name`, truncated to `width` characters.
+fn share_label(row: &Share, width: usize) -> String {
+    let full = match &row.name {
+        Some(name) => format!("{} {name}", row.key),
+        None       => row.key.clone(),
+    };
+    if full.chars().count() <= width {
+        full
+    } else {
+        full.chars().take(width.saturating_sub(1)).chain(['…']).collect()
+    }
+}
+
+/// Human-readable bytes/s using decimal units, matching Onionoo's convention.
+pub fn format_bandwidth(bytes_per_sec: u64) -> String {
+    const UNITS: [&str; 5] = ["B/s", "KB/s", "MB/s", "GB/s", "TB/s"];
+    let mut value = bytes_per_sec as f64;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn response() -> OnionooResponse {
+        serde_json::from_value(json!({
+            "relays_published": "2024-05-01 12:00:00",
+            "relays": [
+                { "fingerprint": "A", "flags": ["Guard", "Running"], "country": "de", "as": "AS1",
+                  "as_name": "One", "consensus_weight": 600, "advertised_bandwidth": 3000,
+                  "platform": "Tor 0.4.8.10 on Linux", "or_addresses": ["1.2.3.4:9001", "[2001:db8::1]:9001"] },
+                { "fingerprint": "B", "flags": ["Exit", "Running"], "country": "DE", "as": "AS2",
+                  "consensus_weight": 300, "advertised_bandwidth": 1000, "platform": "Tor 0.4.8.10 on FreeBSD",
+                  "or_addresses": [] },
+                { "fingerprint": "C", "flags": ["Running"], "consensus_weight": 100, "or_addresses": [] },
+            ],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn buckets_are_sorted_by_weight_with_unknowns_kept() {
+        let report = Report::build(&response());
+        assert_eq!(report.total_relays, 3);
+        assert_eq!(report.total_consensus_weight, 1000);
+
+        let countries: Vec<_> = report.by_country.iter().map(|s| (s.key.as_str(), s.relays)).collect();
+        assert_eq!(countries, [("DE", 2), ("unknown", 1)]);
+        assert!((report.by_country[0].weight_fraction - 0.9).abs() < 1e-9);
+
+        let flags: Vec<_> = report.by_flag.iter().map(|s| s.key.as_str()).collect();
+        assert_eq!(flags, ["Running", "Guard", "Exit"]);
+        assert_eq!(report.by_as[0].name.as_deref(), Some("One"));
+
+        let platforms: Vec<_> = report.by_platform.iter().map(|s| s.key.as_str()).collect();
+        assert_eq!(platforms, ["Linux", "FreeBSD", "unknown"]);
+    }
+
+    #[test]
+    fn ipv6_exit_capacity_and_missing_geo() {
+        let report = Report::build(&response());
+        assert_eq!(report.ipv6.relays, 1);
+        assert_eq!(report.exit_capacity.relays, 1);
+        assert!((report.exit_capacity.fraction_of_total - 0.25).abs() < 1e-9);
+        assert_eq!(report.missing_geo.country, 1);
+        assert_eq!(report.missing_geo.coordinates, 3);
+        assert_eq!(report.missing_geo.as_number, 1);
+    }
+
+    #[test]
+    fn json_keeps_every_bucket_regardless_of_top() {
+        let json = Report::build(&response()).render(Format::Json, 1).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["by_country"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn formats_and_labels() {
+        assert!("MD".parse::<Format>().is_ok());
+        assert!("yaml".parse::<Format>().is_err());
+        assert_eq!(format_bandwidth(999), "999.0 B/s");
+        assert_eq!(format_bandwidth(1_500_000), "1.5 MB/s");
+        let row = Share { key: "AS1".into(), name: Some("A long name".into()), relays: 1, consensus_weight: 1, weight_fraction: 1.0 };
+        assert_eq!(share_label(&row, 20), "AS1 A long name");
+        assert_eq!(share_label(&row, 6), "AS1 A…");
+    }
+}
@@ -0,0 +1,186 @@
+//! summary.rs — machine-readable record of a run, for `--summary PATH`.
+//!
+//! The pieces of a run report into one process-wide [`Summary`] as they go:
+//! loading records the relay count and malformed addresses, exporting the
+//! files and their row counts, the map how relay positions were resolved,
+//! and each phase its wall-clock time. [`finish`] writes it as JSON (via a
+//! temporary file and a rename, so dashboards never read half a summary).
+//! `watch` finishes once per cycle, each cycle replacing the previous file.
+//! Without `--summary` nothing is collected.
+//!
+//! ```json
+//! {
+//!   "command": "export",
+//!   "status": "ok",
+//!   "started_at": 1792332000,
+//!   "relays_published": "2026-10-18 14:00:00",
+//!   "relays": 7123,
+//!   "malformed_addresses": 0,
+//!   "anomalies": [],
+//!   "positions": { "onionoo": 6890, "geolite2": 201, "unplaced": 32 },
+//!   "outputs": [ { "name": "all.csv", "category": "all", "format": "csv", "relays": 7123, "rows": 9950, "bytes": 712345 } ],
+//!   "timings_ms": { "fetch": 812, "render": 433, "commit": 12, "total": 1270 }
+//! }
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::validate::{Anomaly, ValidationError};
+
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    pub command: String,
+    /// `ok`, `rejected` (validation failed) or `error`.
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub started_at: u64,
+    pub relays_published: Option<String>,
+    pub relays: Option<usize>,
+    /// OR addresses that could not be parsed and were left out of every output.
+    pub malformed_addresses: usize,
+    /// Everything [`crate::validate::records`] found, including the above.
+    pub anomalies: Vec<Anomaly>,
+    /// Summed over every map rendered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub positions: Option<Positions>,
+    pub outputs: Vec<Output>,
+    pub timings_ms: BTreeMap<&'static str, u64>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct Positions {
+    pub onionoo: usize,
+    pub geolite2: usize,
+    pub unplaced: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Output {
+    /// Primary file name.
+    pub name: String,
+    pub category: String,
+    pub format: &'static str,
+    /// Relays selected by the output's filter.
+    pub relays: usize,
+    /// Relay/address rows, for the per-address formats.
+    pub rows: usize,
+    pub bytes: usize,
+}
+
+struct Run {
+    path: PathBuf,
+    started: Instant,
+    summary: Summary,
+}
+
+static RUN: Mutex<Option<Run>> = Mutex::new(None);
+
+fn now_unix() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn fresh(command: String) -> Summary {
+    Summary { command, started_at: now_unix(), ..Summary::default() }
+}
+
+/// Start collecting for `command`, to be written to `path` by [`finish`].
+pub fn begin(path: PathBuf, command: &str) {
+    let run = Run { path, started: Instant::now(), summary: fresh(command.to_string()) };
+    *RUN.lock().unwrap_or_else(|e| e.into_inner()) = Some(run);
+}
+
+/// Update the current summary; a no-op without `--summary`.
+pub fn record(f: impl FnOnce(&mut Summary)) {
+    if let Some(run) = RUN.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        f(&mut run.summary);
+    }
+}
+
+/// Run `f`, adding its duration to the `phase` timing.
+pub fn timed<T>(phase: &'static str, f: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let result = f();
+    let ms = started.elapsed().as_millis() as u64;
+    record(|s| *s.timings_ms.entry(phase).or_default() += ms);
+    result
+}
+
+/// Discard what was collected so far and start over, e.g. at the start of
+/// a `watch` cycle.
+pub fn restart() {
+    if let Some(run) = RUN.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        run.started = Instant::now();
+        run.summary = fresh(run.summary.command.clone());
+    }
+}
+
+/// Write the summary, ending with `result`.
+pub fn finish(result: &anyhow::Result<()>) {
+    let mut guard = RUN.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(run) = guard.as_mut() else { return };
+
+    let mut summary = std::mem::take(&mut run.summary);
+    run.summary.command = summary.command.clone();
+    summary.timings_ms.insert("total", run.started.elapsed().as_millis() as u64);
+    summary.status = match result {
+        Ok(())                              => "ok",
+        Err(e) if e.is::<ValidationError>() => "rejected",
+        Err(_)                              => "error",
+    };
+    summary.error = result.as_ref().err().map(|e| format!("{e:#}"));
+
+    let path = run.path.clone();
+    drop(guard);
+    if let Err(e) = write(&summary, &path) {
+        warn!("Could not write run summary to {}: {e:#}", path.display());
+    }
+}
+
+fn write(summary: &Summary, path: &Path) -> anyhow::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, serde_json::to_string_pretty(summary)? + "\n")?;
+    fs::rename(&tmp, path)?;
+    debug!("Wrote run summary to {}.", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_run_is_written_as_json_with_its_outcome() {
+        let path = std::env::temp_dir().join(format!("tor-node-parser-{}-summary.json", std::process::id()));
+        begin(path.clone(), "export");
+        record(|s| s.relays = Some(7));
+        timed("render", || ());
+        let rejected = ValidationError { problems: vec!["only 7 relays (minimum 3000)".into()] };
+        finish(&Err(rejected.into()));
+
+        let value: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(value["command"], "export");
+        assert_eq!(value["status"], "rejected");
+        assert!(value["error"].as_str().unwrap().contains("only 7 relays"));
+        assert_eq!(value["relays"], 7);
+        assert!(value["timings_ms"]["render"].is_u64() && value["timings_ms"]["total"].is_u64());
+
+        // The next cycle starts from scratch.
+        restart();
+        finish(&Ok(()));
+        let value: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(value["status"], "ok");
+        assert!(value["relays"].is_null() && value.get("error").is_none());
+        *RUN.lock().unwrap() = None;
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+//! theme.rs — colours and font of the maps.
+//!
+//! A theme is a flat TOML table: the canvas and globe, country fills and
+//! borders, one colour per kind of relay marker (and for the IPv6 ring),
+//! the legend's text colours, the stops of the sequential colour scale used
+//! by choropleth and density maps, and the font family. The built-in themes
+//! live in `themes/`:
+//!
+//! | Name | Look |
+//! |------|------|
+//! | `dark` | Navy ocean, blue land, bright markers (the default) |
+//! | `light` | Pale ocean and land, darker markers |
+//! | `print` | White paper, grey land, a blue scale that survives greyscale |
+//! | `high-contrast` | Black and white base, pure marker colours |
+//! | `okabe-ito` | `dark` with colour-blind-safe Okabe-Ito markers (also `colorblind`) |
+//!
+//! Anything else is read as a theme file. A file may name a built-in
+//! `base` (default `dark`) and set only the keys it changes.
+
+use std::fs;
+
+use serde::Deserialize;
+
+const BUILT_IN: [(&str, &str); 5] = [
+    ("dark",          include_str!("../themes/dark.toml")),
+    ("light",         include_str!("../themes/light.toml")),
+    ("print",         include_str!("../themes/print.toml")),
+    ("high-contrast", include_str!("../themes/high-contrast.toml")),
+    ("okabe-ito",     include_str!("../themes/okabe-ito.toml")),
+];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Theme {
+    /// Canvas behind everything, and the gaps between density cells.
+    pub background: String,
+    /// The globe, and the outline around markers.
+    pub ocean: String,
+    pub graticule: String,
+    pub land: String,
+    /// Country borders.
+    pub border: String,
+    /// Choropleth countries without a value.
+    pub no_data: String,
+    pub guard: String,
+    pub exit: String,
+    pub middle: String,
+    /// Markers for the optional map categories: relays that are both guard
+    /// and exit, BadExit and MiddleOnly relays and directory authorities.
+    pub guard_exit: String,
+    pub bad_exit: String,
+    pub middle_only: String,
+    pub authority: String,
+    /// Ring around relays with an IPv6 address.
+    pub ipv6: String,
+    /// Time-lapse markers for relays that just joined or left.
+    pub joined: String,
+    pub left: String,
+    /// Legend and caption text.
+    pub text: String,
+    /// Headings such as "Top countries" and inset labels.
+    pub heading: String,
+    /// Secondary text, inset frames and the time-lapse progress bar.
+    pub muted: String,
+    /// The totals line.
+    pub faint: String,
+    /// Sequential colour scale, low to high, as `#rrggbb` or `#rgb` stops.
+    pub ramp: Vec<String>,
+    /// Font family for every label; layout assumes a monospaced font.
+    pub font: String,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        load("dark").expect("built-in theme is valid")
+    }
+}
+
+/// A built-in theme by name, or a theme file.
+pub fn load(spec: &str) -> anyhow::Result<Theme> {
+    let name = if spec == "colorblind" { "okabe-ito" } else { spec };
+    let table = match BUILT_IN.iter().find(|(n, _)| *n == name) {
+        Some((_, text)) => parse_table(text, name, None)?,
+        None if spec.ends_with(".toml") || spec.contains('/') => {
+            let text = fs::read_to_string(spec).map_err(|e| anyhow::anyhow!("cannot read theme {spec}: {e}"))?;
+            parse_table(&text, spec, Some("dark"))?
+        }
+        None => anyhow::bail!(
+            "unknown theme `{spec}` (expected dark, light, print, high-contrast, okabe-ito or a .toml file)"
+        ),
+    };
+    let theme: Theme = toml::Value::Table(table)
+        .try_into()
+        .map_err(|e| anyhow::anyhow!("invalid theme {spec}: {e}"))?;
+    theme.check().map_err(|e| e.context(format!("invalid theme {spec}")))?;
+    Ok(theme)
+}
+
+/// The keys of one theme, merged over its `base` (or `default_base`).
+fn parse_table(text: &str, origin: &str, default_base: Option<&str>) -> anyhow::Result<toml::Table> {
+    let mut table: toml::Table = text.parse().map_err(|e| anyhow::anyhow!("invalid theme {origin}: {e}"))?;
+    let base = match table.remove("base") {
+        Some(base) => base.as_str().ok_or_else(|| anyhow::anyhow!("theme {origin}: `base` must be a theme name"))?.to_string(),
+        None       => match default_base {
+            Some(base) => base.to_string(),
+            None       => return Ok(table),
+        },
+    };
+    let base = base.as_str();
+    let (_, base_text) = BUILT_IN
+        .iter()
+        .find(|(n, _)| *n == base)
+        .ok_or_else(|| anyhow::anyhow!("theme {origin}: unknown base theme `{base}`"))?;
+    let mut merged = parse_table(base_text, base, None)?;
+    merged.extend(table);
+    Ok(merged)
+}
+
+impl Theme {
+    fn check(&self) -> anyhow::Result<()> {
+        let colors = [
+            ("background", &self.background), ("ocean", &self.ocean), ("graticule", &self.graticule),
+            ("land", &self.land), ("border", &self.border), ("no_data", &self.no_data),
+            ("guard", &self.guard), ("exit", &self.exit), ("middle", &self.middle),
+            ("guard_exit", &self.guard_exit), ("bad_exit", &self.bad_exit), ("middle_only", &self.middle_only),
+            ("authority", &self.authority), ("ipv6", &self.ipv6),
+            ("joined", &self.joined), ("left", &self.left), ("text", &self.text),
+            ("heading", &self.heading), ("muted", &self.muted), ("faint", &self.faint),
+        ];
+        for (key, color) in colors {
+            anyhow::ensure!(
+                rgb(color).is_some() || (!color.is_empty() && color.bytes().all(|b| b.is_ascii_alphabetic())),
+                "`{key}` = `{color}` is not a colour (#rgb, #rrggbb or a CSS colour name)"
+            );
+        }
+        anyhow::ensure!(self.ramp.len() >= 2, "`ramp` needs at least two colours");
+        for color in &self.ramp {
+            anyhow::ensure!(rgb(color).is_some(), "`ramp` colour `{color}` is not #rgb or #rrggbb");
+        }
+        anyhow::ensure!(
+            !self.font.is_empty() && !self.font.contains(['\'', '"', '<', '>', '&']),
+            "`font` must be a font family list without quotes"
+        );
+        Ok(())
+    }
+
+    /// Colour at `t` in 0..=1 along the [`ramp`](Theme::ramp).
+    pub fn ramp_color(&self, t: f64) -> String {
+        let stops: Vec<(u8, u8, u8)> = self.ramp.iter().filter_map(|c| rgb(c)).collect();
+        let x = t.clamp(0.0, 1.0) * (stops.len() - 1) as f64;
+        let i = (x.floor() as usize).min(stops.len() - 2);
+        let f = x - i as f64;
+        let (a, b) = (stops[i], stops[i + 1]);
+        let mix = |a: u8, b: u8| (f64::from(a) + (f64::from(b) - f64::from(a)) * f).round() as u8;
+        format!("#{:02x}{:02x}{:02x}", mix(a.0, b.0), mix(a.1, b.1), mix(a.2, b.2))
+    }
+}
+
+/// `#rrggbb` (or `#rgb`) as components.
+fn rgb(color: &str) -> Option<(u8, u8, u8)> {
+    let hex = color.strip_prefix('#')?;
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |s: &str| u8::from_str_radix(s, 16).ok();
+    match hex.len() {
+        6 => Some((channel(&hex[0..2])?, channel(&hex[2..4])?, channel(&hex[4..6])?)),
+        3 => {
+            let short = |i: usize| channel(&hex[i..=i]).map(|v| v * 17);
+            Some((short(0)?, short(1)?, short(2)?))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+
+    fn theme_file(test: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("tor-node-parser-{}-{test}.toml", std::process::id()));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn built_in_themes_are_valid() {
+        for (name, _) in BUILT_IN {
+            assert!(load(name).is_ok(), "{name}");
+        }
+        let okabe_ito = load("colorblind").unwrap();
+        assert_eq!(okabe_ito.ramp, load("dark").unwrap().ramp);
+        assert_ne!(okabe_ito.guard, load("dark").unwrap().guard);
+        assert!(load("solarized").is_err());
+    }
+
+    #[test]
+    fn files_override_their_base() {
+        let path = theme_file("theme-light", "base = \"light\"\nguard = \"#123\"\nfont = \"DejaVu Sans Mono, monospace\"\n");
+        let theme = load(path.to_str().unwrap()).unwrap();
+        let light = load("light").unwrap();
+        assert_eq!((theme.guard.as_str(), theme.font.as_str()), ("#123", "DejaVu Sans Mono, monospace"));
+        assert_eq!((theme.land, theme.ramp), (light.land, light.ramp));
+        fs::remove_file(path).unwrap();
+
+        // Without a base, the keys not given come from `dark`.
+        let path = theme_file("theme-dark", "exit = \"orange\"\n");
+        let theme = load(path.to_str().unwrap()).unwrap();
+        assert_eq!((theme.exit.as_str(), theme.land), ("orange", Theme::default().land));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalid_themes_are_rejected() {
+        let cases = [
+            ("theme-key", "guard_colour = \"#fff\"\n", "guard_colour"),
+            ("theme-base", "base = \"sepia\"\n", "unknown base theme `sepia`"),
+            ("theme-color", "exit = \"#ff00zz\"\n", "`exit`"),
+            ("theme-ramp", "ramp = [\"#000\"]\n", "at least two"),
+            ("theme-font", "font = \"'Fira Code'\"\n", "`font`"),
+        ];
+        for (test, text, expected) in cases {
+            let path = theme_file(test, text);
+            let err = load(path.to_str().unwrap()).err().unwrap();
+            assert!(format!("{err:#}").contains(expected), "{test}: {err:#}");
+            fs::remove_file(path).unwrap();
+        }
+        assert!(load("/nonexistent/theme.toml").is_err());
+    }
+
+    #[test]
+    fn ramp_colours_are_interpolated() {
+        assert_eq!(rgb("#0af"), Some((0x00, 0xaa, 0xff)));
+        assert_eq!(rgb("#12345"), None);
+        let theme = Theme { ramp: vec!["#000000".into(), "#ff0000".into(), "#ffffff".into()], ..Theme::default() };
+        assert_eq!(theme.ramp_color(-1.0), "#000000");
+        assert_eq!(theme.ramp_color(0.25), "#800000");
+        assert_eq!(theme.ramp_color(0.5), "#ff0000");
+        assert_eq!(theme.ramp_color(1.0), "#ffffff");
+    }
+}
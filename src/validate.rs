@@ -0,0 +1,416 @@
+//! validate.rs — sanity checks run before any output is overwritten.
+//!
+//! A stale, truncated or otherwise suspicious Onionoo document must never
+//! replace good data on disk. [`check`] collects every problem it finds into
+//! a [`ValidationError`]; callers keep the existing files and the binary
+//! exits with [`EXIT_VALIDATION`] so schedulers can tell this apart from an
+//! ordinary failure.
+//!
+//! [`records`] looks at individual relays instead: malformed fingerprints
+//! and OR addresses, out-of-range ports and duplicates. Every load reports
+//! what it finds through [`report_records`], once per published document;
+//! bad addresses are left out of the outputs as before, and in [strict
+//! mode](set_strict) any anomaly rejects the document.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::{
+    config::{Config, Format},
+    csv, export,
+    onionoo::{parse_or_address, OnionooResponse, TorNode},
+    output::{self, Vars},
+    summary,
+};
+
+/// Process exit status when validation rejects a document.
+pub const EXIT_VALIDATION: i32 = 3;
+
+/// Onionoo protocol major versions whose `details` layout this tool reads.
+const SUPPORTED_MAJOR_VERSIONS: std::ops::RangeInclusive<u32> = 7..=9;
+
+pub struct Thresholds {
+    /// Reject documents whose `relays_published` is older than this.
+    pub max_age: Duration,
+    pub min_relays: usize,
+    pub min_guards: usize,
+    pub min_exits: usize,
+    /// Largest tolerated relative drop in rows per CSV versus the files
+    /// currently on disk, e.g. `0.25` for 25 %.
+    pub max_drop: f64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            max_age:    Duration::from_secs(6 * 60 * 60),
+            min_relays: 3_000,
+            min_guards: 1_000,
+            min_exits:  500,
+            max_drop:   0.25,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ValidationError {
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Onionoo document rejected, existing outputs kept: {}", self.problems.join("; "))
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Validate `response` against `thresholds`, comparing the row count of
+/// every CSV output in `config` with the file `out` currently publishes
+/// under the same name. Templates with `{date}` or `{published}` name fresh
+/// files each time, so the drop check only applies once such a file exists.
+pub fn check(
+    response: &OnionooResponse,
+    thresholds: &Thresholds,
+    config: &Config,
+    out: &output::Options,
+) -> Result<(), ValidationError> {
+    let mut problems = Vec::new();
+
+    match response.version.as_deref() {
+        None => problems.push("document has no `version` field".to_string()),
+        Some(v) => {
+            let major = v.split('.').next().and_then(|m| m.parse::<u32>().ok());
+            if !major.is_some_and(|m| SUPPORTED_MAJOR_VERSIONS.contains(&m)) {
+                problems.push(format!(
+                    "unsupported Onionoo version {v} (expected {}.x–{}.x)",
+                    SUPPORTED_MAJOR_VERSIONS.start(),
+                    SUPPORTED_MAJOR_VERSIONS.end(),
+                ));
+            }
+        }
+    }
+
+    match response.published_at() {
+        None => problems.push("missing or unparsable `relays_published`".to_string()),
+        Some(published) => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            let age = now.saturating_sub(published);
+            if age > thresholds.max_age.as_secs() {
+                problems.push(format!(
+                    "relays_published {} is {:.1} h old (limit {:.1} h)",
+                    response.relays_published.as_deref().unwrap_or_default(),
+                    age as f64 / 3_600.0,
+                    thresholds.max_age.as_secs_f64() / 3_600.0,
+                ));
+            }
+        }
+    }
+
+    let relays = &response.relays;
+    let guards = relays.iter().filter(|r| r.is_guard()).count();
+    let exits  = relays.iter().filter(|r| r.is_exit()).count();
+    for (label, count, min) in [
+        ("relays", relays.len(), thresholds.min_relays),
+        ("guards", guards,       thresholds.min_guards),
+        ("exits",  exits,        thresholds.min_exits),
+    ] {
+        if count < min {
+            problems.push(format!("only {count} {label} (minimum {min})"));
+        }
+    }
+
+    let vars = Vars::new(response);
+    let published = out.published_dir();
+    for spec in config.outputs.iter().filter(|o| o.format == Format::Csv) {
+        let path = export::names(spec, out, &vars).swap_remove(0);
+        let Some(previous) = csv::existing_rows(&published.join(&path)).filter(|&p| p > 0) else { continue };
+        let new = spec.rows(relays).len();
+        let drop = 1.0 - new as f64 / previous as f64;
+        if drop > thresholds.max_drop {
+            problems.push(format!(
+                "{path} would shrink from {previous} to {new} rows ({:.0}% drop, limit {:.0}%)",
+                drop * 100.0,
+                thresholds.max_drop * 100.0,
+            ));
+        }
+    }
+
+    if problems.is_empty() { Ok(()) } else { Err(ValidationError { problems }) }
+}
+
+// ---------------------------------------------------------------------------
+// Relay records
+// ---------------------------------------------------------------------------
+
+/// Anomalies logged one by one; the rest only with `-v`.
+const REPORTED_ANOMALIES: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyKind {
+    /// Not 40 uppercase hex digits.
+    Fingerprint,
+    /// An OR address that isn't `ip:port` or `[ipv6]:port`.
+    Address,
+    /// A port outside 1–65535.
+    Port,
+    DuplicateFingerprint,
+    /// The same `ip:port` listed twice, by one relay or by two.
+    DuplicateAddress,
+}
+
+impl AnomalyKind {
+    /// Whether the affected address is left out of every output.
+    pub fn drops_address(self) -> bool {
+        matches!(self, AnomalyKind::Address | AnomalyKind::Port)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Anomaly {
+    pub kind: AnomalyKind,
+    /// The offending relay, as given in the document.
+    pub fingerprint: String,
+    pub detail: String,
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "relay {}: {}", self.fingerprint, self.detail)
+    }
+}
+
+fn is_fingerprint(s: &str) -> bool {
+    s.len() == 40 && s.bytes().all(|b| b.is_ascii_digit() || (b'A'..=b'F').contains(&b))
+}
+
+/// Why `addr` was rejected by [`parse_or_address`].
+fn address_problem(addr: &str) -> (AnomalyKind, String) {
+    let port = addr.rsplit_once(':').and_then(|(_, port)| port.parse::<u64>().ok());
+    match port {
+        Some(port) if port == 0 || port > u64::from(u16::MAX) => {
+            (AnomalyKind::Port, format!("port {port} out of range in OR address `{addr}`"))
+        }
+        _ => (AnomalyKind::Address, format!("malformed OR address `{addr}`")),
+    }
+}
+
+/// Every anomaly in the document's relay records, in document order.
+pub fn records(response: &OnionooResponse) -> Vec<Anomaly> {
+    let mut anomalies = Vec::new();
+    let mut fingerprints: HashMap<&str, usize> = HashMap::new();
+    let mut addresses: HashMap<(IpAddr, u16), &str> = HashMap::new();
+    let mut push = |node: &TorNode, kind, detail| {
+        anomalies.push(Anomaly { kind, fingerprint: node.fingerprint.clone(), detail });
+    };
+
+    for node in &response.relays {
+        if !is_fingerprint(&node.fingerprint) {
+            push(node, AnomalyKind::Fingerprint, format!("fingerprint `{}` is not 40 uppercase hex digits", node.fingerprint));
+        }
+        let seen = fingerprints.entry(&node.fingerprint).or_default();
+        *seen += 1;
+        if *seen == 2 {
+            push(node, AnomalyKind::DuplicateFingerprint, "fingerprint listed more than once".to_string());
+        }
+
+        for addr in &node.or_addresses {
+            let Some((ip, port)) = parse_or_address(addr) else {
+                let (kind, detail) = address_problem(addr);
+                push(node, kind, detail);
+                continue;
+            };
+            match addresses.insert((ip.to_canonical(), port), &node.fingerprint) {
+                Some(other) if other == node.fingerprint => {
+                    push(node, AnomalyKind::DuplicateAddress, format!("OR address `{addr}` listed twice"));
+                }
+                Some(other) => {
+                    push(node, AnomalyKind::DuplicateAddress, format!("OR address `{addr}` also used by relay {other}"));
+                }
+                None => {}
+            }
+        }
+    }
+    anomalies
+}
+
+/// Reject documents with any relay record anomaly (`--strict`).
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+/// `relays_published` of the last document whose anomalies were logged.
+static LAST_REPORTED: Mutex<Option<String>> = Mutex::new(None);
+
+/// Make [`report_records`] reject documents with anomalies, for the rest of
+/// the process.
+pub fn set_strict(strict: bool) {
+    STRICT.store(strict, Ordering::Relaxed);
+}
+
+pub fn strict() -> bool {
+    STRICT.load(Ordering::Relaxed)
+}
+
+/// Log the anomalies in `response` and add them to the run summary; in
+/// strict mode, reject the document if there are any.
+///
+/// `watch` and `serve` load the same document every cycle until Onionoo
+/// publishes a new one, so a document's anomalies are logged one by one
+/// only the first time; after that a single line counts them.
+pub fn report_records(response: &OnionooResponse) -> Result<Vec<Anomaly>, ValidationError> {
+    let anomalies = records(response);
+    let published = response.relays_published.clone();
+    let repeated = published.is_some() && {
+        let mut last = LAST_REPORTED.lock().unwrap_or_else(|e| e.into_inner());
+        std::mem::replace(&mut *last, published.clone()) == published
+    };
+    if repeated {
+        if !anomalies.is_empty() {
+            debug!(
+                "{} relay record anomalies, as reported before for {}.",
+                anomalies.len(),
+                published.as_deref().unwrap_or_default(),
+            );
+        }
+    } else {
+        for (i, anomaly) in anomalies.iter().enumerate() {
+            if i < REPORTED_ANOMALIES {
+                warn!("{anomaly}");
+            } else {
+                debug!("{anomaly}");
+            }
+        }
+        if anomalies.len() > REPORTED_ANOMALIES && !crate::log::enabled(crate::log::Level::Debug) {
+            warn!("… and {} more relay record anomalies (see -v).", anomalies.len() - REPORTED_ANOMALIES);
+        }
+    }
+    summary::record(|s| {
+        s.malformed_addresses = anomalies.iter().filter(|a| a.kind.drops_address()).count();
+        s.anomalies = anomalies.clone();
+    });
+    if strict() && !anomalies.is_empty() {
+        return Err(ValidationError {
+            problems: vec![format!("{} malformed relay records (strict mode)", anomalies.len())],
+        });
+    }
+    Ok(anomalies)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::onionoo::format_utc_timestamp;
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    /// `guards` guards and `exits` exits, published `age` seconds ago.
+    fn document(guards: usize, exits: usize, age: u64) -> OnionooResponse {
+        let relays: Vec<_> = (0..guards + exits)
+            .map(|i| json!({
+                "fingerprint": format!("{i:040X}"),
+                "flags": [if i < guards { "Guard" } else { "Exit" }],
+                "or_addresses": [format!("10.0.{}.{}:9001", i / 256, i % 256)],
+            }))
+            .collect();
+        serde_json::from_value(json!({
+            "version": "8.0",
+            "relays_published": format_utc_timestamp(now() - age),
+            "relays": relays,
+        }))
+        .unwrap()
+    }
+
+    fn thresholds() -> Thresholds {
+        Thresholds { min_relays: 4, min_guards: 2, min_exits: 2, ..Default::default() }
+    }
+
+    fn problems(response: &OnionooResponse, config: &Config, out: &output::Options) -> Vec<String> {
+        check(response, &thresholds(), config, out).err().map_or_else(Vec::new, |e| e.problems)
+    }
+
+    fn elsewhere() -> output::Options {
+        output::Options { dir: PathBuf::from("/nonexistent"), ..Default::default() }
+    }
+
+    #[test]
+    fn a_fresh_complete_document_passes() {
+        assert!(problems(&document(2, 2, 60), &Config::default(), &elsewhere()).is_empty());
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let mut response = document(1, 1, 7 * 3_600);
+        response.version = Some("5.2".to_string());
+        let found = problems(&response, &Config::default(), &elsewhere());
+        assert_eq!(found.len(), 5, "{found:?}");
+        assert!(found[0].starts_with("unsupported Onionoo version 5.2"));
+        assert!(found[1].contains("h old (limit 6.0 h)"));
+        assert_eq!(found[2..], ["only 2 relays (minimum 4)", "only 1 guards (minimum 2)", "only 1 exits (minimum 2)"]);
+
+        response.version = None;
+        response.relays_published = Some("yesterday".to_string());
+        let found = problems(&response, &Config::default(), &elsewhere());
+        assert!(found.contains(&"document has no `version` field".to_string()));
+        assert!(found.contains(&"missing or unparsable `relays_published`".to_string()));
+    }
+
+    #[test]
+    fn csv_rows_may_not_drop_too_far() {
+        let dir = std::env::temp_dir().join(format!("tor-node-parser-{}-drop", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let out = output::Options { dir: dir.clone(), ..Default::default() };
+        let config: Config = "[[output]]\ncategory = \"all\"\nformat = \"csv\"".parse().unwrap();
+
+        // No file yet, or an empty one: nothing to compare with.
+        assert!(problems(&document(2, 2, 60), &config, &out).is_empty());
+        fs::write(dir.join("all.csv"), "fingerprint,ipaddr,port\n").unwrap();
+        assert!(problems(&document(2, 2, 60), &config, &out).is_empty());
+
+        fs::write(dir.join("all.csv"), "fingerprint,ipaddr,port\n".to_string() + &"A,1.2.3.4,1\n".repeat(5)).unwrap();
+        assert!(problems(&document(2, 2, 60), &config, &out).is_empty(), "4 of 5 rows is a 20% drop");
+        let found = problems(&document(2, 1, 60), &config, &out);
+        assert_eq!(found[2], "all.csv would shrink from 5 to 3 rows (40% drop, limit 25%)");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn malformed_records_are_found_in_document_order() {
+        let fingerprint = "A".repeat(40);
+        let response: OnionooResponse = serde_json::from_value(json!({
+            "relays": [
+                { "fingerprint": fingerprint, "or_addresses": ["1.2.3.4:9001", "[::ffff:1.2.3.4]:9001", "1.2.3.4:0"] },
+                { "fingerprint": "abc", "or_addresses": ["1.2.3.4:9001", "localhost:9001", "[2001:db8::1]:70000"] },
+                { "fingerprint": fingerprint },
+            ],
+        }))
+        .unwrap();
+        let found: Vec<_> = records(&response).iter().map(|a| (a.kind, a.detail.clone())).collect();
+        assert_eq!(found, [
+            (AnomalyKind::DuplicateAddress, "OR address `[::ffff:1.2.3.4]:9001` listed twice".to_string()),
+            (AnomalyKind::Port, "port 0 out of range in OR address `1.2.3.4:0`".to_string()),
+            (AnomalyKind::Fingerprint, "fingerprint `abc` is not 40 uppercase hex digits".to_string()),
+            (AnomalyKind::DuplicateAddress, format!("OR address `1.2.3.4:9001` also used by relay {fingerprint}")),
+            (AnomalyKind::Address, "malformed OR address `localhost:9001`".to_string()),
+            (AnomalyKind::Port, "port 70000 out of range in OR address `[2001:db8::1]:70000`".to_string()),
+            (AnomalyKind::DuplicateFingerprint, "fingerprint listed more than once".to_string()),
+        ]);
+        assert!(records(&document(2, 2, 0)).is_empty());
+    }
+}
@@ -0,0 +1,1954 @@
+//! world_map.rs — render relay positions as a self-contained SVG world map.
+//!
+//! Country polygons are embedded at compile time from assets/world.geojson
+//! (Natural Earth 110m, downloaded once by build.rs).
+//!
+//! Projection: equirectangular / plate carrée by default; any of
+//! [`crate::projection`]'s, fitted into the same canvas.
+//!
+//! Extent: the whole world by default, or a [`crate::region`] blown up to
+//! fill the canvas, with polygons clipped to it and relays outside it left
+//! out. Up to two more regions can be drawn as insets in the lower left of
+//! the map (over the South Pacific on a world map), each marked by a
+//! dashed box where it lies on the main map.
+//!
+//! Choropleth mode shades each country instead, by its number of relays,
+//! guards or exits or its share of the total consensus weight, on a log
+//! colour scale with its own legend. Onionoo's `country` codes are matched
+//! to the GeoJSON's ISO 3166-1 alpha-2 properties.
+//!
+//! Density mode bins relays into hexagons or squares of a fixed size on the
+//! canvas instead, each coloured by its relay count or summed advertised
+//! bandwidth on the same kind of scale: a few thousand cells rather than a
+//! circle per relay, and dense areas stay readable.
+//!
+//! Dots can also be sized by advertised bandwidth, consensus weight or exit
+//! probability, their area proportional to the value, with a size legend,
+//! so the map shows where capacity sits rather than only where relays are.
+//!
+//! The HTML output wraps the same SVG in a page with hover tooltips
+//! (nickname, fingerprint, flags, AS, bandwidth), click-to-copy
+//! fingerprints, search by nickname, fingerprint or IP and a toggle for
+//! each legend entry, all inline.
+//!
+//! Colours and the font come from a [`crate::theme`]. Dot colours in the
+//! default dark theme:
+//!   purple (#c084fc) — guard
+//!   red    (#f87171) — exit
+//!   yellow (#fde047) — middle
+//!
+//! A dot map can also set apart the [`Category`]s it is given, each by its
+//! own colour (guard+exit, BadExit, MiddleOnly, authority), shape
+//! (authorities as diamonds, HSDirs as squares) or mark (a ring for IPv6,
+//! faded for a GeoLite2 position); the legend lists those drawn.
+//!
+//! Latitude/longitude resolution order:
+//!   1. Onionoo `latitude` / `longitude` fields (present for most relays)
+//!   2. MaxMind GeoLite2-City lookup on the relay's first OR-address IP
+//!      (fallback for relays where Onionoo returns null coordinates)
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    geo,
+    onionoo::{OnionooResponse, TorNode},
+    projection::{self, Bounds, Equirectangular, Projection},
+    region::{self, Area, BBox},
+    stats, summary,
+    theme::Theme,
+};
+
+// Embedded at compile time — no runtime fetch needed.
+const WORLD_GEOJSON: &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/world.geojson"));
+const MAP_HTML: &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/templates/map.html"));
+
+const W: f64 = 1200.0;
+const H: f64 = 600.0;
+const R_MIDDLE:    f64 = 3.0;
+const R_NOTABLE:   f64 = 4.0;
+const R_AUTHORITY: f64 = 5.5;
+/// Radius range for markers scaled by [`MarkerSize`].
+const R_MIN: f64 = 1.5;
+const R_MAX: f64 = 12.0;
+
+/// Largest inset, and the most that fit above the legend.
+const INSET_W: f64 = 320.0;
+const INSET_H: f64 = 200.0;
+pub const MAX_INSETS: usize = 2;
+
+/// How to draw the map.
+pub struct Options {
+    pub projection: Box<dyn Projection>,
+    /// Show only this region instead of the world.
+    pub region: Option<Area>,
+    /// Regions drawn again, enlarged, in the corner of the map.
+    pub insets: Vec<Area>,
+    /// Shade countries by this instead of plotting relays.
+    pub choropleth: Option<Choropleth>,
+    /// Bin relays into cells instead of drawing a dot each.
+    pub density: Option<Density>,
+    /// Scale dot area by this instead of drawing guards and exits a bit
+    /// larger than middles.
+    pub marker_size: Option<MarkerSize>,
+    /// Relays set apart on a dot map by colour, shape or mark.
+    pub categories: Vec<Category>,
+    pub theme: Theme,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            projection: Box::new(Equirectangular),
+            region:     None,
+            insets:     Vec::new(),
+            choropleth: None,
+            density:    None,
+            marker_size: None,
+            categories: DEFAULT_CATEGORIES.to_vec(),
+            theme:      Theme::default(),
+        }
+    }
+}
+
+impl Options {
+    /// `projection` is a [`projection::parse`] spec.
+    pub fn new(projection: Option<&str>) -> anyhow::Result<Self> {
+        Ok(Self {
+            projection: projection.map_or_else(|| Ok(Box::new(Equirectangular) as _), projection::parse)?,
+            ..Self::default()
+        })
+    }
+}
+
+/// Parse the embedded Natural Earth country polygons.
+pub fn world_geojson() -> anyhow::Result<Value> {
+    Ok(serde_json::from_str(WORLD_GEOJSON)?)
+}
+
+// ---------------------------------------------------------------------------
+// Relay styling and position
+// ---------------------------------------------------------------------------
+
+/// A kind of relay a dot map can set apart, besides guards, exits and
+/// middles, which it always does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    /// Relays with both the Guard and Exit flags, otherwise drawn as guards.
+    GuardExit,
+    /// Relays flagged BadExit, otherwise drawn as whatever else they are.
+    BadExit,
+    /// Relays flagged MiddleOnly.
+    MiddleOnly,
+    /// Directory authorities, as larger diamonds.
+    Authority,
+    /// Hidden-service directories, as squares.
+    HsDir,
+    /// Relays with an IPv6 OR address, ringed.
+    Ipv6,
+    /// Relays placed by the GeoLite2 fallback rather than Onionoo, faded.
+    Geolite2,
+}
+
+/// What a map sets apart unless told otherwise.
+pub const DEFAULT_CATEGORIES: [Category; 2] = [Category::GuardExit, Category::BadExit];
+
+const ALL_CATEGORIES: [Category; 7] = [
+    Category::GuardExit, Category::BadExit, Category::MiddleOnly, Category::Authority,
+    Category::HsDir, Category::Ipv6, Category::Geolite2,
+];
+
+impl FromStr for Category {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "guard-exit"  => Ok(Category::GuardExit),
+            "bad-exit"    => Ok(Category::BadExit),
+            "middle-only" => Ok(Category::MiddleOnly),
+            "authority"   => Ok(Category::Authority),
+            "hsdir"       => Ok(Category::HsDir),
+            "ipv6"        => Ok(Category::Ipv6),
+            "geolite2"    => Ok(Category::Geolite2),
+            other => anyhow::bail!(
+                "unknown map category `{other}` (expected guard-exit, bad-exit, middle-only, authority, hsdir, ipv6, geolite2 or all)"
+            ),
+        }
+    }
+}
+
+/// Categories by name, `all` standing for every one.
+pub fn parse_categories<S: AsRef<str>>(names: &[S]) -> anyhow::Result<Vec<Category>> {
+    let mut categories = Vec::new();
+    for name in names {
+        match name.as_ref().trim() {
+            "all" => categories.extend(ALL_CATEGORIES),
+            name  => categories.push(name.parse()?),
+        }
+    }
+    Ok(categories)
+}
+
+/// What a marker's colour says about a relay, in drawing order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Role {
+    Middle,
+    MiddleOnly,
+    Guard,
+    Exit,
+    GuardExit,
+    BadExit,
+    Authority,
+}
+
+impl Role {
+    fn color(self, theme: &Theme) -> &str {
+        match self {
+            Role::Middle     => &theme.middle,
+            Role::MiddleOnly => &theme.middle_only,
+            Role::Guard      => &theme.guard,
+            Role::Exit       => &theme.exit,
+            Role::GuardExit  => &theme.guard_exit,
+            Role::BadExit    => &theme.bad_exit,
+            Role::Authority  => &theme.authority,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Role::Middle     => "Middle",
+            Role::MiddleOnly => "MiddleOnly",
+            Role::Guard      => "Guard",
+            Role::Exit       => "Exit",
+            Role::GuardExit  => "Guard+Exit",
+            Role::BadExit    => "BadExit",
+            Role::Authority  => "Authority",
+        }
+    }
+
+    /// Class of its dots on the HTML map, for the layer toggles.
+    fn class(self) -> &'static str {
+        match self {
+            Role::Middle     => "k-middle",
+            Role::MiddleOnly => "k-middle-only",
+            Role::Guard      => "k-guard",
+            Role::Exit       => "k-exit",
+            Role::GuardExit  => "k-guard-exit",
+            Role::BadExit    => "k-bad-exit",
+            Role::Authority  => "k-authority",
+        }
+    }
+
+    /// Drawn larger than middles, and on top of them.
+    fn notable(self) -> bool {
+        self >= Role::Guard
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Shape {
+    Circle,
+    Square,
+    Diamond,
+}
+
+/// How a dot map draws one relay.
+#[derive(Debug, Clone, Copy)]
+struct Marker {
+    role: Role,
+    shape: Shape,
+    /// Ringed: the relay has an IPv6 OR address.
+    ipv6: bool,
+    /// Faded: the position is GeoLite2's guess from the IP.
+    approximate: bool,
+}
+
+impl Marker {
+    fn radius(&self) -> f64 {
+        match self.role {
+            Role::Authority        => R_AUTHORITY,
+            role if role.notable() => R_NOTABLE,
+            _                      => R_MIDDLE,
+        }
+    }
+
+    /// Classes of the layers it belongs to on the HTML map: its role's and
+    /// one per mark.
+    fn classes(&self) -> String {
+        let mut classes = self.role.class().to_string();
+        for (on, class) in [(self.shape == Shape::Square, "k-hsdir"), (self.ipv6, "k-ipv6"), (self.approximate, "k-geolite2")] {
+            if on {
+                classes.push(' ');
+                classes.push_str(class);
+            }
+        }
+        classes
+    }
+
+    /// Fill and outline attributes: `fill`, ringed in the theme's `ipv6`
+    /// colour or else outlined by `stroke` (empty to inherit the group's),
+    /// and faded for an approximate position.
+    fn paint(&self, theme: &Theme, fill: &str, stroke: &str) -> String {
+        let ring = if self.ipv6 { format!(" stroke='{}' stroke-width='1.2'", theme.ipv6) } else { stroke.to_string() };
+        let fade = if self.approximate { " fill-opacity='0.4'" } else { "" };
+        format!("fill='{fill}'{ring}{fade}")
+    }
+}
+
+impl TorNode {
+    /// This relay's marker with `categories` set apart.
+    fn marker(&self, categories: &[Category]) -> Marker {
+        let on = |c: Category| categories.contains(&c);
+        let role = if on(Category::Authority) && self.has_flag("Authority") {
+            Role::Authority
+        } else if on(Category::BadExit) && self.has_flag("BadExit") {
+            Role::BadExit
+        } else {
+            match (self.is_guard(), self.is_exit()) {
+                (true, true) if on(Category::GuardExit)                       => Role::GuardExit,
+                (true, _)                                                     => Role::Guard,
+                (false, true)                                                 => Role::Exit,
+                _ if on(Category::MiddleOnly) && self.has_flag("MiddleOnly")  => Role::MiddleOnly,
+                _                                                             => Role::Middle,
+            }
+        };
+        let shape = match role {
+            Role::Authority                                    => Shape::Diamond,
+            _ if on(Category::HsDir) && self.has_flag("HSDir") => Shape::Square,
+            _                                                  => Shape::Circle,
+        };
+        Marker {
+            role,
+            shape,
+            ipv6:        on(Category::Ipv6) && self.has_ipv6(),
+            approximate: on(Category::Geolite2) && !self.has_onionoo_position(),
+        }
+    }
+
+    /// Whether Onionoo gave both coordinates, so that
+    /// [`resolve_position`](Self::resolve_position) needs no GeoLite2 lookup.
+    fn has_onionoo_position(&self) -> bool {
+        self.latitude.is_some() && self.longitude.is_some()
+    }
+
+    /// Resolve (latitude, longitude) for this relay.
+    ///
+    /// Tries Onionoo fields first; falls back to a MaxMind GeoLite2-City
+    /// lookup when those are absent.
+    pub fn resolve_position(&self) -> Option<(f64, f64)> {
+        // 1. Onionoo native fields.
+        if let (Some(lat), Some(lon)) = (self.latitude, self.longitude) {
+            return Some((lat, lon));
+        }
+        // 2. MaxMind GeoLite2-City fallback.
+        let ip = self.primary_ip()?;
+        geo::lookup(ip)
+    }
+}
+
+/// A relay and its (latitude, longitude).
+type Placed<'a> = (&'a TorNode, (f64, f64));
+
+// ---------------------------------------------------------------------------
+// Projection onto the canvas
+// ---------------------------------------------------------------------------
+
+/// A rectangle on the canvas.
+#[derive(Debug, Clone, Copy)]
+struct Rect {
+    x: f64,
+    y: f64,
+    w: f64,
+    h: f64,
+}
+
+impl Rect {
+    const CANVAS: Rect = Rect { x: 0.0, y: 0.0, w: W, h: H };
+
+    fn contains(&self, (x, y): (f64, f64)) -> bool {
+        (self.x..=self.x + self.w).contains(&x) && (self.y..=self.y + self.h).contains(&y)
+    }
+
+    fn grow(&self, margin: f64) -> Rect {
+        Rect { x: self.x - margin, y: self.y - margin, w: self.w + 2.0 * margin, h: self.h + 2.0 * margin }
+    }
+}
+
+/// Extent of `area` on the projection's plane, from a grid of points over
+/// it; the whole projection without an area (or if none of it is shown).
+fn area_bounds(projection: &dyn Projection, area: Option<&BBox>) -> Bounds {
+    let Some(a) = area else { return projection.bounds() };
+    let steps = 32;
+    let mut bounds: Option<Bounds> = None;
+    for i in 0..=steps {
+        for j in 0..=steps {
+            let lon = a.west + (a.east - a.west) * f64::from(i) / f64::from(steps);
+            let lat = a.south + (a.north - a.south) * f64::from(j) / f64::from(steps);
+            let Some((x, y)) = projection.forward(lon, lat) else { continue };
+            let b = bounds.get_or_insert(Bounds { x_min: x, x_max: x, y_min: y, y_max: y });
+            b.x_min = b.x_min.min(x);
+            b.x_max = b.x_max.max(x);
+            b.y_min = b.y_min.min(y);
+            b.y_max = b.y_max.max(y);
+        }
+    }
+    bounds.unwrap_or_else(|| projection.bounds())
+}
+
+/// A projection scaled and centred to fit a rectangle of the canvas.
+struct Frame<'a> {
+    projection: &'a dyn Projection,
+    scale: f64,
+    x0: f64,
+    y0: f64,
+    view: Rect,
+    /// Roughly the longitudes and latitudes in view, for the graticule.
+    extent: BBox,
+    /// Degrees between graticule lines.
+    step: f64,
+}
+
+impl<'a> Frame<'a> {
+    /// `area` (or the whole projection) fitted into `view`.
+    fn new(projection: &'a dyn Projection, area: Option<&BBox>, view: Rect) -> Self {
+        let b = area_bounds(projection, area);
+        let scale = (view.w / (b.x_max - b.x_min)).min(view.h / (b.y_max - b.y_min));
+        let (south, north) = projection.lat_range();
+        let world = BBox { south, north, ..BBox::WORLD };
+        let (extent, step) = match area {
+            // Generous, as the view is wider or taller than the area.
+            Some(a) => {
+                let step = [30.0, 10.0, 5.0, 2.0].into_iter().find(|s| a.span() / s >= 3.0).unwrap_or(1.0);
+                (a.grow(a.span()), step)
+            }
+            None => (world, 30.0),
+        };
+        Self {
+            projection,
+            scale,
+            x0: view.x + (view.w - (b.x_max - b.x_min) * scale) / 2.0 - b.x_min * scale,
+            y0: view.y + (view.h - (b.y_max - b.y_min) * scale) / 2.0 + b.y_max * scale,
+            view,
+            extent: BBox { south: extent.south.max(south), north: extent.north.min(north), ..extent },
+            step,
+        }
+    }
+
+    fn to_canvas(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        (self.x0 + x * self.scale, self.y0 - y * self.scale)
+    }
+
+    /// Canvas position of a point, if the projection shows it.
+    fn point(&self, lon: f64, lat: f64) -> Option<(f64, f64)> {
+        self.projection.forward(lon, lat).map(|xy| self.to_canvas(xy))
+    }
+
+    /// Like [`point`](Frame::point), but only inside the view.
+    fn point_in_view(&self, lon: f64, lat: f64) -> Option<(f64, f64)> {
+        self.point(lon, lat).filter(|&p| self.view.grow(0.5).contains(p))
+    }
+
+    fn clamped(&self, lon: f64, lat: f64) -> (f64, f64) {
+        self.to_canvas(self.projection.forward_clamped(lon, lat))
+    }
+}
+
+/// SVG path data through `points`; `None` lifts the pen.
+fn polyline(points: impl Iterator<Item = Option<(f64, f64)>>) -> String {
+    let mut d = String::new();
+    let mut pen_down = false;
+    for point in points {
+        match point {
+            Some((x, y)) => {
+                d.push_str(&format!("{}{x:.1},{y:.1}", if pen_down { 'L' } else { 'M' }));
+                pen_down = true;
+            }
+            None => pen_down = false,
+        }
+    }
+    d
+}
+
+// ---------------------------------------------------------------------------
+// GeoJSON → SVG paths
+// ---------------------------------------------------------------------------
+
+/// Path data for one polygon ring, or `None` if none of it is in view.
+fn ring_to_path(frame: &Frame, coords: &[Value]) -> Option<String> {
+    let mut points = Vec::with_capacity(coords.len());
+    let mut visible = false;
+    for pt in coords {
+        let arr = match pt.as_array() { Some(a) => a, None => continue };
+        let lon = match arr.first().and_then(|v| v.as_f64()) { Some(v) => v, None => continue };
+        let lat = match arr.get(1).and_then(|v| v.as_f64())  { Some(v) => v, None => continue };
+        visible |= frame.projection.forward(lon, lat).is_some();
+        points.push(frame.clamped(lon, lat));
+    }
+    // Just outside the view, so the cut edge's stroke isn't seen.
+    let points = clip_ring(points, frame.view.grow(2.0));
+    if !visible || points.is_empty() {
+        return None;
+    }
+    let mut d = String::new();
+    for (i, (x, y)) in points.into_iter().enumerate() {
+        if i == 0 { d.push_str(&format!("M{x:.2},{y:.2}")) }
+        else       { d.push_str(&format!("L{x:.2},{y:.2}")) }
+    }
+    d.push('Z');
+    Some(d)
+}
+
+/// Cut a closed ring down to the part inside `rect` (Sutherland–Hodgman,
+/// one rectangle edge at a time).
+fn clip_ring(points: Vec<(f64, f64)>, rect: Rect) -> Vec<(f64, f64)> {
+    if points.iter().all(|&p| rect.contains(p)) {
+        return points;
+    }
+    // (x rather than y, bound, keep the side above the bound)
+    let edges = [
+        (true, rect.x, true),
+        (true, rect.x + rect.w, false),
+        (false, rect.y, true),
+        (false, rect.y + rect.h, false),
+    ];
+    let mut ring = points;
+    for (is_x, bound, above) in edges {
+        let coord = |p: (f64, f64)| if is_x { p.0 } else { p.1 };
+        let inside = |p| (coord(p) >= bound) == above;
+        let mut clipped = Vec::with_capacity(ring.len());
+        for (i, &p) in ring.iter().enumerate() {
+            let prev = ring[(i + ring.len() - 1) % ring.len()];
+            if inside(p) != inside(prev) {
+                let t = (bound - coord(prev)) / (coord(p) - coord(prev));
+                clipped.push((prev.0 + (p.0 - prev.0) * t, prev.1 + (p.1 - prev.1) * t));
+            }
+            if inside(p) {
+                clipped.push(p);
+            }
+        }
+        ring = clipped;
+        if ring.is_empty() {
+            break;
+        }
+    }
+    ring
+}
+
+fn geometry_paths(frame: &Frame, geom: &Value) -> Vec<String> {
+    let mut paths = Vec::new();
+    match geom["type"].as_str().unwrap_or("") {
+        "Polygon" => {
+            if let Some(rings) = geom["coordinates"].as_array() {
+                for ring in rings {
+                    if let Some(pts) = ring.as_array() { paths.extend(ring_to_path(frame, pts)); }
+                }
+            }
+        }
+        "MultiPolygon" => {
+            if let Some(polys) = geom["coordinates"].as_array() {
+                for poly in polys {
+                    if let Some(rings) = poly.as_array() {
+                        for ring in rings {
+                            if let Some(pts) = ring.as_array() { paths.extend(ring_to_path(frame, pts)); }
+                        }
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+    paths
+}
+
+// ---------------------------------------------------------------------------
+// Country relay counts
+// ---------------------------------------------------------------------------
+
+fn country_counts<'a>(relays: impl Iterator<Item = &'a TorNode>) -> Vec<(String, usize)> {
+    let mut map: HashMap<String, usize> = HashMap::new();
+    for r in relays {
+        if let Some(cc) = &r.country {
+            *map.entry(cc.to_uppercase()).or_insert(0) += 1;
+        }
+    }
+    let mut counts: Vec<_> = map.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
+}
+
+// ---------------------------------------------------------------------------
+// Marker sizes
+// ---------------------------------------------------------------------------
+
+/// What dot area is proportional to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkerSize {
+    Bandwidth,
+    ConsensusWeight,
+    ExitProbability,
+}
+
+impl FromStr for MarkerSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "bandwidth"        => Ok(MarkerSize::Bandwidth),
+            "consensus-weight" => Ok(MarkerSize::ConsensusWeight),
+            "exit-probability" => Ok(MarkerSize::ExitProbability),
+            other => anyhow::bail!("unknown marker size `{other}` (expected bandwidth, consensus-weight or exit-probability)"),
+        }
+    }
+}
+
+impl MarkerSize {
+    fn value(self, relay: &TorNode) -> f64 {
+        match self {
+            MarkerSize::Bandwidth       => relay.advertised_bandwidth.unwrap_or(0) as f64,
+            MarkerSize::ConsensusWeight => relay.consensus_weight as f64,
+            MarkerSize::ExitProbability => relay.exit_probability.unwrap_or(0.0),
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            MarkerSize::Bandwidth       => "Advertised bandwidth",
+            MarkerSize::ConsensusWeight => "Consensus weight",
+            MarkerSize::ExitProbability => "Exit probability",
+        }
+    }
+
+    /// Label for a round legend value, with just the decimals it needs.
+    fn label(self, v: f64) -> String {
+        let round = |v: f64| format!("{v:.*}", (-v.log10().floor()).max(0.0) as usize);
+        match self {
+            MarkerSize::Bandwidth       => stats::format_bandwidth(v as u64),
+            MarkerSize::ConsensusWeight => round(v),
+            MarkerSize::ExitProbability => format!("{}%", round(v * 100.0)),
+        }
+    }
+}
+
+/// Dot radii for a [`MarkerSize`], relative to the largest value drawn.
+struct Sizing {
+    metric: MarkerSize,
+    max: f64,
+}
+
+impl Sizing {
+    fn new(metric: MarkerSize, relays: &[TorNode]) -> Self {
+        let max = relays.iter().map(|r| metric.value(r)).fold(0.0, f64::max);
+        Self { metric, max }
+    }
+
+    /// Area proportional to `v`, but never smaller than `R_MIN` so the
+    /// smallest relays still show. Rounded to keep the SVG short.
+    fn radius_of(&self, v: f64) -> f64 {
+        let r = if self.max > 0.0 { R_MAX * (v / self.max).sqrt() } else { R_MIN };
+        (r.max(R_MIN) * 10.0).round() / 10.0
+    }
+
+    fn radius(&self, relay: &TorNode) -> f64 {
+        self.radius_of(self.metric.value(relay))
+    }
+
+    /// Round legend values: the largest 1, 2 or 5 × 10ⁿ not above the
+    /// maximum, then a tenth and a hundredth of it.
+    fn legend_values(&self) -> Vec<f64> {
+        if self.max <= 0.0 {
+            return Vec::new();
+        }
+        let p = 10f64.powf(self.max.log10().floor());
+        let top = [5.0, 2.0, 1.0].into_iter().map(|m| m * p).find(|&v| v <= self.max).unwrap_or(p);
+        vec![top / 100.0, top / 10.0, top]
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Colour scales
+// ---------------------------------------------------------------------------
+
+/// What a colour scale measures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Unit {
+    Count,
+    Percent,
+    /// Bytes per second.
+    Bandwidth,
+}
+
+/// Logarithmic colour scale from zero up to `max`.
+struct Scale {
+    title: &'static str,
+    unit: Unit,
+    max: f64,
+}
+
+impl Scale {
+    /// The smallest difference the scale resolves: one relay, 0.01 % or
+    /// 1 MB/s. Ticks start here.
+    fn step(&self) -> f64 {
+        match self.unit {
+            Unit::Count     => 1.0,
+            Unit::Percent   => 0.01,
+            Unit::Bandwidth => 1e6,
+        }
+    }
+
+    /// Position of `v` along the scale, 0..=1.
+    fn position(&self, v: f64) -> f64 {
+        if self.max <= 0.0 {
+            return 0.0;
+        }
+        (1.0 + v / self.step()).ln() / (1.0 + self.max / self.step()).ln()
+    }
+
+    fn color(&self, v: f64, theme: &Theme) -> String {
+        theme.ramp_color(self.position(v))
+    }
+
+    fn label(&self, v: f64) -> String {
+        match self.unit {
+            Unit::Count     => format!("{v}"),
+            Unit::Percent   => format!("{v:.2}%"),
+            Unit::Bandwidth => stats::format_bandwidth(v as u64),
+        }
+    }
+
+    /// A round tick value, without needless decimals.
+    fn tick_label(&self, v: f64) -> String {
+        match self.unit {
+            Unit::Percent => format!("{v}%"),
+            _             => self.label(v),
+        }
+    }
+
+    /// Powers of ten from one step up to the largest value.
+    fn ticks(&self) -> Vec<f64> {
+        let first = self.step().log10().round() as i32;
+        (first..).map(|k| 10f64.powi(k)).take_while(|&v| v <= self.max).collect()
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Choropleth
+// ---------------------------------------------------------------------------
+
+/// What a choropleth map shades countries by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Choropleth {
+    Relays,
+    Guards,
+    Exits,
+    /// Percentage of the total consensus weight.
+    ConsensusWeight,
+}
+
+impl FromStr for Choropleth {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "relays"           => Ok(Choropleth::Relays),
+            "guards"           => Ok(Choropleth::Guards),
+            "exits"            => Ok(Choropleth::Exits),
+            "consensus-weight" => Ok(Choropleth::ConsensusWeight),
+            other => anyhow::bail!("unknown choropleth `{other}` (expected relays, guards, exits or consensus-weight)"),
+        }
+    }
+}
+
+/// Per-country values and the scale they are coloured on.
+struct Shading {
+    /// Keyed by upper-case country code; only countries with a value.
+    values: HashMap<String, f64>,
+    scale: Scale,
+}
+
+impl Shading {
+    fn new(metric: Choropleth, relays: &[TorNode]) -> Self {
+        let mut values: HashMap<String, f64> = HashMap::new();
+        for r in relays {
+            let Some(cc) = &r.country else { continue };
+            let v = match metric {
+                Choropleth::Relays          => 1.0,
+                Choropleth::Guards          => f64::from(u8::from(r.is_guard())),
+                Choropleth::Exits           => f64::from(u8::from(r.is_exit())),
+                Choropleth::ConsensusWeight => r.consensus_weight as f64,
+            };
+            *values.entry(cc.to_uppercase()).or_insert(0.0) += v;
+        }
+        values.retain(|_, v| *v > 0.0);
+        if metric == Choropleth::ConsensusWeight {
+            let total: f64 = values.values().sum();
+            values.values_mut().for_each(|v| *v = *v * 100.0 / total);
+        }
+        let (title, unit) = match metric {
+            Choropleth::Relays          => ("Relays per country", Unit::Count),
+            Choropleth::Guards          => ("Guards per country", Unit::Count),
+            Choropleth::Exits           => ("Exits per country", Unit::Count),
+            Choropleth::ConsensusWeight => ("Share of consensus weight", Unit::Percent),
+        };
+        let max = values.values().copied().fold(0.0, f64::max);
+        Self { values, scale: Scale { title, unit, max } }
+    }
+
+    /// Fill for a GeoJSON country feature, if it has a value.
+    fn fill(&self, feature: &Value, theme: &Theme) -> Option<String> {
+        let v = self.values.get(&region::iso_code(feature)?)?;
+        Some(self.scale.color(*v, theme))
+    }
+
+    /// Countries by value, largest first.
+    fn ranked(&self) -> Vec<(String, String)> {
+        let mut ranked: Vec<_> = self.values.iter().collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        ranked.into_iter().map(|(cc, &v)| (cc.clone(), self.scale.label(v))).collect()
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Density cells
+// ---------------------------------------------------------------------------
+
+/// Cell shape for density mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cells {
+    Hexbin,
+    Grid,
+}
+
+/// Relays binned into cells of `size` pixels instead of drawn one by one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Density {
+    pub cells: Cells,
+    /// Hexagon radius or square side, in canvas pixels.
+    pub size: f64,
+    /// Colour cells by summed advertised bandwidth rather than relay count.
+    pub bandwidth: bool,
+}
+
+impl Density {
+    const DEFAULT_SIZE: f64 = 10.0;
+
+    /// Parse `hexbin[:SIZE]` or `grid[:SIZE]`; `by` is `relays` (default)
+    /// or `bandwidth`.
+    pub fn parse(spec: &str, by: Option<&str>) -> anyhow::Result<Self> {
+        let (name, size) = match spec.split_once(':') {
+            Some((name, size)) => {
+                let size: f64 = size.trim().parse().map_err(|e| anyhow::anyhow!("invalid cell size in `{spec}`: {e}"))?;
+                anyhow::ensure!((2.0..=200.0).contains(&size), "cell size in `{spec}` must be 2 to 200 pixels");
+                (name, size)
+            }
+            None => (spec, Self::DEFAULT_SIZE),
+        };
+        let cells = match name {
+            "hexbin" => Cells::Hexbin,
+            "grid"   => Cells::Grid,
+            other    => anyhow::bail!("unknown density mode `{other}` (expected hexbin or grid)"),
+        };
+        let bandwidth = match by {
+            None | Some("relays") => false,
+            Some("bandwidth")     => true,
+            Some(other) => anyhow::bail!("unknown density weighting `{other}` (expected relays or bandwidth)"),
+        };
+        Ok(Self { cells, size, bandwidth })
+    }
+
+    /// Cell containing canvas point `(x, y)`, as integer coordinates.
+    fn cell(&self, (x, y): (f64, f64)) -> (i32, i32) {
+        match self.cells {
+            Cells::Grid => ((x / self.size).floor() as i32, (y / self.size).floor() as i32),
+            // Pointy-top hexagons in axial coordinates, rounded via cube coordinates.
+            Cells::Hexbin => {
+                let q = (3f64.sqrt() / 3.0 * x - y / 3.0) / self.size;
+                let r = (2.0 / 3.0 * y) / self.size;
+                let s = -q - r;
+                let (mut rq, mut rr, rs) = (q.round(), r.round(), s.round());
+                let (dq, dr, ds) = ((rq - q).abs(), (rr - r).abs(), (rs - s).abs());
+                if dq > dr && dq > ds {
+                    rq = -rr - rs;
+                } else if dr > ds {
+                    rr = -rq - rs;
+                }
+                (rq as i32, rr as i32)
+            }
+        }
+    }
+
+    /// Canvas centre of a hexagon, or top-left corner of a square.
+    fn origin(&self, (a, b): (i32, i32)) -> (f64, f64) {
+        let (a, b) = (f64::from(a), f64::from(b));
+        match self.cells {
+            Cells::Grid   => (a * self.size, b * self.size),
+            Cells::Hexbin => (self.size * 3f64.sqrt() * (a + b / 2.0), self.size * 1.5 * b),
+        }
+    }
+
+    fn scale(&self, max: f64) -> Scale {
+        match self.bandwidth {
+            false => Scale { title: "Relays per cell", unit: Unit::Count, max },
+            true  => Scale { title: "Advertised bandwidth per cell", unit: Unit::Bandwidth, max },
+        }
+    }
+
+    /// Relays in view summed per cell, and the relays counted.
+    fn bin<'a>(&self, frame: &Frame, placed: &[Placed<'a>]) -> (BTreeMap<(i32, i32), f64>, Vec<&'a TorNode>) {
+        let mut cells = BTreeMap::new();
+        let mut shown = Vec::new();
+        for &(relay, (lat, lon)) in placed {
+            let Some(p) = frame.point_in_view(lon, lat) else { continue };
+            let v = if self.bandwidth { relay.advertised_bandwidth.unwrap_or(0) as f64 } else { 1.0 };
+            *cells.entry(self.cell(p)).or_insert(0.0) += v;
+            shown.push(relay);
+        }
+        (cells, shown)
+    }
+}
+
+/// Density cells, coloured on `scale` (the main map's, so insets read the
+/// same way).
+fn draw_cells(s: &mut String, theme: &Theme, density: &Density, scale: &Scale, cells: &BTreeMap<(i32, i32), f64>) {
+    let size = density.size;
+    s.push_str(&format!("  <g stroke='{}' stroke-width='0.5'>\n", theme.background));
+    for (&cell, &v) in cells {
+        let (x, y) = density.origin(cell);
+        let fill = scale.color(v, theme);
+        match density.cells {
+            Cells::Grid   => s.push_str(&format!("    <rect x='{x:.1}' y='{y:.1}' width='{size}' height='{size}' fill='{fill}'/>\n")),
+            Cells::Hexbin => s.push_str(&format!("    <use href='#hex' x='{x:.1}' y='{y:.1}' fill='{fill}'/>\n")),
+        }
+    }
+    s.push_str("  </g>\n");
+}
+
+/// A hexagon of radius `size` around the origin, for `<use>`.
+fn hexagon(size: f64) -> String {
+    let corners: Vec<String> = (0..6)
+        .map(|i| {
+            let angle = (-90.0 + 60.0 * f64::from(i)).to_radians();
+            format!("{:.2},{:.2}", size * angle.cos(), size * angle.sin())
+        })
+        .collect();
+    format!("M{}Z", corners.join("L"))
+}
+
+// ---------------------------------------------------------------------------
+// SVG rendering
+// ---------------------------------------------------------------------------
+
+/// Globe, graticule and country polygons within `frame`, the countries
+/// shaded if there is a `shading`.
+fn draw_base(s: &mut String, theme: &Theme, frame: &Frame, geojson: &Value, shading: Option<&Shading>) {
+    // the globe itself in ocean colour
+    let outline = polyline(frame.projection.outline().into_iter().map(|xy| Some(frame.to_canvas(xy))));
+    s.push_str(&format!("  <path d='{outline}Z' fill='{}'/>\n", theme.ocean));
+
+    // graticule, sampled finely enough that curved projections bend it
+    let e = frame.extent;
+    let lats = || (0..=90).map(move |i| e.south + (e.north - e.south) * f64::from(i) / 90.0);
+    let lons = || (0..=180).map(move |i| e.west + (e.east - e.west) * f64::from(i) / 180.0);
+    let lines = |from: f64, to: f64| {
+        let step = frame.step;
+        ((from / step).ceil() as i32..=(to / step).floor() as i32).map(move |i| f64::from(i) * step)
+    };
+    s.push_str(&format!("  <g stroke='{}' stroke-width='0.5' fill='none'>\n", theme.graticule));
+    for lon in lines(e.west, e.east) {
+        let d = polyline(lats().map(|lat| frame.point(lon, lat)));
+        s.push_str(&format!("    <path d='{d}'/>\n"));
+    }
+    for lat in lines(e.south, e.north) {
+        let d = polyline(lons().map(|lon| frame.point(lon, lat)));
+        s.push_str(&format!("    <path d='{d}'/>\n"));
+    }
+    s.push_str("  </g>\n");
+
+    // country polygons (embedded)
+    let land = if shading.is_some() { &theme.no_data } else { &theme.land };
+    s.push_str(&format!("  <g fill='{land}' stroke='{}' stroke-width='0.5'>\n", theme.border));
+    if let Some(features) = geojson["features"].as_array() {
+        for feature in features {
+            let fill = shading.and_then(|sh| sh.fill(feature, theme));
+            for d in geometry_paths(frame, &feature["geometry"]) {
+                match &fill {
+                    Some(fill) => s.push_str(&format!("    <path d='{d}' fill='{fill}'/>\n")),
+                    None       => s.push_str(&format!("    <path d='{d}'/>\n")),
+                }
+            }
+        }
+    }
+    s.push_str("  </g>\n");
+}
+
+/// Dots for the relays in view — middles first, then guards/exits on top.
+/// Returns the relays drawn.
+/// With a `sizing`, larger dots go underneath smaller ones.
+/// `interactive` dots carry their legend entries as classes and their
+/// index in `placed` as `data-i`, for the HTML map's script.
+fn draw_dots<'a>(
+    s: &mut String,
+    theme: &Theme,
+    frame: &Frame,
+    placed: &[Placed<'a>],
+    categories: &[Category],
+    sizing: Option<&Sizing>,
+    interactive: bool,
+) -> Vec<&'a TorNode> {
+    let mut order: Vec<_> = placed.iter().enumerate().collect();
+    if let Some(sizing) = sizing {
+        order.sort_by(|(_, a), (_, b)| sizing.radius(b.0).total_cmp(&sizing.radius(a.0)));
+        s.push_str(&format!("  <g stroke='{}' stroke-width='0.6' fill-opacity='0.85'>\n", theme.ocean));
+    } else {
+        s.push_str(&format!("  <g stroke='{}' stroke-width='0.6'>\n", theme.ocean));
+    }
+    let mut shown = Vec::new();
+    for pass in [false, true] {
+        for &(i, &(relay, (lat, lon))) in &order {
+            let marker = relay.marker(categories);
+            if marker.role.notable() != pass { continue; }
+
+            let Some(pos) = frame.point_in_view(lon, lat) else { continue };
+            shown.push(relay);
+            let r      = sizing.map_or_else(|| marker.radius(), |sz| sz.radius(relay));
+            let data   = if interactive { format!(" class='{}' data-i='{i}'", marker.classes()) } else { String::new() };
+            let paint  = marker.paint(theme, marker.role.color(theme), "");
+            push_marker(s, marker.shape, pos, r, &format!("{paint}{data}"));
+        }
+    }
+    s.push_str("  </g>\n");
+    shown
+}
+
+/// A marker of `shape` centred on (x, y), its area that of a circle of
+/// radius `r`, with `attrs` (see [`Marker::paint`]).
+fn push_marker(s: &mut String, shape: Shape, (x, y): (f64, f64), r: f64, attrs: &str) {
+    match shape {
+        Shape::Circle => s.push_str(&format!("    <circle cx='{x:.1}' cy='{y:.1}' r='{r}' {attrs}/>\n")),
+        Shape::Square => {
+            let side = r * std::f64::consts::PI.sqrt();
+            s.push_str(&format!(
+                "    <rect x='{:.1}' y='{:.1}' width='{side:.1}' height='{side:.1}' {attrs}/>\n",
+                x - side / 2.0,
+                y - side / 2.0
+            ));
+        }
+        Shape::Diamond => {
+            let d = r * std::f64::consts::FRAC_PI_2.sqrt();
+            s.push_str(&format!(
+                "    <path d='M{x:.1},{:.1}l{d:.1},{d:.1} -{d:.1},{d:.1} -{d:.1},-{d:.1}z' {attrs}/>\n",
+                y - d
+            ));
+        }
+    }
+}
+
+/// The edge of `b`, finely enough sampled to follow curved projections.
+fn bbox_ring(b: &BBox) -> Vec<(f64, f64)> {
+    let steps = 45;
+    let t = |i: usize| i as f64 / steps as f64;
+    let (dx, dy) = (b.east - b.west, b.north - b.south);
+    (0..steps).map(|i| (b.west + dx * t(i), b.south))
+        .chain((0..steps).map(|i| (b.east, b.south + dy * t(i))))
+        .chain((0..steps).map(|i| (b.east - dx * t(i), b.north)))
+        .chain((0..=steps).map(|i| (b.west, b.north - dy * t(i))))
+        .collect()
+}
+
+/// Where each inset goes: stacked upwards from just above the legend, each
+/// as large as fits `INSET_W`×`INSET_H` at the area's own aspect ratio.
+fn inset_views(projection: &dyn Projection, insets: &[Area]) -> Vec<Rect> {
+    let mut bottom = H - 84.0;
+    insets
+        .iter()
+        .map(|area| {
+            let b = area_bounds(projection, Some(&area.bbox));
+            let aspect = (b.x_max - b.x_min) / (b.y_max - b.y_min);
+            let (w, h) = if aspect >= INSET_W / INSET_H { (INSET_W, INSET_W / aspect) } else { (INSET_H * aspect, INSET_H) };
+            bottom -= h;
+            let view = Rect { x: 10.0, y: bottom, w, h };
+            bottom -= 10.0;
+            view
+        })
+        .collect()
+}
+
+pub fn render_svg(relays: &[TorNode], geojson: &Value, opts: &Options) -> String {
+    render(relays, geojson, opts, false).0
+}
+
+/// The same map as a single HTML page: the SVG inline, plus each plotted
+/// relay's details as JSON for the tooltips, search and layer toggles in
+/// templates/map.html. Nothing is loaded from elsewhere.
+pub fn render_html(relays: &[TorNode], geojson: &Value, opts: &Options) -> String {
+    let (svg, placed, markers) = render(relays, geojson, opts, true);
+    let svg = svg.split_once('\n').map_or(svg.as_str(), |(_, rest)| rest);
+    let details: Vec<Value> = placed
+        .iter()
+        .map(|(relay, _)| {
+            json!({
+                "nickname":    relay.nickname,
+                "fingerprint": relay.fingerprint,
+                "flags":       relay.flags,
+                "as":          relay.as_number,
+                "as_name":     relay.as_name,
+                "bandwidth":   stats::format_bandwidth(relay.advertised_bandwidth.unwrap_or(0)),
+                "country":     relay.country,
+                "addresses":   relay.or_addresses,
+            })
+        })
+        .collect();
+    // `</script>` inside a string would end the JSON block early.
+    let details = Value::Array(details).to_string().replace("</", "<\\/");
+    let theme = &opts.theme;
+    // A toggle per legend entry, hiding the dots with its class.
+    let mut layers = String::new();
+    for entry in legend(&markers, theme) {
+        layers.push_str(&format!(
+            "  <label><input type=\"checkbox\" data-layer=\"{}\" checked><svg class=\"swatch\" viewBox=\"-7 -7 14 14\">\n",
+            entry.class
+        ));
+        entry.swatch(&mut layers, theme, (0.0, 0.0), 5.5);
+        layers.push_str(&format!("  </svg>{}</label>\n", entry.label));
+    }
+    let colors = [
+        ("background", &theme.background), ("ocean", &theme.ocean), ("border", &theme.border),
+        ("text", &theme.text), ("heading", &theme.heading), ("muted", &theme.muted), ("font", &theme.font),
+    ];
+    let page = colors.iter().fold(MAP_HTML.to_string(), |page, (key, value)| page.replace(&format!("{{{{{key}}}}}"), value));
+    page
+        .replace("{{title}}", &xml_escape(&title(opts)))
+        .replace("{{layers}}", &layers)
+        .replace("{{count}}", &placed.len().to_string())
+        .replace("{{svg}}", svg)
+        .replace("{{relays}}", &details)
+}
+
+/// The map as SVG, the relays with a position, which `interactive` dots
+/// refer to by index, and the markers of the dots drawn.
+fn render<'a>(
+    relays: &'a [TorNode],
+    geojson: &Value,
+    opts: &Options,
+    interactive: bool,
+) -> (String, Vec<Placed<'a>>, Vec<Marker>) {
+    let mut s = String::with_capacity(4 << 20);
+    let theme = &opts.theme;
+    let projection = opts.projection.as_ref();
+    let region = opts.region.as_ref();
+    let frame = Frame::new(projection, region.map(|a| &a.bbox), Rect::CANVAS);
+
+    let shading = opts.choropleth.map(|metric| Shading::new(metric, relays));
+    let sizing  = opts.marker_size.map(|metric| Sizing::new(metric, relays));
+
+    let title = xml_escape(&title(opts));
+    let desc = match (&shading, &opts.density) {
+        (Some(sh), _)   => format!("Tor relays by country: {}.", sh.scale.title.to_lowercase()),
+        (None, Some(d)) => format!("Tor relay density: {}.", d.scale(0.0).title.to_lowercase()),
+        (None, None)    => "Live Tor relay positions, coloured by relay role as the legend shows.".to_string(),
+    };
+    s.push_str(&format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<svg xmlns="http://www.w3.org/2000/svg" width="{W}" height="{H}" viewBox="0 0 {W} {H}">
+  <title>{title}</title>
+  <desc>{desc}</desc>
+"#
+    ));
+    if let Some(d) = opts.density.filter(|d| d.cells == Cells::Hexbin && shading.is_none()) {
+        s.push_str(&format!("  <defs><path id='hex' d='{}'/></defs>\n", hexagon(d.size)));
+    }
+
+    // background, then the map
+    s.push_str(&format!("  <rect width='{W}' height='{H}' fill='{}'/>\n", theme.background));
+    draw_base(&mut s, theme, &frame, geojson, shading.as_ref());
+
+    let mut placed = Vec::new();
+    let mut shown  = Vec::new();
+    let mut density_scale = None;
+    match &shading {
+        Some(sh) => {
+            let drawn: Vec<String> = geojson["features"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(region::iso_code)
+                .collect();
+            let mut missing: Vec<_> = sh.values.keys().filter(|cc| !drawn.contains(cc)).collect();
+            missing.sort();
+            for cc in &missing {
+                debug!("Country {cc} has relays but no polygon on the map.");
+            }
+            info!(
+                "Shaded {} countries by {} ({} without a polygon).",
+                sh.values.len() - missing.len(),
+                sh.scale.title.to_lowercase(),
+                missing.len(),
+            );
+        }
+        None => {
+            // Resolve every position once, for the map and its insets.
+            let mut from_mmdb = 0usize;
+            for relay in relays {
+                let Some(pos) = relay.resolve_position() else {
+                    debug!("Relay {} has no position, not plotted.", relay.fingerprint);
+                    continue;
+                };
+                // Count how many positions came from the GeoLite2 fallback.
+                if !relay.has_onionoo_position() {
+                    from_mmdb += 1;
+                }
+                placed.push((relay, pos));
+            }
+
+            let mut cell_count = 0;
+            match &opts.density {
+                Some(density) => {
+                    let (cells, counted) = density.bin(&frame, &placed);
+                    let scale = density.scale(cells.values().copied().fold(0.0, f64::max));
+                    draw_cells(&mut s, theme, density, &scale, &cells);
+                    cell_count = cells.len();
+                    density_scale = Some(scale);
+                    shown = counted;
+                }
+                None => shown = draw_dots(&mut s, theme, &frame, &placed, &opts.categories, sizing.as_ref(), interactive),
+            }
+            let resolved = placed.len();
+            let plotted  = shown.len();
+            let unplaced = relays.len() - resolved;
+            let hidden   = resolved - plotted;
+            let what = match opts.density {
+                Some(_) => format!("{plotted} relays in {cell_count} cells"),
+                None    => format!("{plotted} dots"),
+            };
+            info!(
+                "Plotted {what} ({from_mmdb} resolved via GeoLite2 fallback, {unplaced} relays without a position{}).",
+                if hidden > 0 { format!(", {hidden} out of view") } else { String::new() },
+            );
+            summary::record(|s| {
+                let p = s.positions.get_or_insert_with(Default::default);
+                p.onionoo  += resolved - from_mmdb;
+                p.geolite2 += from_mmdb;
+                p.unplaced += unplaced;
+            });
+        }
+    }
+
+    // insets, each marked on the main map
+    for (i, (area, view)) in opts.insets.iter().zip(inset_views(projection, &opts.insets)).enumerate() {
+        let marker = polyline(bbox_ring(&area.bbox).into_iter().map(|(lon, lat)| frame.point(lon, lat)));
+        s.push_str(&format!(
+            "  <path d='{marker}' fill='none' stroke='{}' stroke-width='0.8' stroke-dasharray='3 2'/>\n",
+            theme.muted
+        ));
+
+        let inset = Frame::new(projection, Some(&area.bbox), view);
+        let Rect { x, y, w, h } = view;
+        s.push_str(&format!(
+            "  <clipPath id='inset-{i}'><rect x='{x:.1}' y='{y:.1}' width='{w:.1}' height='{h:.1}'/></clipPath>\n"
+        ));
+        s.push_str(&format!("  <g clip-path='url(#inset-{i})'>\n"));
+        s.push_str(&format!("  <rect x='{x:.1}' y='{y:.1}' width='{w:.1}' height='{h:.1}' fill='{}'/>\n", theme.background));
+        draw_base(&mut s, theme, &inset, geojson, shading.as_ref());
+        if shading.is_none() {
+            match (&opts.density, &density_scale) {
+                (Some(density), Some(scale)) => draw_cells(&mut s, theme, density, scale, &density.bin(&inset, &placed).0),
+                _ => {
+                    draw_dots(&mut s, theme, &inset, &placed, &opts.categories, sizing.as_ref(), interactive);
+                }
+            }
+        }
+        s.push_str("  </g>\n");
+        s.push_str(&format!(
+            "  <rect x='{x:.1}' y='{y:.1}' width='{w:.1}' height='{h:.1}' fill='none' stroke='{}' stroke-width='1'/>\n",
+            theme.muted
+        ));
+        s.push_str(&format!(
+            "  <text x='{:.1}' y='{:.1}' font-family='{}' font-size='10' fill='{}'>{}</text>\n",
+            x + 5.0, y + 13.0, theme.font, theme.heading, xml_escape(&area.label)
+        ));
+    }
+
+    // The legend shows what is drawn; a regional dot map's totals describe
+    // the relays on it, otherwise all of them.
+    let markers: Vec<Marker> = shown.iter().map(|r| r.marker(&opts.categories)).collect();
+    let listed: Vec<&TorNode> = match (region, &shading) {
+        (Some(_), None) => shown,
+        _               => relays.iter().collect(),
+    };
+
+    // legend
+    let lx = 16.0_f64;
+    s.push_str(&format!("  <g font-family='{}' font-size='12' fill='{}'>\n", theme.font, theme.text));
+    match shading.as_ref().map(|sh| &sh.scale).or(density_scale.as_ref()) {
+        Some(scale) => draw_scale(&mut s, theme, scale, shading.is_some(), lx, H - 56.0),
+        None => {
+            let width = draw_markers(&mut s, theme, &legend(&markers, theme), lx, H - 70.0);
+            if let Some(sizing) = &sizing {
+                draw_sizes(&mut s, theme, sizing, lx + width, H - 54.0);
+            }
+        }
+    }
+    let total   = listed.len();
+    let guards  = listed.iter().filter(|r| r.is_guard()).count();
+    let exits   = listed.iter().filter(|r| r.is_exit()).count();
+    let middles = listed.iter().filter(|r| !r.is_guard() && !r.is_exit()).count();
+    s.push_str(&format!(
+        "    <text x='{lx:.1}' y='{:.1}' font-size='10' fill='{}'>total: {total}  guards: {guards}  exits: {exits}  middles: {middles}</text>\n",
+        H - 8.0,
+        theme.faint
+    ));
+    s.push_str("  </g>\n");
+
+    // top-10 countries
+    let counts: Vec<(String, String)> = match &shading {
+        Some(sh) => sh.ranked(),
+        None     => country_counts(listed.into_iter()).into_iter().map(|(cc, n)| (cc, n.to_string())).collect(),
+    };
+    let cx = W - 95.0;
+    let mut cy = 20.0_f64;
+    s.push_str(&format!("  <g font-family='{}' font-size='10' fill='{}'>\n", theme.font, theme.muted));
+    s.push_str(&format!("    <text x='{cx:.1}' y='{cy:.1}' font-size='11' fill='{}'>Top countries</text>\n", theme.heading));
+    cy += 14.0;
+    for (cc, count) in counts.iter().take(10) {
+        s.push_str(&format!("    <text x='{cx:.1}' y='{cy:.1}'>{cc}  {count}</text>\n"));
+        cy += 13.0;
+    }
+    s.push_str("  </g>\n");
+
+    s.push_str("</svg>\n");
+    (s, placed, markers)
+}
+
+fn title(opts: &Options) -> String {
+    match &opts.region {
+        Some(area) => format!("Tor Relay Map: {}", area.label),
+        None       => "Tor Relay World Map".to_string(),
+    }
+}
+
+/// Colour-scale legend, its title's baseline at `y`; `no_data` adds a
+/// swatch for countries without a value.
+fn draw_scale(s: &mut String, theme: &Theme, scale: &Scale, no_data: bool, x: f64, y: f64) {
+    let (w, h) = (240.0, 10.0);
+    let stops: String = (0..theme.ramp.len())
+        .map(|i| {
+            let t = i as f64 / (theme.ramp.len() - 1) as f64;
+            format!("<stop offset='{:.0}%' stop-color='{}'/>", t * 100.0, theme.ramp_color(t))
+        })
+        .collect();
+    s.push_str(&format!("    <linearGradient id='scale'>{stops}</linearGradient>\n"));
+    s.push_str(&format!("    <text x='{x:.1}' y='{y:.1}'>{}</text>\n", scale.title));
+    let top = y + 8.0;
+    s.push_str(&format!("    <rect x='{x:.1}' y='{top:.1}' width='{w}' height='{h}' fill='url(#scale)'/>\n"));
+    for v in scale.ticks() {
+        let tx = x + w * scale.position(v);
+        s.push_str(&format!(
+            "    <line x1='{tx:.1}' y1='{top:.1}' x2='{tx:.1}' y2='{:.1}' stroke='{}' stroke-width='0.8'/>\n",
+            top + h + 3.0,
+            theme.text
+        ));
+        s.push_str(&format!(
+            "    <text x='{tx:.1}' y='{:.1}' font-size='9' text-anchor='middle'>{}</text>\n",
+            top + h + 13.0,
+            scale.tick_label(v)
+        ));
+    }
+    if !no_data {
+        return;
+    }
+    let nx = x + w + 14.0;
+    s.push_str(&format!(
+        "    <rect x='{nx:.1}' y='{top:.1}' width='{h}' height='{h}' fill='{}' stroke='{}' stroke-width='0.5'/>\n",
+        theme.no_data, theme.border
+    ));
+    s.push_str(&format!("    <text x='{:.1}' y='{:.1}' font-size='10'>none</text>\n", nx + h + 4.0, top + h - 1.0));
+}
+
+/// One line of the marker legend, which is also a layer of the HTML map.
+struct LegendEntry<'t> {
+    marker: Marker,
+    fill: &'t str,
+    label: &'static str,
+    /// Class of the dots it describes.
+    class: &'static str,
+}
+
+impl LegendEntry<'_> {
+    fn swatch(&self, s: &mut String, theme: &Theme, (x, y): (f64, f64), r: f64) {
+        let outline = format!(" stroke='{}' stroke-width='0.8'", theme.ocean);
+        push_marker(s, self.marker.shape, (x, y), r, &self.marker.paint(theme, self.fill, &outline));
+    }
+}
+
+/// The legend for `markers`: each role among them (middle, guard and exit
+/// always), then a neutral sample of each shape or mark they use.
+fn legend<'t>(markers: &[Marker], theme: &'t Theme) -> Vec<LegendEntry<'t>> {
+    let mut roles = vec![Role::Middle, Role::Guard, Role::Exit];
+    roles.extend(markers.iter().map(|m| m.role));
+    roles.sort();
+    roles.dedup();
+    let plain = Marker { role: Role::Middle, shape: Shape::Circle, ipv6: false, approximate: false };
+    let mut entries: Vec<LegendEntry> = roles
+        .into_iter()
+        .map(|role| {
+            let shape = if role == Role::Authority { Shape::Diamond } else { Shape::Circle };
+            LegendEntry { marker: Marker { role, shape, ..plain }, fill: role.color(theme), label: role.label(), class: role.class() }
+        })
+        .collect();
+    let marks = [
+        (markers.iter().any(|m| m.shape == Shape::Square), Marker { shape: Shape::Square, ..plain }, "HSDir", "k-hsdir"),
+        (markers.iter().any(|m| m.ipv6), Marker { ipv6: true, ..plain }, "IPv6", "k-ipv6"),
+        (markers.iter().any(|m| m.approximate), Marker { approximate: true, ..plain }, "GeoLite2", "k-geolite2"),
+    ];
+    for (used, marker, label, class) in marks {
+        if used {
+            entries.push(LegendEntry { marker, fill: &theme.muted, label, class });
+        }
+    }
+    entries
+}
+
+/// Marker legend in columns of three rows from `y` down. Returns its width.
+fn draw_markers(s: &mut String, theme: &Theme, entries: &[LegendEntry], x: f64, y: f64) -> f64 {
+    let mut cx = x;
+    for column in entries.chunks(3) {
+        let mut ly = y;
+        for entry in column {
+            entry.swatch(s, theme, (cx + 6.0, ly), 6.0);
+            s.push_str(&format!("    <text x='{:.1}' y='{:.1}'>{}</text>\n", cx + 16.0, ly + 4.5, entry.label));
+            ly += 20.0;
+        }
+        let longest = column.iter().map(|e| e.label.len()).max().unwrap_or(0);
+        cx += (16.0 + 7.5 * longest as f64 + 14.0).max(100.0);
+    }
+    cx - x
+}
+
+/// Size legend for scaled markers: reference circles resting on a common
+/// baseline, each labelled, under a title whose baseline is at `y`.
+fn draw_sizes(s: &mut String, theme: &Theme, sizing: &Sizing, x: f64, y: f64) {
+    let values = sizing.legend_values();
+    if values.is_empty() {
+        return;
+    }
+    s.push_str(&format!("    <text x='{x:.1}' y='{y:.1}' font-size='11'>Dot area: {}</text>\n", sizing.metric.title().to_lowercase()));
+    let base = y + 32.0;
+    let mut cx = x;
+    for v in values {
+        let r = sizing.radius_of(v);
+        let label = sizing.metric.label(v);
+        s.push_str(&format!(
+            "    <circle cx='{:.1}' cy='{:.1}' r='{r}' fill='none' stroke='{}' stroke-width='0.8'/>\n",
+            cx + r, base - r, theme.text
+        ));
+        s.push_str(&format!("    <text x='{:.1}' y='{:.1}' font-size='9'>{label}</text>\n", cx + 2.0 * r + 4.0, base - 1.0));
+        cx += 2.0 * r + 4.0 + 5.5 * label.chars().count() as f64 + 12.0;
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Time-lapse
+// ---------------------------------------------------------------------------
+
+/// Seconds each snapshot of a time-lapse is shown for by default.
+pub const FRAME_SECONDS: f64 = 1.0;
+
+/// A relay in one snapshot of a time-lapse, compared with the one before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    Stayed,
+    Joined,
+    Left,
+    Absent,
+}
+
+/// One relay across a series of snapshots: drawn as listed in the latest
+/// snapshot it is in, and present in `present[i]` of them.
+struct Track<'a> {
+    relay: &'a TorNode,
+    present: Vec<bool>,
+}
+
+impl Track<'_> {
+    fn change(&self, i: usize) -> Change {
+        let before = i > 0 && self.present[i - 1];
+        match (self.present[i], before) {
+            (true, true)                => Change::Stayed,
+            (true, false) if i == 0     => Change::Stayed,
+            (true, false)               => Change::Joined,
+            (false, true)               => Change::Left,
+            (false, false)              => Change::Absent,
+        }
+    }
+
+    /// Fill in snapshot `i`: its role's colour, the theme's `joined` in
+    /// the snapshot it first reappears in, `left` in the one it is first
+    /// missing from, otherwise none.
+    fn fill<'t>(&self, i: usize, role: Role, theme: &'t Theme) -> &'t str {
+        match self.change(i) {
+            Change::Stayed => role.color(theme),
+            Change::Joined => &theme.joined,
+            Change::Left   => &theme.left,
+            Change::Absent => "none",
+        }
+    }
+}
+
+/// An animated SVG of `snapshots`, oldest first, each shown for `seconds`
+/// and the whole looping: the same projection, region and countries as
+/// [`render_svg`], with relays appearing and disappearing and a caption
+/// giving each snapshot's date and how many relays joined or left.
+///
+/// Relays are coloured by role, with `opts.categories` as on a dot map;
+/// shapes and marks are left out. Relays listed throughout are plain dots;
+/// the rest switch colour with one discrete SMIL `<animate>` each, so the
+/// file grows with the churn rather than with the number of snapshots times
+/// relays. Viewers without SMIL show the first snapshot.
+pub fn render_timelapse(snapshots: &[OnionooResponse], geojson: &Value, opts: &Options, seconds: f64) -> String {
+    let n = snapshots.len();
+    let theme = &opts.theme;
+    let region = opts.region.as_ref();
+    let frame = Frame::new(opts.projection.as_ref(), region.map(|a| &a.bbox), Rect::CANVAS);
+
+    // Every relay ever listed, in fingerprint order.
+    let mut tracks: BTreeMap<&str, Track> = BTreeMap::new();
+    for (i, snapshot) in snapshots.iter().enumerate() {
+        for relay in &snapshot.relays {
+            let track = tracks
+                .entry(relay.fingerprint.as_str())
+                .or_insert_with(|| Track { relay, present: vec![false; n] });
+            track.relay = relay;
+            track.present[i] = true;
+        }
+    }
+    let tracks: Vec<Track> = tracks.into_values().collect();
+    let placed: Vec<(&Track, (f64, f64))> =
+        tracks.iter().filter_map(|t| Some((t, t.relay.resolve_position()?))).collect();
+    let joined = |i: usize| tracks.iter().filter(|t| t.change(i) == Change::Joined).count();
+    let left   = |i: usize| tracks.iter().filter(|t| t.change(i) == Change::Left).count();
+
+    let total = seconds * n as f64;
+    // One value per snapshot, each held for its frame.
+    let animate = |attribute: &str, values: &[&str]| {
+        format!(
+            "<animate attributeName='{attribute}' values='{}' dur='{total}s' calcMode='discrete' repeatCount='indefinite'/>",
+            values.join(";")
+        )
+    };
+
+    let title = xml_escape(&match region {
+        Some(area) => format!("Tor Relay Time-lapse: {}", area.label),
+        None       => "Tor Relay Time-lapse".to_string(),
+    });
+    let mut s = String::with_capacity(4 << 20);
+    s.push_str(&format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<svg xmlns="http://www.w3.org/2000/svg" width="{W}" height="{H}" viewBox="0 0 {W} {H}">
+  <title>{title}</title>
+  <desc>Tor relays over {n} snapshots, marking those that joined or left.</desc>
+"#
+    ));
+    s.push_str(&format!("  <rect width='{W}' height='{H}' fill='{}'/>\n", theme.background));
+    draw_base(&mut s, theme, &frame, geojson, None);
+
+    // Steady relays underneath — middles, then guards/exits — and changing
+    // ones on top so a join or departure is never hidden.
+    let steady = |t: &Track| t.present.iter().all(|&p| p);
+    let mut in_view = 0usize;
+    let mut roles = vec![Role::Middle, Role::Guard, Role::Exit];
+    s.push_str(&format!("  <g stroke='{}' stroke-width='0.6'>\n", theme.ocean));
+    for pass in [false, true] {
+        for &(t, (lat, lon)) in placed.iter().filter(|(t, _)| steady(t)) {
+            let marker = t.relay.marker(&opts.categories);
+            if marker.role.notable() != pass { continue; }
+            let Some((x, y)) = frame.point_in_view(lon, lat) else { continue };
+            in_view += 1;
+            roles.push(marker.role);
+            s.push_str(&format!(
+                "    <circle cx='{x:.1}' cy='{y:.1}' r='{}' fill='{}'/>\n",
+                marker.radius(),
+                marker.role.color(theme)
+            ));
+        }
+    }
+    for &(t, (lat, lon)) in placed.iter().filter(|(t, _)| !steady(t)) {
+        let Some((x, y)) = frame.point_in_view(lon, lat) else { continue };
+        in_view += 1;
+        let marker = t.relay.marker(&opts.categories);
+        roles.push(marker.role);
+        let fills: Vec<&str> = (0..n).map(|i| t.fill(i, marker.role, theme)).collect();
+        s.push_str(&format!(
+            "    <circle cx='{x:.1}' cy='{y:.1}' r='{}' fill='{}'>{}</circle>\n",
+            marker.radius(),
+            fills[0],
+            animate("fill", &fills)
+        ));
+    }
+    s.push_str("  </g>\n");
+
+    // legend: the roles drawn, then joins and departures
+    roles.sort();
+    roles.dedup();
+    let mut legend: Vec<(&str, &str)> = roles.into_iter().map(|role| (role.color(theme), role.label())).collect();
+    legend.extend([(theme.joined.as_str(), "Joined"), (theme.left.as_str(), "Left")]);
+    let lx = 16.0_f64;
+    s.push_str(&format!("  <g font-family='{}' font-size='12' fill='{}'>\n", theme.font, theme.text));
+    let mut ly = H - 30.0 - 20.0 * (legend.len() - 1) as f64;
+    for (color, label) in legend {
+        s.push_str(&format!("    <circle cx='{:.1}' cy='{ly:.1}' r='6' fill='{color}' stroke='{}' stroke-width='0.8'/>\n", lx + 6.0, theme.ocean));
+        s.push_str(&format!("    <text x='{:.1}' y='{:.1}'>{label}</text>\n", lx + 16.0, ly + 4.5));
+        ly += 20.0;
+    }
+    s.push_str("  </g>\n");
+
+    // a caption per snapshot, each visible for its own frame, and a bar
+    // showing how far through the series the animation is
+    s.push_str(&format!("  <g font-family='{}' font-size='14' fill='{}'>\n", theme.font, theme.text));
+    for (i, snapshot) in snapshots.iter().enumerate() {
+        let visibility: Vec<&str> = (0..n).map(|j| if i == j { "visible" } else { "hidden" }).collect();
+        let date = snapshot.relays_published.as_deref().unwrap_or("unknown date");
+        let changes = if i == 0 { String::new() } else { format!("  +{} −{}", joined(i), left(i)) };
+        s.push_str(&format!(
+            "    <text x='{W}' y='{:.1}' dx='-16' text-anchor='end' visibility='{}'>{} · {} relays{changes}{}</text>\n",
+            H - 16.0,
+            visibility[0],
+            xml_escape(date),
+            snapshot.relays.len(),
+            animate("visibility", &visibility),
+        ));
+    }
+    s.push_str("  </g>\n");
+    s.push_str(&format!(
+        "  <rect x='0' y='{:.1}' width='0' height='3' fill='{}'><animate attributeName='width' from='0' to='{W}' dur='{total}s' repeatCount='indefinite'/></rect>\n",
+        H - 3.0,
+        theme.muted
+    ));
+    s.push_str("</svg>\n");
+
+    info!(
+        "Animated {n} snapshots: {in_view} relays on the map ({} throughout, {} without a position), {} joins and {} departures.",
+        tracks.iter().filter(|t| steady(t)).count(),
+        tracks.len() - placed.len(),
+        (1..n).map(joined).sum::<usize>(),
+        (1..n).map(left).sum::<usize>(),
+    );
+    s
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn relays(list: Value) -> Vec<TorNode> {
+        serde_json::from_value(list).unwrap()
+    }
+
+    fn square(x0: f64, y0: f64, side: f64) -> Vec<(f64, f64)> {
+        vec![(x0, y0), (x0 + side, y0), (x0 + side, y0 + side), (x0, y0 + side)]
+    }
+
+    #[test]
+    fn rings_are_clipped_to_the_view() {
+        let rect = Rect { x: 0.0, y: 0.0, w: 10.0, h: 10.0 };
+        // Entirely inside: untouched.
+        assert_eq!(clip_ring(square(2.0, 2.0, 5.0), rect), square(2.0, 2.0, 5.0));
+        // Entirely outside: nothing left.
+        assert!(clip_ring(square(20.0, 20.0, 5.0), rect).is_empty());
+        // Overlapping a corner: cut down to the overlap.
+        let clipped = clip_ring(square(5.0, 5.0, 10.0), rect);
+        assert_eq!(clipped.len(), 4);
+        for corner in square(5.0, 5.0, 5.0) {
+            assert!(clipped.contains(&corner), "{corner:?} in {clipped:?}");
+        }
+    }
+
+    #[test]
+    fn a_region_fills_the_view() {
+        let europe = region::parse("europe").unwrap().resolve(&Value::Null).unwrap();
+        let frame = Frame::new(&Equirectangular, Some(&europe.bbox), Rect::CANVAS);
+        let view = Rect::CANVAS.grow(0.5);
+        let (west, south) = frame.point(europe.bbox.west, europe.bbox.south).unwrap();
+        let (east, north) = frame.point(europe.bbox.east, europe.bbox.north).unwrap();
+        assert!(view.contains((west, south)) && view.contains((east, north)));
+        // Touching the canvas edges along at least one axis.
+        assert!((east - west - W).abs() < 1e-6 || (south - north - H).abs() < 1e-6);
+        assert!(frame.point_in_view(-100.0, 40.0).is_none());
+        assert_eq!(frame.step, 10.0);
+    }
+
+    #[test]
+    fn insets_stack_above_the_legend() {
+        let insets: Vec<Area> = ["europe", "0,0,1,10"]
+            .into_iter()
+            .map(|spec| region::parse(spec).unwrap().resolve(&Value::Null).unwrap())
+            .collect();
+        assert_eq!(insets.len(), MAX_INSETS);
+        let views = inset_views(&Equirectangular, &insets);
+        // Wide Europe takes the full inset width, the narrow box the full height.
+        assert_eq!(views[0].w, INSET_W);
+        assert_eq!(views[1].h, INSET_H);
+        assert!(views[1].y + views[1].h < views[0].y);
+        assert!(views.iter().all(|v| v.y >= 0.0 && v.y + v.h <= H - 84.0));
+    }
+
+    #[test]
+    fn scales_are_logarithmic_from_one_step() {
+        let scale = Scale { title: "", unit: Unit::Count, max: 999.0 };
+        assert_eq!(scale.position(0.0), 0.0);
+        assert!((scale.position(999.0) - 1.0).abs() < 1e-12);
+        assert!((scale.position(30.6) - 0.5).abs() < 0.01);
+        assert_eq!(scale.ticks(), [1.0, 10.0, 100.0]);
+        assert_eq!(Scale { max: 0.0, ..scale }.position(5.0), 0.0);
+
+        let percent = Scale { title: "", unit: Unit::Percent, max: 12.5 };
+        assert_eq!(percent.ticks(), [0.01, 0.1, 1.0, 10.0]);
+        assert_eq!((percent.label(2.345_6), percent.tick_label(0.1)), ("2.35%".to_string(), "0.1%".to_string()));
+        let theme = Theme::default();
+        assert_eq!(percent.color(0.0, &theme), theme.ramp_color(0.0));
+        assert_eq!(percent.color(12.5, &theme), theme.ramp_color(1.0));
+    }
+
+    #[test]
+    fn choropleth_shades_countries_by_metric() {
+        assert_eq!("consensus-weight".parse::<Choropleth>().unwrap(), Choropleth::ConsensusWeight);
+        assert!("bandwidth".parse::<Choropleth>().is_err());
+
+        let relays = relays(json!([
+            { "fingerprint": "A", "country": "de", "flags": ["Guard"], "consensus_weight": 300 },
+            { "fingerprint": "B", "country": "DE", "flags": ["Exit"], "consensus_weight": 100 },
+            { "fingerprint": "C", "country": "fr", "flags": ["Guard"], "consensus_weight": 400 },
+            { "fingerprint": "D", "consensus_weight": 200 },
+        ]));
+        let guards = Shading::new(Choropleth::Guards, &relays);
+        assert_eq!(guards.ranked(), [("DE".into(), "1".into()), ("FR".into(), "1".into())]);
+        let exits = Shading::new(Choropleth::Exits, &relays);
+        assert_eq!(exits.values.keys().collect::<Vec<_>>(), ["DE"]);
+
+        // Shares of the weight of relays with a country.
+        let weight = Shading::new(Choropleth::ConsensusWeight, &relays);
+        assert_eq!(weight.ranked(), [("DE".into(), "50.00%".into()), ("FR".into(), "50.00%".into())]);
+        assert_eq!(weight.scale.max, 50.0);
+
+        let theme = Theme::default();
+        let feature = |code: &str| json!({ "properties": { "ISO_A2": code } });
+        assert_eq!(weight.fill(&feature("FR"), &theme), Some(theme.ramp_color(1.0)));
+        assert_eq!(weight.fill(&feature("IT"), &theme), None);
+    }
+
+    #[test]
+    fn density_specs() {
+        let hexbin = Density::parse("hexbin", None).unwrap();
+        assert_eq!(hexbin, Density { cells: Cells::Hexbin, size: Density::DEFAULT_SIZE, bandwidth: false });
+        assert_eq!(Density::parse("grid:25", Some("bandwidth")).unwrap(), Density { cells: Cells::Grid, size: 25.0, bandwidth: true });
+        for (spec, by) in [("grid:1", None), ("grid:big", None), ("hexbin:500", None), ("voronoi", None), ("grid", Some("weight"))] {
+            assert!(Density::parse(spec, by).is_err(), "{spec} {by:?}");
+        }
+    }
+
+    #[test]
+    fn points_fall_into_the_cell_around_them() {
+        let grid = Density::parse("grid:10", None).unwrap();
+        assert_eq!(grid.cell((25.0, 9.9)), (2, 0));
+        assert_eq!(grid.cell((-0.1, 10.0)), (-1, 1));
+        assert_eq!(grid.origin((2, 0)), (20.0, 0.0));
+
+        let hexbin = Density::parse("hexbin:10", None).unwrap();
+        for cell in [(0, 0), (3, -1), (-2, 5), (7, 7)] {
+            let (x, y) = hexbin.origin(cell);
+            assert_eq!(hexbin.cell((x, y)), cell);
+            // Anywhere within the inscribed circle of the hexagon.
+            for deg in (0..360).step_by(30) {
+                let (dy, dx) = f64::from(deg).to_radians().sin_cos();
+                assert_eq!(hexbin.cell((x + 8.6 * dx, y + 8.6 * dy)), cell, "{cell:?} at {deg}°");
+            }
+        }
+    }
+
+    #[test]
+    fn relays_in_view_are_binned() {
+        let relays = relays(json!([
+            { "fingerprint": "A", "advertised_bandwidth": 3000 },
+            { "fingerprint": "B", "advertised_bandwidth": 1000 },
+            { "fingerprint": "C" },
+            { "fingerprint": "D", "advertised_bandwidth": 5000 },
+        ]));
+        let placed: Vec<Placed> = vec![
+            (&relays[0], (0.5, 0.5)),
+            (&relays[1], (0.6, 0.6)),
+            (&relays[2], (45.0, 90.0)),
+            // Outside the region.
+            (&relays[3], (-40.0, -60.0)),
+        ];
+        let area = BBox { west: -10.0, south: -10.0, east: 100.0, north: 50.0 };
+        let frame = Frame::new(&Equirectangular, Some(&area), Rect::CANVAS);
+
+        let density = Density::parse("grid:20", None).unwrap();
+        let (cells, shown) = density.bin(&frame, &placed);
+        assert_eq!(shown.len(), 3);
+        assert_eq!(cells.len(), 2);
+        assert_eq!(cells[&density.cell(frame.point(0.5, 0.5).unwrap())], 2.0);
+
+        let density = Density::parse("hexbin:20", Some("bandwidth")).unwrap();
+        let (cells, _) = density.bin(&frame, &placed);
+        assert_eq!(cells.values().copied().fold(0.0, f64::max), 4000.0);
+        assert_eq!(cells.len(), 2);
+        assert_eq!(density.scale(4000.0).unit, Unit::Bandwidth);
+    }
+
+    #[test]
+    fn marker_area_follows_the_metric() {
+        assert_eq!("exit-probability".parse::<MarkerSize>().unwrap(), MarkerSize::ExitProbability);
+        assert!("weight".parse::<MarkerSize>().is_err());
+
+        let relays = relays(json!([
+            { "fingerprint": "A", "advertised_bandwidth": 40_000_000, "exit_probability": 0.034 },
+            { "fingerprint": "B", "advertised_bandwidth": 10_000_000 },
+            { "fingerprint": "C", "advertised_bandwidth": 1_000 },
+            { "fingerprint": "D" },
+        ]));
+        let sizing = Sizing::new(MarkerSize::Bandwidth, &relays);
+        // A quarter of the bandwidth, half the radius; tiny relays still show.
+        assert_eq!(relays.iter().map(|r| sizing.radius(r)).collect::<Vec<_>>(), [R_MAX, R_MAX / 2.0, R_MIN, R_MIN]);
+        assert_eq!(sizing.legend_values(), [200_000.0, 2_000_000.0, 20_000_000.0]);
+
+        let sizing = Sizing::new(MarkerSize::ExitProbability, &relays);
+        assert_eq!(sizing.legend_values(), [0.0002, 0.002, 0.02]);
+        let labels: Vec<String> = sizing.legend_values().into_iter().map(|v| sizing.metric.label(v)).collect();
+        assert_eq!(labels, ["0.02%", "0.2%", "2%"]);
+
+        // Nothing to scale by: every dot the smallest size, no legend.
+        let sizing = Sizing::new(MarkerSize::ConsensusWeight, &relays);
+        assert_eq!((sizing.radius(&relays[0]), sizing.legend_values()), (R_MIN, Vec::new()));
+    }
+
+    #[test]
+    fn html_map_fills_in_the_template() {
+        let geojson = json!({ "type": "FeatureCollection", "features": [
+            { "properties": { "ISO_A2": "DE" },
+              "geometry": { "type": "Polygon", "coordinates": [[[6.0, 47.3], [15.0, 47.3], [15.0, 55.0], [6.0, 47.3]]] } },
+        ] });
+        let relays = relays(json!([
+            { "fingerprint": "A", "nickname": "</script><b>", "flags": ["Guard"], "latitude": 52.5, "longitude": 13.4,
+              "advertised_bandwidth": 2_000_000, "or_addresses": ["1.2.3.4:9001"] },
+            { "fingerprint": "B", "flags": ["Exit"], "latitude": 48.1, "longitude": 11.6 },
+            { "fingerprint": "C", "flags": ["Running"] },
+        ]));
+        let html = render_html(&relays, &geojson, &Options::default());
+
+        assert!(!html.contains("{{"), "placeholder left in the page");
+        assert!(!html.contains("<?xml"));
+        assert!(html.contains("<span id=\"status\">2 relays</span>"));
+        for class in ["k-middle", "k-guard", "k-exit"] {
+            assert!(html.contains(&format!("data-layer=\"{class}\"")), "{class}");
+        }
+        assert!(html.contains("class='k-guard' data-i='0'") && html.contains("class='k-exit' data-i='1'"));
+
+        // The relay details survive a nickname that would close the script.
+        let start = html.find("id=\"relays\">").unwrap() + "id=\"relays\">".len();
+        let end = start + html[start..].find("</script>").unwrap();
+        let details: Value = serde_json::from_str(&html[start..end]).unwrap();
+        assert_eq!(details[0]["nickname"], "</script><b>");
+        assert_eq!(details[1]["fingerprint"], "B");
+        assert_eq!(details.as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn tracks_mark_joins_and_departures() {
+        let relays = relays(json!([{ "fingerprint": "A", "flags": ["Guard"] }]));
+        let track = Track { relay: &relays[0], present: vec![true, false, false, true, true] };
+        let changes: Vec<Change> = (0..5).map(|i| track.change(i)).collect();
+        assert_eq!(changes, [Change::Stayed, Change::Left, Change::Absent, Change::Joined, Change::Stayed]);
+        // Absent from the first snapshot is not a join.
+        let late = Track { relay: &relays[0], present: vec![false, true] };
+        assert_eq!((late.change(0), late.change(1)), (Change::Absent, Change::Joined));
+
+        let theme = Theme::default();
+        let fills: Vec<&str> = (0..5).map(|i| track.fill(i, Role::Guard, &theme)).collect();
+        assert_eq!(fills, [theme.guard.as_str(), theme.left.as_str(), "none", theme.joined.as_str(), theme.guard.as_str()]);
+    }
+
+    #[test]
+    fn timelapse_animates_only_the_churn() {
+        let snapshot = |published: &str, fingerprints: &[&str]| -> OnionooResponse {
+            let relays: Vec<Value> = fingerprints
+                .iter()
+                .map(|fp| json!({ "fingerprint": fp, "latitude": 50.0, "longitude": 10.0 }))
+                .collect();
+            serde_json::from_value(json!({ "relays_published": published, "relays": relays })).unwrap()
+        };
+        let snapshots = [
+            snapshot("2024-05-01 12:00:00", &["A", "B"]),
+            snapshot("2024-05-01 13:00:00", &["A", "C"]),
+            snapshot("2024-05-01 14:00:00", &["A", "C"]),
+        ];
+        let svg = render_timelapse(&snapshots, &json!({ "features": [] }), &Options::default(), 2.0);
+
+        assert!(svg.contains("over 3 snapshots"));
+        // B and C change colour, A is a plain dot.
+        assert_eq!(svg.matches("<animate attributeName='fill'").count(), 2);
+        assert_eq!(svg.matches("<circle cx").count() - svg.matches("r='6'").count(), 3);
+        let theme = Theme::default();
+        assert!(svg.contains(&format!("values='{};{};none'", theme.middle, theme.left)));
+        assert!(svg.contains(&format!("values='none;{};{}'", theme.joined, theme.middle)));
+        assert!(svg.contains("2024-05-01 13:00:00 · 2 relays  +1 −1"));
+        assert!(svg.contains("dur='6s'"));
+    }
+
+    #[test]
+    fn categories_by_name() {
+        assert_eq!(parse_categories(&["ipv6", " hsdir"]).unwrap(), [Category::Ipv6, Category::HsDir]);
+        assert_eq!(parse_categories(&["all"]).unwrap(), ALL_CATEGORIES);
+        assert!(parse_categories(&["exit"]).is_err());
+    }
+
+    #[test]
+    fn markers_set_apart_the_chosen_categories() {
+        let relays = relays(json!([
+            { "fingerprint": "A", "flags": ["Guard", "Exit", "HSDir"], "latitude": 1.0, "longitude": 2.0,
+              "or_addresses": ["1.2.3.4:9001", "[2001:db8::1]:9001"] },
+            { "fingerprint": "B", "flags": ["Exit", "BadExit"], "latitude": 1.0 },
+            { "fingerprint": "C", "flags": ["Authority", "Guard", "HSDir"] },
+            { "fingerprint": "D", "flags": ["MiddleOnly"] },
+        ]));
+        let roles = |categories: &[Category]| -> Vec<Role> { relays.iter().map(|r| r.marker(categories).role).collect() };
+        assert_eq!(roles(&[]), [Role::Guard, Role::Exit, Role::Guard, Role::Middle]);
+        assert_eq!(roles(&DEFAULT_CATEGORIES), [Role::GuardExit, Role::BadExit, Role::Guard, Role::Middle]);
+        assert_eq!(roles(&ALL_CATEGORIES), [Role::GuardExit, Role::BadExit, Role::Authority, Role::MiddleOnly]);
+
+        let all: Vec<Marker> = relays.iter().map(|r| r.marker(&ALL_CATEGORIES)).collect();
+        assert_eq!(all.iter().map(|m| m.shape).collect::<Vec<_>>(), [Shape::Square, Shape::Circle, Shape::Diamond, Shape::Circle]);
+        assert_eq!(all[0].classes(), "k-guard-exit k-hsdir k-ipv6");
+        // Only one coordinate from Onionoo counts as a GeoLite2 position.
+        assert_eq!(all[1].classes(), "k-bad-exit k-geolite2");
+        assert_eq!(all[2].radius(), R_AUTHORITY);
+
+        let theme = Theme::default();
+        assert_eq!(all[0].paint(&theme, "red", ""), format!("fill='red' stroke='{}' stroke-width='1.2'", theme.ipv6));
+        assert_eq!(all[1].paint(&theme, "red", " stroke='blue'"), "fill='red' stroke='blue' fill-opacity='0.4'");
+    }
+
+    #[test]
+    fn legend_lists_the_roles_and_marks_in_use() {
+        let theme = Theme::default();
+        let plain = Marker { role: Role::Middle, shape: Shape::Circle, ipv6: false, approximate: false };
+        let labels = |markers: &[Marker]| -> Vec<&str> { legend(markers, &theme).iter().map(|e| e.label).collect() };
+        assert_eq!(labels(&[]), ["Middle", "Guard", "Exit"]);
+        let markers = [
+            Marker { role: Role::Authority, shape: Shape::Diamond, ..plain },
+            Marker { role: Role::BadExit, ipv6: true, ..plain },
+            Marker { approximate: true, ..plain },
+        ];
+        assert_eq!(labels(&markers), ["Middle", "Guard", "Exit", "BadExit", "Authority", "IPv6", "GeoLite2"]);
+        let entries = legend(&markers, &theme);
+        assert_eq!((entries[4].marker.shape, entries[4].class), (Shape::Diamond, "k-authority"));
+        assert_eq!(entries[5].fill, theme.muted);
+    }
+}